@@ -1,61 +1,113 @@
-use std::collections::VecDeque;
-use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
 
-#[derive(Default)]
+use crate::ring_buffer::Consumer;
+
+#[derive(Clone, Default)]
 pub struct BufferOptions {
     pub resolution: usize,
     pub discard: bool,
     pub overlap: bool,
+    // If set, the number of samples between the start of two frames
+    pub hop: Option<usize>,
+    // The sample rate of the input, used to estimate capture times
+    pub rate: u32,
+}
+
+// A window of audio data, and the position of its first sample in the stream
+pub struct Frame {
+    pub samples: Vec<f32>,
+    pub timestamp: u64,
+    // Estimated time at which the last sample was recorded
+    pub captured: Instant,
 }
 
 pub struct AudioBuffer {
     options: BufferOptions,
-    buffer: VecDeque<f32>,
-    receiver: Receiver<Vec<f32>>,
+    buffer: Consumer,
 }
 
 impl AudioBuffer {
-    pub fn new(receiver: Receiver<Vec<f32>>, options: BufferOptions) -> AudioBuffer {
-        AudioBuffer {
-            buffer: VecDeque::with_capacity(options.resolution),
-            receiver,
-            options,
+    pub fn new(buffer: Consumer, options: BufferOptions) -> AudioBuffer {
+        assert!(buffer.capacity() >= options.resolution);
+        AudioBuffer { buffer, options }
+    }
+
+    // Number of samples lost because the analysis was too slow
+    pub fn overruns(&self) -> usize {
+        self.buffer.overruns()
+    }
+
+    // Number of frames that had to wait for the audio input
+    pub fn underruns(&self) -> usize {
+        self.buffer.underruns()
+    }
+
+    // Estimate when the end of a frame of n samples was recorded
+    // Samples received after it are assumed to have arrived in real time
+    fn captured(&self, n: usize) -> Instant {
+        let newer = (self.buffer.len() - n) as f64;
+        let received = self.buffer.received();
+        if self.options.rate == 0 {
+            return received;
         }
+        let delay = Duration::from_secs_f64(newer / f64::from(self.options.rate));
+        received.checked_sub(delay).unwrap_or(received)
     }
 
     // Return n elements, n being options.resolution
+    // If options.hop is set, frames start exactly hop samples apart
     // If options.discard is true, overwrite old elements
     // If options.overlap is true, don't delete read elements
-    // When receiver dies and data is exhausted, start returning None
-    pub fn take(&mut self) -> Option<Vec<f32>> {
+    // When the producer dies and data is exhausted, start returning None
+    pub fn take(&mut self) -> Option<Frame> {
         // Set n as the previously received packet resolution
         let n = self.options.resolution;
-        // Read all waiting packets
-        for packet in self.receiver.try_iter() {
-            self.buffer.extend(packet);
-        }
         // Make sure buffer contains at least n elements
-        while self.buffer.len() < n {
-            let recv = self.receiver.recv().ok()?;
-            self.buffer.extend(recv);
+        if !self.buffer.wait_for(n) {
+            return None;
+        }
+        // If a hop size is set, read frames on a fixed grid
+        if let Some(hop) = self.options.hop {
+            // If discard is on, skip whole hops to stay on the grid
+            if self.options.discard {
+                let late = (self.buffer.len() - n) / hop * hop;
+                self.buffer.skip(late);
+            }
+            let samples = self.buffer.peek(n);
+            let timestamp = self.buffer.position();
+            let captured = self.captured(n);
+            // Move to the start of the next frame
+            self.buffer.skip(hop.min(n));
+            return Some(Frame {
+                samples,
+                timestamp,
+                captured,
+            });
         }
         // If discard is on, discard surplus data
         if self.options.discard && self.buffer.len() > n {
-            self.buffer.drain(0..self.buffer.len() - n);
+            let surplus = self.buffer.len() - n;
+            self.buffer.skip(surplus);
         }
-        // If overlap is allowed, return n oldest elements, and only delete those over the limit
+        let timestamp = self.buffer.position();
+        let samples = self.buffer.peek(n);
+        let captured = self.captured(n);
+        // If overlap is allowed, only delete the elements over the limit
         if self.options.overlap {
-            let ret = self.buffer.iter().cloned().take(n).collect();
             // Calculate unneeded data for next batch
             let surplus = self.buffer.len() - n;
             // Cap surplus at n to avoid ignoring data
             let surplus = surplus.min(n);
             // Delete surplus data
-            self.buffer.drain(0..surplus);
-            Some(ret)
+            self.buffer.skip(surplus);
         // If overlap is not allowed, remove them before returning
         } else {
-            Some(self.buffer.drain(0..n).collect())
+            self.buffer.skip(n);
         }
+        Some(Frame {
+            samples,
+            timestamp,
+            captured,
+        })
     }
 }
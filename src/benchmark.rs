@@ -0,0 +1,99 @@
+// Measures the speedup of multithreaded scoring on synthetic data
+
+use std::time::{Duration, Instant};
+
+use crate::fourier::ScoringOptions;
+use crate::frequency::Frequency;
+use crate::scores::ScoreCalculator;
+
+// Number of frames scored for the benchmark
+const FRAME_COUNT: usize = 50;
+
+// Build a spectrum shaped like the output of the fourier analysis
+// A simple linear congruential generator keeps the data deterministic
+fn synthetic_frame(resolution: usize, options: ScoringOptions, seed: &mut u32) -> Vec<Frequency> {
+    let len = resolution * options.zpadding as usize;
+    (1..len / 2)
+        .map(|i| {
+            *seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            Frequency {
+                intensity: (*seed >> 16) as f32 / 65536f32,
+                value: i as f32 / len as f32 * options.frequency as f32,
+            }
+        })
+        .collect()
+}
+
+// Build the lookup table and score frames, returning the time taken by each step
+fn run_with(
+    frames: &[Vec<Frequency>],
+    options: ScoringOptions,
+    threads: usize,
+) -> (Duration, Duration, Vec<Vec<f32>>) {
+    let start = Instant::now();
+    let mut calculator = match options.sparse {
+        Some(selection) => ScoreCalculator::sparse(selection, threads),
+        None => ScoreCalculator::new(frames[0].as_slice(), threads),
+    };
+    let table = start.elapsed();
+
+    let start = Instant::now();
+    let results = frames[1..]
+        .iter()
+        .map(|frame| {
+            let seconds = 0.1f32;
+            let scores = calculator.calculate(frame.clone(), seconds, options.halflife);
+            scores.note_scores.to_vec()
+        })
+        .collect();
+    let scoring = start.elapsed() / (frames.len() - 1) as u32;
+    (table, scoring, results)
+}
+
+// Compare the serial and the multithreaded paths, and print the results
+pub fn run(resolution: usize, options: ScoringOptions) -> Result<(), String> {
+    println!(
+        "Benchmarking {} threads, resolution {}, zero padding {}, {}",
+        options.threads,
+        resolution,
+        options.zpadding,
+        match options.sparse {
+            Some(selection) => format!("{} sparse partials", selection.count),
+            None => "lookup table".to_owned(),
+        }
+    );
+    let mut seed = 1;
+    let frames: Vec<_> = (0..=FRAME_COUNT)
+        .map(|_| synthetic_frame(resolution, options, &mut seed))
+        .collect();
+
+    let (serial_table, serial_scoring, serial_results) = run_with(&frames, options, 1);
+    let (table, scoring, results) = run_with(&frames, options, options.threads);
+
+    let ms = |d: Duration| d.as_secs_f64() * 1e3;
+    println!(
+        "Setup:        {:9.2} ms serial, {:9.2} ms parallel, x{:.2}",
+        ms(serial_table),
+        ms(table),
+        ms(serial_table) / ms(table)
+    );
+    println!(
+        "Frame score:  {:9.2} ms serial, {:9.2} ms parallel, x{:.2}",
+        ms(serial_scoring),
+        ms(scoring),
+        ms(serial_scoring) / ms(scoring)
+    );
+
+    // NaN scores are possible on silent octaves, so compare the bits
+    let identical = serial_results
+        .iter()
+        .flatten()
+        .zip(results.iter().flatten())
+        .all(|(a, b)| a.to_bits() == b.to_bits());
+    if identical {
+        println!("Results are identical");
+        Ok(())
+    } else {
+        Err("Serial and parallel results differ".to_owned())
+    }
+}
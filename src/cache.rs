@@ -0,0 +1,269 @@
+/*
+
+cache.rs stores the dissonance lookup table on disk, as it is slow to build.
+
+A table only depends on the analysed frequencies and the virtual instrument,
+so it is keyed by the sample rate, the fft size, the tuning and the timbre.
+Any mismatch or corruption simply causes the table to be rebuilt.
+
+*/
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::dissonance;
+use crate::frequency::Frequency;
+use crate::messages;
+use crate::notes::{BASE_FREQUENCY, NOTE_COUNT};
+
+// Identifies table files
+const MAGIC: &[u8; 8] = b"IMPRVTBL";
+// Increment whenever the file layout or the dissonance formula changes
+const VERSION: u32 = 1;
+
+// Everything the table depends on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CacheKey {
+    pub rate: i32,
+    pub resolution: usize,
+    pub zpadding: u32,
+    // The frequency of the base note
+    pub tuning: f32,
+    // A hash of the frequency components of every note of the virtual instrument
+    pub timbre: u64,
+    // A hash of the analysed frequencies
+    pub spectrum: u64,
+}
+
+impl CacheKey {
+    pub fn new(heard: &[Frequency], rate: i32, resolution: usize, zpadding: u32) -> CacheKey {
+        let timbre = dissonance::get_notes_harmonics()
+            .iter()
+            .flat_map(|note| note.iter())
+            .fold(FNV_OFFSET, hash_frequency);
+        let spectrum = heard.iter().fold(FNV_OFFSET, |hash, f| {
+            fnv(hash, &f.value.to_le_bytes())
+        });
+        CacheKey {
+            rate,
+            resolution,
+            zpadding,
+            tuning: BASE_FREQUENCY,
+            timbre,
+            spectrum,
+        }
+    }
+
+    // Files are named after the key, so that different settings can be cached together
+    fn file_name(&self) -> String {
+        format!(
+            "dissonance-v{}-{}hz-{}x{}-{:016x}.bin",
+            VERSION,
+            self.rate,
+            self.resolution,
+            self.zpadding,
+            fnv(self.timbre, &self.spectrum.to_le_bytes())
+        )
+    }
+
+    fn header(&self, bins: usize) -> Vec<u8> {
+        let mut header = Vec::with_capacity(64);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&self.rate.to_le_bytes());
+        header.extend_from_slice(&(self.resolution as u64).to_le_bytes());
+        header.extend_from_slice(&self.zpadding.to_le_bytes());
+        header.extend_from_slice(&self.tuning.to_bits().to_le_bytes());
+        header.extend_from_slice(&self.timbre.to_le_bytes());
+        header.extend_from_slice(&self.spectrum.to_le_bytes());
+        header.extend_from_slice(&(NOTE_COUNT as u64).to_le_bytes());
+        header.extend_from_slice(&(bins as u64).to_le_bytes());
+        header
+    }
+}
+
+// FNV-1a, as the standard hashers are not guaranteed to be stable between builds
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+fn fnv(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |hash, &b| (hash ^ u64::from(b)).wrapping_mul(FNV_PRIME))
+}
+
+fn hash_frequency(hash: u64, f: &Frequency) -> u64 {
+    let hash = fnv(hash, &f.value.to_le_bytes());
+    fnv(hash, &f.intensity.to_le_bytes())
+}
+
+// The default cache directory, following the XDG convention
+pub fn default_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("improve"))
+}
+
+// Read a table, returning None if it is missing, outdated or corrupted
+pub fn load(dir: &Path, key: &CacheKey, bins: usize) -> Option<Vec<Vec<f32>>> {
+    let mut file = File::open(dir.join(key.file_name())).ok()?;
+    let mut bytes = vec![];
+    file.read_to_end(&mut bytes).ok()?;
+
+    // Check that the table was built with the same settings
+    let header = key.header(bins);
+    let data_len = NOTE_COUNT * bins * 4;
+    if bytes.len() != header.len() + data_len + 8 || bytes[..header.len()] != header[..] {
+        return None;
+    }
+    // Check the data against its checksum
+    let (data, checksum) = bytes[header.len()..].split_at(data_len);
+    let mut expected = [0u8; 8];
+    expected.copy_from_slice(checksum);
+    if fnv(FNV_OFFSET, data) != u64::from_le_bytes(expected) {
+        return None;
+    }
+
+    let values: Vec<f32> = data
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    Some(values.chunks(bins).map(|row| row.to_vec()).collect())
+}
+
+// Write a table, going through a temporary file to never leave a partial table
+// The temporary file is named after the process, as several runs can build the same table at once
+pub fn save(dir: &Path, key: &CacheKey, table: &[Vec<f32>]) -> io::Result<()> {
+    let bins = table.first().map_or(0, |row| row.len());
+    fs::create_dir_all(dir)?;
+    let path = dir.join(key.file_name());
+    let temp = path.with_extension(format!("{}.tmp", std::process::id()));
+
+    let mut data = Vec::with_capacity(NOTE_COUNT * bins * 4);
+    for value in table.iter().flatten() {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    let written = File::create(&temp).and_then(|file| {
+        let mut file = BufWriter::new(file);
+        file.write_all(&key.header(bins))?;
+        file.write_all(&data)?;
+        file.write_all(&fnv(FNV_OFFSET, &data).to_le_bytes())?;
+        file.flush()
+    });
+    match written.and_then(|_| fs::rename(&temp, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            fs::remove_file(&temp).ok();
+            Err(e)
+        }
+    }
+}
+
+// Get the table from the cache if possible, or build it and try to cache it
+pub fn load_or_build(
+    dir: Option<&Path>,
+    key: &CacheKey,
+    heard: &[Frequency],
+    threads: usize,
+) -> Vec<Vec<f32>> {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return dissonance::dissonance_scores(heard, threads),
+    };
+    if let Some(table) = load(dir, key, heard.len()) {
+        messages::info(&format!("Loaded dissonance table from {}", dir.display()));
+        return table;
+    }
+    let table = dissonance::dissonance_scores(heard, threads);
+    match save(dir, key, &table) {
+        Ok(()) => messages::info(&format!("Saved dissonance table to {}", dir.display())),
+        Err(e) => messages::warn(&format!("Could not cache dissonance table: {}", e)),
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An empty directory, unique to the test run
+    fn temporary_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("improve-{}-{}", std::process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn key() -> CacheKey {
+        CacheKey {
+            rate: 44100,
+            resolution: 4096,
+            zpadding: 2,
+            tuning: 440f32,
+            timbre: 1234,
+            spectrum: 5678,
+        }
+    }
+
+    fn table(bins: usize) -> Vec<Vec<f32>> {
+        (0..NOTE_COUNT)
+            .map(|note| {
+                (0..bins)
+                    .map(|bin| (note * bins + bin) as f32 / 7f32)
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = temporary_dir("cache-round-trip");
+        save(&dir, &key(), &table(5)).unwrap();
+        assert_eq!(load(&dir, &key(), 5), Some(table(5)));
+        // Only the table is left
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn mismatched_settings() {
+        let dir = temporary_dir("cache-mismatch");
+        save(&dir, &key(), &table(5)).unwrap();
+        // The tuning is only in the header, not in the file name
+        let retuned = CacheKey {
+            tuning: 442f32,
+            ..key()
+        };
+        assert_eq!(load(&dir, &retuned, 5), None);
+        let resampled = CacheKey {
+            rate: 48000,
+            ..key()
+        };
+        assert_eq!(load(&dir, &resampled, 5), None);
+        assert_eq!(load(&dir, &key(), 6), None);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn corrupted_tables() {
+        let dir = temporary_dir("cache-corrupted");
+        save(&dir, &key(), &table(5)).unwrap();
+        let path = dir.join(key().file_name());
+        let bytes = fs::read(&path).unwrap();
+
+        let mut flipped = bytes.clone();
+        flipped[key().header(5).len() + 3] ^= 1;
+        fs::write(&path, &flipped).unwrap();
+        assert_eq!(load(&dir, &key(), 5), None);
+
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert_eq!(load(&dir, &key(), 5), None);
+
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(load(&dir, &key(), 5), Some(table(5)));
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,393 @@
+// Analyses a whole chart ahead of time, one beat at a time
+// The beats are then stepped through in time with playback, or by hand
+
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use crate::dissonance;
+use crate::fourier::{self, Outputs, ScoringOptions};
+use crate::messages;
+use crate::midi_input::{self, Sounding, TrackEvent};
+use crate::musicxml;
+use crate::notes::NOTE_COUNT;
+use crate::scores::{ScoreCalculator, Scores};
+use crate::stats::FrameStats;
+
+// The time between two frames, so that the keys are read and the beats change on time
+const FRAME: Duration = Duration::from_millis(30);
+
+// The tempo when the chart doesn't give one, in quarter notes per minute
+const DEFAULT_TEMPO: f64 = 120.0;
+
+// A bar, with its position and length in quarter notes
+#[derive(Clone, Copy, Debug)]
+pub struct Bar {
+    pub start: f64,
+    // The number of beats, and the length of each of them
+    pub beats: u32,
+    pub beat_length: f64,
+}
+
+// A note of the chart, from its start to its end in quarter notes
+#[derive(Clone, Copy, Debug)]
+pub struct Span {
+    pub start: f64,
+    pub end: f64,
+    // The MIDI number and velocity of the note
+    pub note: u8,
+    pub velocity: u8,
+}
+
+// A chart as written, before being split into beats
+#[derive(Default)]
+pub struct Tune {
+    pub bars: Vec<Bar>,
+    pub spans: Vec<Span>,
+    // The tempo changes, in quarter notes per minute, from their position in quarter notes
+    pub tempos: Vec<(f64, f64)>,
+}
+
+impl Tune {
+    // Read the bars and notes of a Standard MIDI File, following the sustain pedal
+    fn from_midi(bytes: &[u8]) -> Result<Tune, String> {
+        let (division, events) = midi_input::read_song(bytes)?;
+        if division & 0x8000 != 0 || division == 0 {
+            return Err("MIDI files timed in SMPTE frames have no beats".to_owned());
+        }
+        let quarters = |tick: u64| tick as f64 / division as f64;
+
+        let mut tune = Tune::default();
+        let mut signatures = vec![(0f64, 4, 4)];
+        let mut sounding = Sounding::default();
+        // The start of every note sounding
+        let mut starts = HashMap::new();
+        // The position of the last event, as the end of the tracks
+        let mut last = 0f64;
+        for (tick, event) in events {
+            let position = quarters(tick);
+            last = position;
+            match event {
+                TrackEvent::Midi(event) => {
+                    sounding.apply(event);
+                    // Close the notes that stopped, and open the ones that started
+                    starts.retain(|key, &mut (start, velocity)| {
+                        let playing = sounding.notes.get(key) == Some(&velocity);
+                        if !playing {
+                            tune.spans.push(Span {
+                                start,
+                                end: position,
+                                note: key.1,
+                                velocity,
+                            });
+                        }
+                        playing
+                    });
+                    for (&key, &velocity) in sounding.notes.iter() {
+                        starts.entry(key).or_insert((position, velocity));
+                    }
+                }
+                TrackEvent::Tempo(tempo) => tune.tempos.push((position, 60e6 / tempo as f64)),
+                TrackEvent::TimeSignature(beats, unit) => signatures.push((position, beats, unit)),
+                TrackEvent::End => {}
+            }
+        }
+        // Notes still on at the end of the file last until then
+        let end = tune.spans.iter().map(|span| span.end).fold(last, f64::max);
+        for (key, (start, velocity)) in starts {
+            tune.spans.push(Span {
+                start,
+                end,
+                note: key.1,
+                velocity,
+            });
+        }
+
+        // Lay the bars out until the last note, each with the meter in force when it starts
+        let end = tune.spans.iter().map(|span| span.end).fold(0f64, f64::max);
+        let mut start = 0f64;
+        while start < end {
+            let (_, beats, unit) = signatures
+                .iter()
+                .rev()
+                .find(|signature| signature.0 <= start + 1e-6)
+                .cloned()
+                .unwrap();
+            let bar = Bar {
+                start,
+                beats: beats.max(1),
+                beat_length: 4f64 / unit.max(1) as f64,
+            };
+            start += bar.beats as f64 * bar.beat_length;
+            tune.bars.push(bar);
+        }
+        Ok(tune)
+    }
+
+    // The time of a position, in seconds from the start
+    fn seconds(&self, position: f64) -> f64 {
+        let mut seconds = 0f64;
+        let mut last = (0f64, DEFAULT_TEMPO);
+        for &(change, tempo) in self.tempos.iter().take_while(|tempo| tempo.0 < position) {
+            seconds += (change - last.0) * 60f64 / last.1;
+            last = (change, tempo);
+        }
+        seconds + (position - last.0) * 60f64 / last.1
+    }
+}
+
+// A beat of the chart, and the notes heard during it
+pub struct Beat {
+    // The time the beat starts at, in seconds from the start of the chart
+    pub start: f64,
+    // The bar and the beat within it, from 1
+    pub bar: usize,
+    pub beat: usize,
+    // The MIDI number and velocity of every note sounding, even for a part of the beat
+    pub notes: Vec<(u8, u8)>,
+}
+
+pub struct Chart {
+    pub beats: Vec<Beat>,
+}
+
+impl Chart {
+    // Read a Standard MIDI File if the path ends with .mid, or an uncompressed MusicXML file
+    pub fn open(path: &str) -> Result<Chart, String> {
+        let lower = path.to_lowercase();
+        let bytes = std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        let tune = if lower.ends_with(".mid") || lower.ends_with(".midi") {
+            Tune::from_midi(&bytes)
+        } else if lower.ends_with(".mxl") {
+            Err("Compressed MusicXML is not supported, export it as .musicxml".to_owned())
+        } else {
+            musicxml::read(&String::from_utf8_lossy(&bytes))
+        }
+        .map_err(|e| format!("{}: {}", path, e))?;
+        if tune.bars.is_empty() {
+            return Err(format!("{}: The chart has no notes", path));
+        }
+        Ok(Chart::from_tune(&tune))
+    }
+
+    // Split every bar into beats, and find the notes of each of them
+    pub fn from_tune(tune: &Tune) -> Chart {
+        let mut beats = Vec::new();
+        for (i, bar) in tune.bars.iter().enumerate() {
+            let next = tune
+                .bars
+                .get(i + 1)
+                .map_or(f64::INFINITY, |next| next.start);
+            for beat in 0..bar.beats {
+                let start = bar.start + beat as f64 * bar.beat_length;
+                let end = (start + bar.beat_length).min(next);
+                if start >= end {
+                    break;
+                }
+                // Keep the loudest velocity of a note played twice
+                let mut notes = HashMap::new();
+                for span in tune.spans.iter() {
+                    if span.start < end && span.end > start {
+                        let velocity = notes.entry(span.note).or_insert(0);
+                        *velocity = span.velocity.max(*velocity);
+                    }
+                }
+                let mut notes = notes.into_iter().collect::<Vec<_>>();
+                notes.sort_unstable();
+                beats.push(Beat {
+                    start: tune.seconds(start),
+                    bar: i + 1,
+                    beat: beat as usize + 1,
+                    notes,
+                });
+            }
+        }
+        Chart { beats }
+    }
+
+    // The beat being played at a time
+    fn beat_at(&self, time: f64) -> usize {
+        self.beats
+            .iter()
+            .rposition(|beat| beat.start <= time)
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ChartOptions {
+    // If the beats only change when asked to, instead of following the tempo
+    pub manual: bool,
+    // How early the next beat is shown, in seconds
+    pub lookahead: f64,
+}
+
+// Sent by the displays to move through the chart
+#[derive(Clone, Copy, Debug)]
+pub enum ChartCommand {
+    Previous,
+    Next,
+}
+
+// Scores every beat of the chart, then sends the scores of the current one at every frame
+pub fn chart_thread(
+    chart: Chart,
+    commands: Receiver<ChartCommand>,
+    sender: Sender<Scores>,
+    mut options: ScoringOptions,
+    chart_options: ChartOptions,
+    mut outputs: Outputs,
+) {
+    // Every chord is only scored once
+    messages::info(&format!("Analysing {} beats", chart.beats.len()));
+    let harmonics = Box::new(dissonance::get_notes_harmonics());
+    let mut calculator = ScoreCalculator::exact(options.threads);
+    let mut chords: HashMap<&[(u8, u8)], usize> = HashMap::new();
+    let mut chord_scores = Vec::new();
+    let mut beat_chords = Vec::new();
+    for beat in chart.beats.iter() {
+        let chord = *chords.entry(beat.notes.as_slice()).or_insert_with(|| {
+            let partials = midi_input::partials(beat.notes.iter().cloned(), &harmonics);
+            // Without a halflife, the scores of a chord don't depend on the previous ones
+            let scores = calculator.calculate(partials, 0f32, 0f32);
+            chord_scores.push((scores.note_scores, scores.note_values));
+            chord_scores.len() - 1
+        });
+        beat_chords.push(chord);
+    }
+
+    messages::info("Starting the chart");
+    let start = Instant::now();
+    // The position in the chart, moved by the commands
+    let mut offset = 0f64;
+    let mut position = 0;
+    loop {
+        let frame_start = Instant::now();
+        let elapsed = (frame_start - start).as_secs_f64();
+        let current = if chart_options.manual {
+            position
+        } else {
+            chart.beat_at(elapsed + offset)
+        };
+        for command in commands.try_iter() {
+            let target = match command {
+                ChartCommand::Previous => current.saturating_sub(1),
+                ChartCommand::Next => (current + 1).min(chart.beats.len() - 1),
+            };
+            position = target;
+            offset = chart.beats[target].start - elapsed;
+        }
+        let time = if chart_options.manual {
+            chart.beats[position].start
+        } else {
+            elapsed + offset
+        };
+
+        // Show the beat about to be played
+        let index = chart.beat_at(time + chart_options.lookahead);
+        let beat = &chart.beats[index];
+        let (note_scores, note_values): ([f32; NOTE_COUNT], [f32; NOTE_COUNT]) =
+            chord_scores[beat_chords[index]];
+        let mut scores = Scores {
+            note_scores,
+            note_values,
+            fourier: Vec::new(),
+            stats: None,
+            suggestions: Vec::new(),
+            pitch_classes: [0f32; 12],
+            label: Some(format!(
+                "Bar {} beat {} of {}",
+                beat.bar,
+                beat.beat,
+                chart.beats.last().unwrap().bar
+            )),
+        };
+        fourier::publish(&mut scores, &mut options, &mut outputs, time);
+        scores.stats = Some(FrameStats {
+            timestamp: (time * options.frequency as f64) as u64,
+            captured: frame_start,
+            wait: Duration::default(),
+            fourier: Duration::default(),
+            scoring: frame_start.elapsed(),
+            overruns: 0,
+            underruns: 0,
+        });
+        if sender.send(scores).is_err() {
+            return;
+        }
+        std::thread::sleep(FRAME);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A Standard MIDI File of a single track, 480 ticks per beat
+    fn midi(events: &[u8]) -> Vec<u8> {
+        let mut bytes = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x01\xE0MTrk".to_vec();
+        bytes.extend_from_slice(&(events.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(events);
+        bytes
+    }
+
+    fn bars(tune: &Tune) -> Vec<(f64, u32, f64)> {
+        tune.bars
+            .iter()
+            .map(|bar| (bar.start, bar.beats, bar.beat_length))
+            .collect()
+    }
+
+    #[test]
+    fn notes_on_until_the_end() {
+        let tune = Tune::from_midi(&midi(&[
+            0x00, 0x90, 60, 100, // A chord never released
+            0x00, 0x90, 64, 100, //
+            0x8F, 0x00, 0xFF, 0x2F, 0x00, // The end, a bar later
+        ]))
+        .unwrap();
+        assert_eq!(bars(&tune), vec![(0.0, 4, 1.0)]);
+        let mut spans = tune
+            .spans
+            .iter()
+            .map(|span| (span.start, span.end, span.note))
+            .collect::<Vec<_>>();
+        spans.sort_by_key(|span| span.2);
+        assert_eq!(spans, vec![(0.0, 4.0, 60), (0.0, 4.0, 64)]);
+    }
+
+    #[test]
+    fn meter_changes() {
+        let tune = Tune::from_midi(&midi(&[
+            0x00, 0xFF, 0x58, 0x04, 3, 2, 24, 8, // 3/4
+            0x00, 0x90, 60, 100, //
+            0x8B, 0x20, 0x80, 60, 0, // Three beats later
+            0x00, 0xFF, 0x58, 0x04, 6, 3, 24, 8, // 6/8
+            0x00, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40, // 60 quarter notes per minute
+            0x00, 0x90, 62, 100, //
+            0x8B, 0x20, 0x80, 62, 0, //
+            0x00, 0xFF, 0x2F, 0x00,
+        ]))
+        .unwrap();
+        assert_eq!(bars(&tune), vec![(0.0, 3, 1.0), (3.0, 6, 0.5)]);
+
+        let chart = Chart::from_tune(&tune);
+        let beats = chart
+            .beats
+            .iter()
+            .map(|beat| (beat.bar, beat.beat, beat.start, beat.notes.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(beats.len(), 9);
+        assert_eq!(beats[0], (1, 1, 0.0, vec![(60, 100)]));
+        assert_eq!(beats[2], (1, 3, 1.0, vec![(60, 100)]));
+        assert_eq!(beats[3], (2, 1, 1.5, vec![(62, 100)]));
+        assert_eq!(beats[8], (2, 6, 4.0, vec![(62, 100)]));
+    }
+
+    #[test]
+    fn smpte_files_have_no_beats() {
+        let mut bytes = midi(&[0x00, 0xFF, 0x2F, 0x00]);
+        bytes[12] = 0xE7;
+        assert!(Tune::from_midi(&bytes).is_err());
+    }
+}
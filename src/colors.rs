@@ -0,0 +1,183 @@
+// Colour maps, turning values from 0.0 to 1.0 into colours
+
+// Colours sampled at regular intervals, interpolated linearly
+type Stops = [(u8, u8, u8)];
+
+const GREY: [(u8, u8, u8); 2] = [(0, 0, 0), (255, 255, 255)];
+
+const HEAT: [(u8, u8, u8); 5] = [
+    (0, 0, 0),
+    (128, 0, 0),
+    (255, 64, 0),
+    (255, 200, 0),
+    (255, 255, 255),
+];
+
+// Approximations of the matplotlib colour maps
+const VIRIDIS: [(u8, u8, u8); 9] = [
+    (68, 1, 84),
+    (72, 40, 120),
+    (62, 74, 137),
+    (49, 104, 142),
+    (38, 130, 142),
+    (31, 158, 137),
+    (53, 183, 121),
+    (109, 205, 89),
+    (253, 231, 37),
+];
+
+const CIVIDIS: [(u8, u8, u8); 9] = [
+    (0, 34, 78),
+    (18, 53, 112),
+    (59, 73, 108),
+    (87, 93, 109),
+    (112, 113, 115),
+    (138, 134, 120),
+    (165, 156, 116),
+    (195, 179, 105),
+    (254, 232, 56),
+];
+
+const MAGMA: [(u8, u8, u8); 9] = [
+    (0, 0, 4),
+    (28, 16, 68),
+    (79, 18, 123),
+    (129, 37, 129),
+    (181, 54, 122),
+    (229, 80, 100),
+    (251, 135, 97),
+    (254, 194, 135),
+    (252, 253, 191),
+];
+
+// Dark blue to white through orange, the brightness always increases
+const CONTRAST: [(u8, u8, u8); 4] = [(0, 0, 0), (0, 70, 230), (255, 150, 0), (255, 255, 255)];
+
+// The original green to red gradient, unreadable with red-green colour blindness
+const CLASSIC: [(u8, u8, u8); 3] = [(0, 255, 0), (255, 255, 0), (255, 0, 0)];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorMap {
+    Grey,
+    Heat,
+    Viridis,
+    Cividis,
+    Magma,
+    Contrast,
+    Classic,
+}
+
+// The names accepted on the command line
+pub const COLOR_MAP_NAMES: [&str; 7] = [
+    "grey", "heat", "viridis", "cividis", "magma", "contrast", "classic",
+];
+
+impl ColorMap {
+    pub fn from_name(name: &str) -> Option<ColorMap> {
+        match name {
+            "grey" => Some(ColorMap::Grey),
+            "heat" => Some(ColorMap::Heat),
+            "viridis" => Some(ColorMap::Viridis),
+            "cividis" => Some(ColorMap::Cividis),
+            "magma" => Some(ColorMap::Magma),
+            "contrast" => Some(ColorMap::Contrast),
+            "classic" => Some(ColorMap::Classic),
+            _ => None,
+        }
+    }
+
+    // The following colour map, to cycle through all of them
+    pub fn next(self) -> ColorMap {
+        let index = COLOR_MAP_NAMES
+            .iter()
+            .position(|&name| ColorMap::from_name(name) == Some(self))
+            .unwrap();
+        let name = COLOR_MAP_NAMES[(index + 1) % COLOR_MAP_NAMES.len()];
+        ColorMap::from_name(name).unwrap()
+    }
+
+    fn stops(self) -> &'static Stops {
+        match self {
+            ColorMap::Grey => &GREY,
+            ColorMap::Heat => &HEAT,
+            ColorMap::Viridis => &VIRIDIS,
+            ColorMap::Cividis => &CIVIDIS,
+            ColorMap::Magma => &MAGMA,
+            ColorMap::Contrast => &CONTRAST,
+            ColorMap::Classic => &CLASSIC,
+        }
+    }
+
+    // Get the colour of a value, clamped to 0.0 ..= 1.0
+    pub fn get(self, value: f32) -> (u8, u8, u8) {
+        let stops = self.stops();
+        let value = if value.is_nan() { 0f32 } else { value.clamp(0f32, 1f32) };
+        let pos = value * (stops.len() - 1) as f32;
+        let i = (pos as usize).min(stops.len() - 2);
+        let t = pos - i as f32;
+        let (a, b) = (stops[i], stops[i + 1]);
+        let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+        (mix(a.0, b.0), mix(a.1, b.1), mix(a.2, b.2))
+    }
+}
+
+// Black or white, whichever is the most readable on the given colour
+pub fn text_color((r, g, b): (u8, u8, u8)) -> (u8, u8, u8) {
+    let luma = 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32;
+    if luma > 128f32 {
+        (30, 30, 30)
+    } else {
+        (255, 255, 255)
+    }
+}
+
+// The colours of everything that doesn't show a value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Theme {
+    Dark,
+    Light,
+}
+
+pub const THEME_NAMES: [&str; 2] = ["dark", "light"];
+
+impl Theme {
+    pub fn from_name(name: &str) -> Option<Theme> {
+        match name {
+            "dark" => Some(Theme::Dark),
+            "light" => Some(Theme::Light),
+            _ => None,
+        }
+    }
+
+    // Behind the panels
+    pub fn background(self) -> (u8, u8, u8) {
+        match self {
+            Theme::Dark => (30, 30, 30),
+            Theme::Light => (225, 225, 225),
+        }
+    }
+
+    // Behind the graphs
+    pub fn panel(self) -> (u8, u8, u8) {
+        match self {
+            Theme::Dark => (0, 0, 0),
+            Theme::Light => (255, 255, 255),
+        }
+    }
+
+    // Text and graph lines
+    pub fn foreground(self) -> (u8, u8, u8) {
+        match self {
+            Theme::Dark => (255, 255, 255),
+            Theme::Light => (20, 20, 20),
+        }
+    }
+
+    // Gridlines, drawn over the values
+    pub fn grid(self) -> (u8, u8, u8) {
+        match self {
+            Theme::Dark => (90, 90, 90),
+            Theme::Light => (150, 150, 150),
+        }
+    }
+}
@@ -0,0 +1,185 @@
+// Finds the audio devices, and the channel of the input to analyse
+
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+use sdl2::AudioSubsystem;
+
+// The numbers of channels a device can be opened with to read one of them
+const CHANNEL_COUNTS: [u8; 4] = [2, 4, 6, 8];
+pub const MAX_CHANNELS: u8 = 8;
+
+// The names of the capture or playback devices, sdl2 only listing the playback ones
+// Some drivers can't list their devices, in which case the list is empty
+fn device_names(_audio: &AudioSubsystem, capture: bool) -> Vec<String> {
+    let capture = if capture { 1 } else { 0 };
+    // Safety: the audio subsystem is initialised, and the names are copied right away
+    unsafe {
+        let count = sdl2::sys::SDL_GetNumAudioDevices(capture);
+        (0..count.max(0))
+            .filter_map(|index| {
+                let name = sdl2::sys::SDL_GetAudioDeviceName(index, capture);
+                if name.is_null() {
+                    None
+                } else {
+                    Some(CStr::from_ptr(name).to_string_lossy().into_owned())
+                }
+            })
+            .collect()
+    }
+}
+
+// Print the devices with their index, for the --device option
+pub fn list(audio: &AudioSubsystem) {
+    for (title, capture) in [("Capture devices", true), ("Playback devices", false)].iter() {
+        println!("{}:", title);
+        let names = device_names(audio, *capture);
+        if names.is_empty() {
+            println!(
+                "  None listed by the {} driver",
+                audio.current_audio_driver()
+            );
+        }
+        for (index, name) in names.iter().enumerate() {
+            println!("  {}: {}", index, name);
+        }
+    }
+}
+
+// The rate and number of channels of a capture device, when it is asked for some
+// sdl2 opens the devices letting SDL convert anything, which would hide a missing channel or rate,
+// so the device is opened once more allowing any change, giving the spec it really has
+pub fn native_spec(
+    _audio: &AudioSubsystem,
+    device: Option<&str>,
+    rate: i32,
+    channels: u8,
+) -> Result<(i32, u8), String> {
+    let device = match device {
+        Some(device) => Some(CString::new(device).map_err(|e| e.to_string())?),
+        None => None,
+    };
+    // Safety: the audio subsystem is initialised, and the device is closed right away
+    unsafe {
+        let mut desired: sdl2::sys::SDL_AudioSpec = std::mem::zeroed();
+        desired.freq = rate;
+        desired.format = sdl2::sys::AUDIO_F32SYS as _;
+        desired.channels = channels;
+        let mut obtained: sdl2::sys::SDL_AudioSpec = std::mem::zeroed();
+        let id = sdl2::sys::SDL_OpenAudioDevice(
+            device
+                .as_ref()
+                .map_or(ptr::null(), |device| device.as_ptr()),
+            1,
+            &desired,
+            &mut obtained,
+            sdl2::sys::SDL_AUDIO_ALLOW_ANY_CHANGE as _,
+        );
+        if id == 0 {
+            return Err(sdl2::get_error());
+        }
+        sdl2::sys::SDL_CloseAudioDevice(id);
+        Ok((obtained.freq, obtained.channels))
+    }
+}
+
+// The name of a capture device from its index, its name, or a part of its name
+pub fn find_capture(audio: &AudioSubsystem, device: &str) -> Result<String, String> {
+    let names = device_names(audio, true);
+    if let Ok(index) = device.parse::<usize>() {
+        return names.get(index).cloned().ok_or_else(|| {
+            format!(
+                "No capture device {}, there are {}, see --list-devices",
+                index,
+                names.len()
+            )
+        });
+    }
+    if let Some(name) = names.iter().find(|name| name.as_str() == device) {
+        return Ok(name.clone());
+    }
+    let lower = device.to_lowercase();
+    let matching = names
+        .iter()
+        .filter(|name| name.to_lowercase().contains(&lower))
+        .collect::<Vec<_>>();
+    match matching.as_slice() {
+        [name] => Ok((*name).clone()),
+        [] => Err(format!(
+            "No capture device named {}, see --list-devices",
+            device
+        )),
+        _ => Err(format!(
+            "Several capture devices are named {}: {}",
+            device,
+            matching
+                .iter()
+                .map(|name| name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+// The channels of the input analysed
+#[derive(Clone, Debug)]
+pub enum InputChannels {
+    // Every channel mixed down by the driver
+    Mix,
+    // A single channel, from 0
+    Single(usize),
+    // The channels of the band, which are scored, and the ones of the player, only displayed
+    Split {
+        accompaniment: Vec<usize>,
+        played: Vec<usize>,
+    },
+}
+
+impl InputChannels {
+    // Parse "mix" or a channel number from 1
+    pub fn from_name(name: &str) -> Option<InputChannels> {
+        match name {
+            "mix" => Some(InputChannels::Mix),
+            _ => parse_channel(name).map(InputChannels::Single),
+        }
+    }
+
+    // The channels mixed into every analysed input, the first one being scored
+    pub fn sources(&self) -> Vec<Vec<usize>> {
+        match self {
+            InputChannels::Mix => vec![vec![0]],
+            InputChannels::Single(channel) => vec![vec![*channel]],
+            InputChannels::Split {
+                accompaniment,
+                played,
+            } => vec![accompaniment.clone(), played.clone()],
+        }
+    }
+
+    // The highest channel read, from 0, none when the driver mixes them down
+    pub fn highest(&self) -> Option<usize> {
+        match self {
+            InputChannels::Mix => None,
+            _ => self.sources().into_iter().flatten().max(),
+        }
+    }
+
+    // The number of channels to open the device with, the drivers only taking some of them
+    pub fn channel_count(&self) -> u8 {
+        match self.highest() {
+            None => 1,
+            Some(highest) => *CHANNEL_COUNTS
+                .iter()
+                .find(|&&count| count as usize > highest)
+                .unwrap_or(&MAX_CHANNELS),
+        }
+    }
+}
+
+// Parse a channel number from 1, as on an audio interface, to an index from 0
+pub fn parse_channel(name: &str) -> Option<usize> {
+    match name.trim().parse::<usize>() {
+        Ok(channel) if (1..=MAX_CHANNELS as usize).contains(&channel) => Some(channel - 1),
+        _ => None,
+    }
+}
@@ -1,4 +1,7 @@
+use crate::colors::{ColorMap, Theme};
+use crate::instrument::Fretboard;
 use crate::notes::Note;
+use crate::scores::Suggestion;
 
 #[derive(Clone, Copy, Debug)]
 pub enum Notation {
@@ -18,6 +21,33 @@ impl Notation {
         let index = note.get_octave_index() as usize;
         self.get_names()[index]
     }
+    // The name of the note followed by its octave, without padding
+    pub fn get_full_name(&self, note: Note) -> String {
+        format!("{}{}", self.get_name(note).trim(), note.get_octave())
+    }
+    // Find a note from its full name in either notation, as in "E4" or "Mi4"
+    pub fn parse_note(name: &str) -> Option<Note> {
+        Note::iter().find(|&note| {
+            [Notation::English, Notation::Romance]
+                .iter()
+                .any(|notation| notation.get_full_name(note).eq_ignore_ascii_case(name))
+        })
+    }
+    // The suggested notes and their scores, as in "E 0.02, B 0.05, G# 0.11"
+    pub fn format_suggestions(&self, suggestions: &[Suggestion]) -> String {
+        suggestions
+            .iter()
+            .map(|suggestion| {
+                let name = if suggestion.folded {
+                    self.get_name(suggestion.note).trim().to_string()
+                } else {
+                    self.get_full_name(suggestion.note)
+                };
+                format!("{} {:.2}", name, suggestion.score)
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
     pub fn get_names(self) -> [&'static str; 12] {
         match self {
             Notation::English => NOTE_NAMES_ENGLISH,
@@ -26,9 +56,54 @@ impl Notation {
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+pub struct SpectrogramOptions {
+    pub colormap: ColorMap,
+    // The loudness range displayed, in decibels
+    pub db_range: f32,
+}
+
+// The order of the pitch classes around the circle
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CircleOrder {
+    Chromatic,
+    Fifths,
+}
+
+impl CircleOrder {
+    // The pitch classes from the top of the circle, clockwise
+    pub fn pitch_classes(self) -> [usize; 12] {
+        let step = match self {
+            CircleOrder::Chromatic => 1,
+            CircleOrder::Fifths => 7,
+        };
+        let mut classes = [0; 12];
+        for (i, class) in classes.iter_mut().enumerate() {
+            *class = i * step % 12;
+        }
+        classes
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct DisplayOptions {
     pub notation: Notation,
     pub clear_term: bool,
-    pub instrument: (),
+    // The instrument drawn, and the frets displayed
+    pub fretboard: Fretboard,
+    // The colours of the scores
+    pub colormap: ColorMap,
+    pub theme: Theme,
+    // If set, display a spectrogram window
+    pub spectrogram: Option<SpectrogramOptions>,
+    // If a window with the recent scores should be displayed
+    pub history: bool,
+    // If only the suggested notes should be printed, as text
+    pub suggestions_only: bool,
+    // If set, display the 12 pitch classes around a circle
+    pub circle: Option<CircleOrder>,
+    // If the fretboard is mirrored, with the nut on the right
+    pub left_handed: bool,
+    // If the lowest string is at the top, instead of the bottom as in tablatures
+    pub flip_strings: bool,
 }
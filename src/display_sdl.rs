@@ -1,149 +1,427 @@
 // The SDL display loop
 
 // Standard
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::Instant;
 
 // Tools
 use itertools::Itertools;
-use palette::Gradient;
-use palette::Hsv;
-use palette::Srgb;
 
 // Sdl
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
+use sdl2::pixels::PixelFormatEnum;
 use sdl2::rect::Point;
 use sdl2::rect::Rect;
 use sdl2::render::Canvas;
 use sdl2::render::Texture;
+use sdl2::render::TextureCreator;
+use sdl2::ttf::Font;
+use sdl2::video::FullscreenType;
 use sdl2::video::Window;
-use sdl2::video::WindowPos;
+use sdl2::video::WindowContext;
 use sdl2::Sdl;
 
 // Crate
-use crate::display::DisplayOptions;
-use crate::notes::Note::*;
+use crate::chart::ChartCommand;
+use crate::colors::{self, ColorMap, Theme};
+use crate::display::{CircleOrder, DisplayOptions, SpectrogramOptions};
+use crate::instrument::Fretboard;
+use crate::history::ScoreHistory;
+use crate::notes::Note;
 use crate::scores::Scores;
+use crate::spectrogram::Spectrogram;
+use crate::stats::Monitor;
 use crate::tools::*;
 
-// Guitar constants
-
-// The note for every guitar strings, from E2 to E4
-const STRING_COUNT: usize = 6;
-const STRINGS: [usize; STRING_COUNT] = [
-    E2 as usize,
-    A2 as usize,
-    D3 as usize,
-    G3 as usize,
-    B3 as usize,
-    E4 as usize,
-];
-
-// Dimensions in pixels for every fretboard elements
+// Default dimensions in pixels for every fretboard elements, scaled with the window
 const STRING_HEIGHT: u32 = 18;
 const FRET_WIDTH: u32 = 27;
-const FRET_COUNT: u32 = 44;
 const FRET_LINE: u32 = 9;
-const FONT_HEIGHT: u16 = STRING_HEIGHT as u16 - 1;
 
-// Note range
-const FIRST_NOTE: usize = STRINGS[0];
-const LAST_NOTE: usize = STRINGS[STRING_COUNT - 1] + FRET_COUNT as usize;
+// The largest default scale of the fretboard, when only a few frets are displayed
+const MAX_SCALE: f32 = 2f32;
 
 // Font asset
 const FONT_NAME: &str = "assets/UbuntuMono-R.ttf";
 
-// Board graph window dimensions
-const BOARD_HEIGHT: u32 = (STRING_COUNT as u32 + 1) * STRING_HEIGHT;
-const BOARD_WIDTH: u32 = (FRET_COUNT) * FRET_WIDTH + FRET_LINE;
-
-// Fourier graph dimensions
+// Default panel dimensions, also used as weights when the window is resized
+const DEFAULT_WIDTH: u32 = 1197;
 const FOURIER_HEIGHT: u32 = 200;
-const FOURIER_WIDTH: u32 = 1024;
+const SPECTROGRAM_HEIGHT: u32 = 300;
+const HISTORY_HEIGHT: u32 = 300;
+
+// Spectrogram resolution, every frame is a column
+const SPECTROGRAM_ROWS: u32 = 400;
+const SPECTROGRAM_COLUMNS: u32 = 1024;
+
+// History resolution, every frame is a column and every note a row
+const HISTORY_COLUMNS: u32 = 1024;
+// The number of frames kept for scrubbing
+const HISTORY_LENGTH: usize = HISTORY_COLUMNS as usize * 8;
+
+// The position of every panel, computed from the size of the window in pixels
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Layout {
+    history: Option<Rect>,
+    graph: Rect,
+    // On the right of the graph, if displayed
+    circle: Option<Rect>,
+    board: Rect,
+    spectrogram: Option<Rect>,
+    // Fretboard dimensions
+    fret_width: u32,
+    fret_line: u32,
+    string_height: u32,
+    // Fretboard orientation
+    left_handed: bool,
+    flip_strings: bool,
+    // The displayed frets and strings
+    fretboard: Fretboard,
+    string_count: usize,
+}
+
+impl Layout {
+    fn new((width, height): (u32, u32), options: DisplayOptions) -> Layout {
+        // Scale the fretboard to the width, without taking more than half the height
+        let (board_width, board_height) = Layout::board_size(options.fretboard, 1f32);
+        let scale = (width as f32 / board_width as f32)
+            .min(height as f32 / 2f32 / board_height as f32);
+        let fret_width = ((FRET_WIDTH as f32 * scale) as u32).max(3);
+        let fret_line = ((FRET_LINE as f32 * scale) as u32).max(1);
+        let string_height = ((STRING_HEIGHT as f32 * scale) as u32).max(4);
+        let (board_width, board_height) = Layout::board_size(options.fretboard, scale);
+
+        // Share the remaining height between the other panels
+        let history_weight = if options.history { HISTORY_HEIGHT } else { 0 };
+        let spectrogram_weight = if options.spectrogram.is_some() {
+            SPECTROGRAM_HEIGHT
+        } else {
+            0
+        };
+        let total_weight = FOURIER_HEIGHT + history_weight + spectrogram_weight;
+        let remaining = height.saturating_sub(board_height);
+        let share = |weight: u32| (remaining as u64 * weight as u64 / total_weight as u64) as u32;
+
+        // Stack the panels from the top
+        let mut y = 0;
+        let mut panel = |height: u32| {
+            let rect = Rect::new(0, y, width, height);
+            y += height as i32;
+            rect
+        };
+        let history = if options.history {
+            Some(panel(share(history_weight)))
+        } else {
+            None
+        };
+        let graph = panel(share(FOURIER_HEIGHT));
+        // Take a square from the graph for the circle
+        let (graph, circle) = match options.circle {
+            Some(_) => {
+                let size = graph.height().min(width / 2);
+                let circle = Rect::new((width - size) as i32, graph.y(), size, size);
+                let graph = Rect::new(0, graph.y(), width - size, graph.height());
+                (graph, Some(circle))
+            }
+            None => (graph, None),
+        };
+        let board = panel(board_height);
+        let spectrogram = options
+            .spectrogram
+            .map(|_| panel(share(spectrogram_weight)));
+
+        // Center the fretboard
+        let board = Rect::new(
+            (width.saturating_sub(board_width) / 2) as i32,
+            board.y(),
+            board_width,
+            board_height,
+        );
+
+        Layout {
+            history,
+            graph,
+            circle,
+            board,
+            spectrogram,
+            fret_width,
+            fret_line,
+            string_height,
+            left_handed: options.left_handed,
+            flip_strings: options.flip_strings,
+            fretboard: options.fretboard,
+            string_count: options.fretboard.instrument.strings().len(),
+        }
+    }
+
+    // The size of the fretboard at a given scale
+    // A line of fret numbers is above the strings, and a half line of inlays below them
+    fn board_size(fretboard: Fretboard, scale: f32) -> (u32, u32) {
+        let fret_width = ((FRET_WIDTH as f32 * scale) as u32).max(3);
+        let fret_line = ((FRET_LINE as f32 * scale) as u32).max(1);
+        let string_height = ((STRING_HEIGHT as f32 * scale) as u32).max(4);
+        let string_count = fretboard.instrument.strings().len() as u32;
+        let width = fretboard.column_count() as u32 * fret_width + fret_line;
+        let height = (string_count + 1) * string_height + string_height / 2;
+        (width, height)
+    }
+
+    // The left of a fret, the open strings being separated from the rest by a line
+    // Left handed fretboards start from the right
+    fn fret_x(&self, fret: usize) -> i32 {
+        let column = self.fretboard.column(fret);
+        let (column, line) = if self.left_handed {
+            (self.fretboard.column_count() - 1 - column, column == 0)
+        } else {
+            (column, column > 0)
+        };
+        let x = column as u32 * self.fret_width + line as u32 * self.fret_line;
+        self.board.x() + x as i32
+    }
+
+    // The top of a string, from the lowest one, below the header
+    // The highest string is at the top, as in tablatures, unless flipped
+    fn string_y(&self, string: usize) -> i32 {
+        let row = if self.flip_strings {
+            string + 1
+        } else {
+            self.string_count - string
+        };
+        self.board.y() + (row as u32 * self.string_height) as i32
+    }
+
+    // The font size fitting in a fret
+    fn font_height(&self) -> u16 {
+        (self.string_height as u16 - 1).max(4)
+    }
+
+    // The top of the inlays, below the strings
+    fn inlay_y(&self) -> i32 {
+        self.board.y() + ((self.string_count + 1) as u32 * self.string_height) as i32
+    }
+
+    // The default window size, zooming on the fretboard when only a few frets are displayed
+    fn default_size(options: DisplayOptions) -> (u32, u32) {
+        let (board_width, _) = Layout::board_size(options.fretboard, 1f32);
+        let scale = (DEFAULT_WIDTH as f32 / board_width as f32).min(MAX_SCALE);
+        let (_, board_height) = Layout::board_size(options.fretboard, scale);
+        let mut height = FOURIER_HEIGHT + board_height;
+        if options.history {
+            height += HISTORY_HEIGHT;
+        }
+        if options.spectrogram.is_some() {
+            height += SPECTROGRAM_HEIGHT;
+        }
+        (DEFAULT_WIDTH, height)
+    }
+}
+
+// Every text texture, rendered again when the font size changes
+struct Texts<'a> {
+    // The name of every note in an octave, in dark and light text
+    notes: Vec<Texture<'a>>,
+    notes_light: Vec<Texture<'a>>,
+    // The fret numbers
+    frets: Vec<Texture<'a>>,
+    // The full name of every C, by octave
+    octaves: Vec<Texture<'a>>,
+    // Displayed when the history is paused
+    paused: Texture<'a>,
+    // The ends of the colour legend
+    consonant: Texture<'a>,
+    dissonant: Texture<'a>,
+}
+
+impl<'a> Texts<'a> {
+    fn new(
+        texture_creator: &'a TextureCreator<WindowContext>,
+        font: &Font,
+        options: DisplayOptions,
+    ) -> Texts<'a> {
+        let notation = options.notation;
+        let render = |text: &str, color: Color| {
+            let surface = font.render(text).blended(color).unwrap();
+            texture_creator
+                .create_texture_from_surface(&surface)
+                .unwrap()
+        };
+
+        let foreground = Color::from(options.theme.foreground());
+
+        // Build a texture for every note names
+        let notes = notation
+            .get_names()
+            .iter()
+            .map(|name| render(name, Color::RGB(30, 30, 30)))
+            .collect_vec();
+        let notes_light = notation
+            .get_names()
+            .iter()
+            .map(|name| render(name, Color::RGB(255, 255, 255)))
+            .collect_vec();
+
+        // Build the header, with a number for every fret above the capo
+        let frets = (0..=options.fretboard.frets())
+            .map(|fret| render(&fret.to_string(), foreground))
+            .collect_vec();
+
+        let octaves = Note::iter()
+            .filter(|note| note.get_octave_index() == 0)
+            .map(|note| render(&notation.get_full_name(note), foreground))
+            .collect_vec();
+
+        let paused = render("paused, arrows to scrub, space to resume", foreground);
+
+        Texts {
+            notes,
+            notes_light,
+            frets,
+            octaves,
+            paused,
+            consonant: render("consonant ", foreground),
+            dissonant: render(" dissonant", foreground),
+        }
+    }
+
+    // The name of a note, readable on the given colour
+    fn note(&self, index: usize, color: (u8, u8, u8)) -> &Texture<'a> {
+        if colors::text_color(color) == (255, 255, 255) {
+            &self.notes_light[index % 12]
+        } else {
+            &self.notes[index % 12]
+        }
+    }
+
+    // The full name of a C
+    fn octave(&self, note: Note) -> &Texture<'a> {
+        &self.octaves[note.get_octave() as usize]
+    }
+}
 
 // The display loop, receives data from the fourier thread
 // Hard to abstract further because of rust-sdl safety guards
+// The bracket keys move through the chart if one is followed
 pub fn display(
     sdl: Sdl,
     receiver: Receiver<Scores>,
     options: DisplayOptions,
+    mut monitor: Monitor,
+    chart: Option<Sender<ChartCommand>>,
 ) -> Result<(), String> {
-    // Open windows
+    // Open a single resizable window, using every pixel on high DPI screens
 
     let video_subsystem = sdl.video().unwrap();
 
-    let mut window = video_subsystem
-        .window("ImproVe Fourier", FOURIER_WIDTH, FOURIER_HEIGHT)
-        .position_centered()
-        .build()
-        .unwrap();
-    let pos = window.position();
-    window.set_position(
-        WindowPos::Centered,
-        WindowPos::Positioned(pos.1 - BOARD_HEIGHT as i32 - 100),
-    );
-
-    let mut canvas_fourier = window.into_canvas().build().unwrap();
-
+    let (width, height) = Layout::default_size(options);
     let window = video_subsystem
-        .window("ImproVe Fretboard", BOARD_WIDTH, BOARD_HEIGHT)
+        .window("ImproVe", width, height)
         .position_centered()
+        .resizable()
+        .allow_highdpi()
         .build()
         .unwrap();
 
-    let mut canvas_board = window.into_canvas().build().unwrap();
+    let mut canvas = window.into_canvas().build().unwrap();
+    canvas.present();
 
-    canvas_board.present();
-    canvas_fourier.present();
+    // Compute the layout from the actual size in pixels
+    let mut size = canvas.output_size()?;
+    let mut layout = Layout::new(size, options);
 
     // Build text textures, for use in the loop
 
     // Init the front
     let ttf_context = sdl2::ttf::init().unwrap();
-    let texture_creator = canvas_board.texture_creator();
-    let font = ttf_context.load_font(FONT_NAME, FONT_HEIGHT).unwrap();
-
-    // Build a texture for every note names
-    let textures = options
-        .notation
-        .get_names()
-        .iter()
-        .map(|name| {
-            let surface = font
-                .render(name)
-                .blended(Color::RGBA(30, 30, 30, 255))
-                .unwrap();
-            texture_creator
-                .create_texture_from_surface(&surface)
-                .unwrap()
-        })
-        .collect_vec();
-
-    // Build the header, with numbers from 0 to 43, but with an additional space between 0 and 1
-    let header = std::iter::once(" 0  ".to_string())
-        .chain((1..FRET_COUNT).map(|i| format!("{:^3}", i)))
-        .join("");
-
-    let surface_header = font
-        .render(&header)
-        .blended(Color::RGB(255, 255, 255))
-        .unwrap();
-
-    let texture_header = texture_creator
-        .create_texture_from_surface(&surface_header)
+    let texture_creator = canvas.texture_creator();
+    let mut font = ttf_context
+        .load_font(FONT_NAME, layout.font_height())
         .unwrap();
+    let mut texts = Texts::new(&texture_creator, &font, options);
+
+    // Build the spectrogram and history textures, if needed
+    let mut spectrogram = options
+        .spectrogram
+        .map(|spectrogram| SpectrogramView::new(&texture_creator, spectrogram, options.theme));
+    let mut history = if options.history {
+        Some(HistoryView::new(&texture_creator, options))
+    } else {
+        None
+    };
 
     // Build the event pump, to kill everything elegantly
     let mut events = sdl.event_pump().unwrap();
 
     // Iterate on scores
     for scores in receiver.into_iter() {
+        let start = Instant::now();
+
+        // Update the layout and the text if the window changed size
+        let new_size = canvas.output_size()?;
+        if new_size != size {
+            size = new_size;
+            let font_height = layout.font_height();
+            layout = Layout::new(size, options);
+            if layout.font_height() != font_height {
+                font = ttf_context
+                    .load_font(FONT_NAME, layout.font_height())
+                    .unwrap();
+                texts = Texts::new(&texture_creator, &font, options);
+            }
+        }
+
+        canvas.set_draw_color(Color::from(options.theme.background()));
+        canvas.clear();
+
+        // Draw the score history
+        if let (Some(history), Some(rect)) = (&mut history, layout.history) {
+            history.draw(&mut canvas, rect, &scores, &texts);
+        }
+
         // Draw the fourier frequency graph
-        draw_graph(&mut canvas_fourier, &scores);
+        draw_graph(&mut canvas, layout.graph, &scores, options.theme);
+        draw_legend(&mut canvas, layout.graph, options.colormap, &texts);
+
+        // Draw the suggested notes and the timings over the graph
+        let mut lines = Vec::new();
+        if let Some(label) = &scores.label {
+            lines.push(label.clone());
+        }
+        if !scores.suggestions.is_empty() {
+            let suggestions = options.notation.format_suggestions(&scores.suggestions);
+            lines.push(format!("Best: {}", suggestions));
+        }
+        if monitor.visible {
+            lines.extend(monitor.lines());
+        }
+        draw_text(
+            &mut canvas,
+            &texture_creator,
+            &font,
+            &lines,
+            layout.graph,
+            options.theme,
+        );
+
+        // Draw the pitch classes
+        if let (Some(order), Some(rect)) = (options.circle, layout.circle) {
+            draw_circle(&mut canvas, rect, &scores, &texts, order, options);
+        }
 
         // Draw the fretboard graph
-        draw_board(&mut canvas_board, &scores, &textures, &texture_header);
+        draw_board(&mut canvas, &layout, &scores, &texts, options);
+
+        // Scroll the spectrogram
+        if let (Some(spectrogram), Some(rect)) = (&mut spectrogram, layout.spectrogram) {
+            spectrogram.draw(&mut canvas, rect, &scores, &texts);
+        }
+
+        canvas.present();
+
+        if let Some(stats) = &scores.stats {
+            monitor.record(stats, start.elapsed());
+        }
 
         for event in events.poll_iter() {
             match event {
@@ -154,6 +432,48 @@ pub fn display(
                 } => {
                     return Ok(());
                 }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F3),
+                    ..
+                } => {
+                    monitor.visible = !monitor.visible;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    ..
+                } => {
+                    // Toggle fullscreen, the new size is picked up on the next frame
+                    let window = canvas.window_mut();
+                    let state = match window.fullscreen_state() {
+                        FullscreenType::Off => FullscreenType::Desktop,
+                        _ => FullscreenType::Off,
+                    };
+                    window.set_fullscreen(state)?;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::LeftBracket),
+                    ..
+                } => {
+                    if let Some(chart) = &chart {
+                        chart.send(ChartCommand::Previous).ok();
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::RightBracket),
+                    ..
+                } => {
+                    if let Some(chart) = &chart {
+                        chart.send(ChartCommand::Next).ok();
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    if let Some(history) = &mut history {
+                        history.handle_key(keycode);
+                    }
+                }
                 _ => {}
             }
         }
@@ -165,96 +485,377 @@ pub fn display(
 // Display the fretboard graph
 fn draw_board(
     canvas: &mut Canvas<Window>,
+    layout: &Layout,
     scores: &Scores,
-    texture_notes: &[Texture],
-    texture_header: &Texture,
+    texts: &Texts,
+    options: DisplayOptions,
 ) {
-    // Clear canvas
-    canvas.set_draw_color(Color::RGB(30, 30, 30));
-    canvas.clear();
+    let fret_width = layout.fret_width;
+    let string_height = layout.string_height;
+    let fretboard = options.fretboard;
 
     // Display Header
-    canvas
-        .copy(
-            &texture_header,
-            None,
-            Some(Rect::new(0, 0, BOARD_WIDTH, STRING_HEIGHT)),
-        )
-        .unwrap();
+    for fret in fretboard.columns() {
+        let texture = &texts.frets[fret];
+        let query = texture.query();
+        let center = Point::new(
+            layout.fret_x(fret) + fret_width as i32 / 2,
+            layout.board.y() + string_height as i32 / 2,
+        );
+        let rect = Rect::from_center(center, query.width, query.height);
+        canvas.copy(texture, None, Some(rect)).unwrap();
+    }
 
-    let mut note_scores = scores.note_scores[FIRST_NOTE..LAST_NOTE].to_owned();
+    // Display the inlays, two dots for octaves
+    canvas.set_draw_color(Color::from(options.theme.foreground()));
+    let dot = (string_height / 4).max(1);
+    for fret in fretboard.columns() {
+        let inlays = fretboard.inlays(fret) as i32;
+        let center = layout.fret_x(fret) + fret_width as i32 / 2;
+        for i in 0..inlays {
+            let x = center + (2 * i - inlays + 1) * dot as i32;
+            let y = layout.inlay_y() + (string_height / 4) as i32;
+            canvas
+                .fill_rect(Rect::from_center(Point::new(x, y), dot, dot))
+                .unwrap();
+        }
+    }
+
+    // Normalize the scores over the displayed notes
+    let (lowest, highest) = fretboard.range();
+    let range = lowest as usize..=highest as usize;
+    let mut note_scores = scores.note_scores[range.clone()].to_owned();
     note_scores.normalize();
-    let mut note_values = scores.note_values[FIRST_NOTE..LAST_NOTE].to_owned();
+    let mut note_values = scores.note_values[range].to_owned();
     note_values.normalize();
 
-    let gradient_score = {
-        let gradient_a = Hsv::new(120.0, 1.0, 1.0);
-        let gradient_b = Hsv::new(0.0, 1.0, 1.0);
-        Gradient::new(vec![gradient_a, gradient_b])
-    };
-    // The canvas position
-    let mut pnt = Point::new(0, 0);
-    // Skip first line
-    pnt = pnt.offset(0, STRING_HEIGHT as i32);
+    // The height of the line showing played notes
+    let underline = (string_height / 6).max(1);
 
     // For every guitar strings
-    for &j in STRINGS.iter().rev() {
-        // For every note on that string
-        for i in j..j + FRET_COUNT as usize {
+    for (string, open) in fretboard.strings().into_iter().enumerate() {
+        let y = layout.string_y(string);
+        // For every displayed note on that string
+        for fret in fretboard.columns() {
+            let x = layout.fret_x(fret);
+            let i = fretboard.note(open, fret) as usize;
+
             // Write the name with the appropriate color
 
-            // Get note name and calculated score
-            let texture = &texture_notes[i % 12];
-            let score = note_scores[i - FIRST_NOTE];
+            // Get calculated score and its colour
+            let score = note_scores[i - lowest as usize];
+            let color = options.colormap.get(score);
+            // Get the note name, readable on that colour
+            let texture = texts.note(i, color);
             // Get the colored rectangle coordinates
-            let rect = Rect::new(pnt.x, pnt.y, FRET_WIDTH, STRING_HEIGHT);
-            // Get the gradient color
-            let gradient_poll = gradient_score.get(score);
-            let color: (u8, u8, u8) = Srgb::from(gradient_poll).into_format().into_components();
+            let rect = Rect::new(x, y, fret_width, string_height);
             // Draw tesxt and color to canvas
             canvas.set_draw_color(Color::from(color));
             canvas.fill_rect(rect).unwrap();
-            let rect = Rect::new(pnt.x, pnt.y - 1, FRET_WIDTH, STRING_HEIGHT);
+            let rect = Rect::new(x, y - 1, fret_width, string_height);
             canvas.copy(texture, None, Some(rect)).unwrap();
-            
+
             // Underline notes being played (depending on value)
-            
+
             // Get note value
-            let value = note_values[i - FIRST_NOTE];
+            let value = note_values[i - lowest as usize];
             // Get the colored rectangle coordinates
-            let rect = Rect::new(pnt.x, pnt.y + STRING_HEIGHT as i32 - 3, FRET_WIDTH, 3);
+            let rect = Rect::new(
+                x,
+                y + (string_height - underline) as i32,
+                fret_width,
+                underline,
+            );
             // Get the gradient color
             let color = (value * 255f32) as u8;
             let color: (u8, u8, u8) = (color, color, color);
             // Draw tesxt and color to canvas
             canvas.set_draw_color(Color::from(color));
             canvas.fill_rect(rect).unwrap();
+        }
+    }
+}
+
+fn draw_graph(canvas: &mut Canvas<Window>, rect: Rect, scores: &Scores, theme: Theme) {
+    // Clear graph
+    canvas.set_draw_color(Color::from(theme.panel()));
+    canvas.fill_rect(rect).unwrap();
+
+    draw_notes(canvas, rect, scores, theme);
+}
+
+// Display the 12 pitch classes around a circle, coloured by consonance
+fn draw_circle(
+    canvas: &mut Canvas<Window>,
+    rect: Rect,
+    scores: &Scores,
+    texts: &Texts,
+    order: CircleOrder,
+    options: DisplayOptions,
+) {
+    canvas.set_draw_color(Color::from(options.theme.panel()));
+    canvas.fill_rect(rect).unwrap();
+
+    let mut pitch_classes = scores.pitch_classes.to_vec();
+    pitch_classes.normalize();
+
+    // Every pitch class is a square on the circle, starting from the top
+    let size = rect.width().min(rect.height());
+    let cell = (size / 6).max(1);
+    let radius = size.saturating_sub(cell) as f32 / 2f32 - 2f32;
+    let center = rect.center();
+    for (i, &class) in order.pitch_classes().iter().enumerate() {
+        let angle = i as f32 * std::f32::consts::PI / 6f32;
+        let x = center.x() + (radius * angle.sin()) as i32;
+        let y = center.y() - (radius * angle.cos()) as i32;
+        let color = options.colormap.get(pitch_classes[class]);
+        canvas.set_draw_color(Color::from(color));
+        canvas
+            .fill_rect(Rect::from_center(Point::new(x, y), cell, cell))
+            .unwrap();
+
+        // Write the name in the middle of the square
+        let texture = texts.note(class, color);
+        let query = texture.query();
+        let label = Rect::from_center(Point::new(x, y), query.width, query.height);
+        canvas.copy(texture, None, Some(label)).unwrap();
+    }
+}
 
-            // Add the bar to differentiate the zero 'fret' from the rest
-            if i == j {
-                pnt = pnt.offset(FRET_LINE as i32, 0);
+// Display the colours of the scores in the top right corner of a panel
+fn draw_legend(canvas: &mut Canvas<Window>, panel: Rect, colormap: ColorMap, texts: &Texts) {
+    let consonant = texts.consonant.query();
+    let dissonant = texts.dissonant.query();
+    let width = (panel.width() / 4).min(256);
+    let height = consonant.height;
+
+    // Lay the labels on both sides of the colour bar
+    let y = panel.top() + 4;
+    let right = panel.right() - 4 - dissonant.width as i32;
+    let left = right - width as i32;
+    let label = Rect::new(left - consonant.width as i32, y, consonant.width, height);
+    canvas.copy(&texts.consonant, None, Some(label)).unwrap();
+    let label = Rect::new(right, y, dissonant.width, height);
+    canvas.copy(&texts.dissonant, None, Some(label)).unwrap();
+
+    for x in 0..width {
+        let color = colormap.get(x as f32 / (width - 1).max(1) as f32);
+        canvas.set_draw_color(Color::from(color));
+        let x = left + x as i32;
+        canvas
+            .draw_line(Point::new(x, y), Point::new(x, y + height as i32 - 1))
+            .unwrap();
+    }
+}
+
+// A scrolling log-frequency spectrogram, with a line for every C
+struct SpectrogramView<'a> {
+    spectrogram: Spectrogram,
+    colormap: ColorMap,
+    theme: Theme,
+    // The displayed image, updated every frame
+    texture: Texture<'a>,
+    pixels: Vec<u8>,
+}
+
+impl<'a> SpectrogramView<'a> {
+    fn new(
+        texture_creator: &'a TextureCreator<WindowContext>,
+        options: SpectrogramOptions,
+        theme: Theme,
+    ) -> SpectrogramView<'a> {
+        let spectrogram = Spectrogram::new(SPECTROGRAM_ROWS, options.db_range);
+        let texture = texture_creator
+            .create_texture_streaming(
+                PixelFormatEnum::RGB24,
+                SPECTROGRAM_COLUMNS,
+                SPECTROGRAM_ROWS,
+            )
+            .unwrap();
+        // Start with the colour of silence
+        let (r, g, b) = options.colormap.get(0f32);
+        let pixels = [r, g, b].repeat((SPECTROGRAM_COLUMNS * SPECTROGRAM_ROWS) as usize);
+        SpectrogramView {
+            spectrogram,
+            colormap: options.colormap,
+            theme,
+            texture,
+            pixels,
+        }
+    }
+
+    // Add a column for the latest frame, and draw it in the given rectangle
+    fn draw(&mut self, canvas: &mut Canvas<Window>, rect: Rect, scores: &Scores, texts: &Texts) {
+        let column = self.spectrogram.column(&scores.fourier);
+
+        // Shift every row to the left, and write the new pixel at the end
+        let pitch = SPECTROGRAM_COLUMNS as usize * 3;
+        for (row, value) in self.pixels.chunks_mut(pitch).zip(column) {
+            row.copy_within(3.., 0);
+            let (r, g, b) = self.colormap.get(value);
+            row[pitch - 3..].copy_from_slice(&[r, g, b]);
+        }
+        self.texture.update(None, &self.pixels, pitch).unwrap();
+        canvas.copy(&self.texture, None, Some(rect)).unwrap();
+
+        // Draw the note gridlines and their names
+        canvas.set_draw_color(Color::from(self.theme.grid()));
+        for note in Note::iter().filter(|note| note.get_octave_index() == 0) {
+            if let Some(row) = self.spectrogram.row_of(note.freq()) {
+                let y = rect.y() + row * rect.height() as i32 / SPECTROGRAM_ROWS as i32;
+                draw_gridline(canvas, rect, y, texts.octave(note));
             }
-            pnt = pnt.offset(FRET_WIDTH as i32, 0);
         }
-        pnt = Point::new(0, pnt.y() + STRING_HEIGHT as i32);
     }
-    canvas.present();
 }
 
-fn draw_graph(canvas: &mut Canvas<Window>, scores: &Scores) {
-    // Clear graph
-    canvas.set_draw_color(Color::RGB(0, 0, 0));
-    canvas.clear();
+// The scores of the recent frames, as a heat map of notes over time
+struct HistoryView<'a> {
+    history: ScoreHistory,
+    // The score colours, precomputed for speed
+    palette: Vec<(u8, u8, u8)>,
+    theme: Theme,
+    // The range of notes displayed, from the fretboard
+    first_note: usize,
+    last_note: usize,
+    // The displayed image, with a pixel per frame and note
+    texture: Texture<'a>,
+    pixels: Vec<u8>,
+}
 
-    draw_notes(canvas, scores);
+impl<'a> HistoryView<'a> {
+    fn new(
+        texture_creator: &'a TextureCreator<WindowContext>,
+        options: DisplayOptions,
+    ) -> HistoryView<'a> {
+        let (lowest, highest) = options.fretboard.range();
+        let (first_note, last_note) = (lowest as usize, highest as usize + 1);
+        let note_count = (last_note - first_note) as u32;
+        let texture = texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, HISTORY_COLUMNS, note_count)
+            .unwrap();
+        let palette = (0..=255)
+            .map(|i| options.colormap.get(i as f32 / 255f32))
+            .collect_vec();
+        HistoryView {
+            history: ScoreHistory::new(HISTORY_LENGTH),
+            palette,
+            theme: options.theme,
+            first_note,
+            last_note,
+            texture,
+            pixels: vec![0; (HISTORY_COLUMNS * note_count * 3) as usize],
+        }
+    }
 
-    // Flush
-    canvas.present();
+    // Pause with space, scrub with the arrows and page keys, go back to live with end
+    fn handle_key(&mut self, keycode: Keycode) {
+        match keycode {
+            Keycode::Space => self.history.toggle_pause(),
+            Keycode::Left => self.history.scrub(-1),
+            Keycode::Right => self.history.scrub(1),
+            Keycode::PageUp => self.history.scrub(-(HISTORY_COLUMNS as isize) / 4),
+            Keycode::PageDown => self.history.scrub(HISTORY_COLUMNS as isize / 4),
+            Keycode::End => self.history.resume(),
+            _ => {}
+        }
+    }
+
+    // Record the latest frame, and draw the history in the given rectangle
+    fn draw(&mut self, canvas: &mut Canvas<Window>, rect: Rect, scores: &Scores, texts: &Texts) {
+        let note_count = self.last_note - self.first_note;
+        let mut note_scores = scores.note_scores[self.first_note..self.last_note].to_owned();
+        note_scores.normalize();
+        self.history.push(note_scores);
+
+        // Fill the image column by column, highest notes at the top
+        let pitch = HISTORY_COLUMNS as usize * 3;
+        for (x, frame) in self.history.view(HISTORY_COLUMNS as usize).enumerate() {
+            for y in 0..note_count {
+                let color = match frame {
+                    Some(frame) => {
+                        let score = frame[note_count - 1 - y];
+                        let score = if score.is_nan() { 0f32 } else { score.clamp(0f32, 1f32) };
+                        self.palette[(score * 255f32) as usize]
+                    }
+                    None => self.theme.background(),
+                };
+                let i = y * pitch + x * 3;
+                self.pixels[i..i + 3].copy_from_slice(&[color.0, color.1, color.2]);
+            }
+        }
+        self.texture.update(None, &self.pixels, pitch).unwrap();
+        canvas.copy(&self.texture, None, Some(rect)).unwrap();
+
+        // Draw a line under every C, and its name
+        canvas.set_draw_color(Color::from(self.theme.grid()));
+        for note in Note::iter()
+            .skip(self.first_note)
+            .take(note_count)
+            .filter(|note| note.get_octave_index() == 0)
+        {
+            let row = (self.last_note - note as usize) as i32;
+            let y = rect.y() + row * rect.height() as i32 / note_count as i32;
+            draw_gridline(canvas, rect, y, texts.octave(note));
+        }
+
+        // Show how to get out of the paused state
+        if self.history.is_paused() {
+            let query = texts.paused.query();
+            let x = rect.right() - query.width as i32 - 4;
+            let label = Rect::new(x, rect.y() + 4, query.width, query.height);
+            canvas.set_draw_color(Color::from(self.theme.panel()));
+            canvas.fill_rect(label).unwrap();
+            canvas.copy(&texts.paused, None, Some(label)).unwrap();
+        }
+    }
+}
+
+// Draw a horizontal line across a panel, with a label sitting on it
+fn draw_gridline(canvas: &mut Canvas<Window>, rect: Rect, y: i32, label: &Texture) {
+    canvas
+        .draw_line(Point::new(rect.left(), y), Point::new(rect.right(), y))
+        .unwrap();
+    let query = label.query();
+    let label_rect = Rect::new(
+        rect.left() + 2,
+        y - query.height as i32,
+        query.width,
+        query.height,
+    );
+    canvas.copy(label, None, Some(label_rect)).unwrap();
+}
+
+// Display lines of text in the top left corner of a panel
+fn draw_text(
+    canvas: &mut Canvas<Window>,
+    texture_creator: &TextureCreator<WindowContext>,
+    font: &Font,
+    lines: &[String],
+    panel: Rect,
+    theme: Theme,
+) {
+    let mut pnt = panel.top_left().offset(4, 4);
+    for line in lines {
+        let surface = font
+            .render(line)
+            .blended(Color::from(theme.foreground()))
+            .unwrap();
+        let rect = Rect::new(pnt.x, pnt.y, surface.width(), surface.height());
+        let texture = texture_creator
+            .create_texture_from_surface(&surface)
+            .unwrap();
+        // Hide the graph behind the text for readability
+        canvas.set_draw_color(Color::from(theme.panel()));
+        canvas.fill_rect(rect).unwrap();
+        canvas.copy(&texture, None, Some(rect)).unwrap();
+        pnt = pnt.offset(0, surface.height() as i32);
+    }
 }
 
 // Display the fourier graph
 #[allow(dead_code)]
-fn draw_fourier(canvas: &mut Canvas<Window>, scores: &Scores) {
+fn draw_fourier(canvas: &mut Canvas<Window>, rect: Rect, scores: &Scores) {
     canvas.set_draw_color(Color::RGB(30, 255, 30));
 
     // Skip boring frequencies
@@ -303,10 +904,10 @@ fn draw_fourier(canvas: &mut Canvas<Window>, scores: &Scores) {
             Point::new(
                 f.value.map_interval(
                     min_hz ..= max_hz,
-                    0 ..= FOURIER_WIDTH as i32 - 1),
+                    rect.left() ..= rect.right() - 1),
                 f.intensity.map_interval_rev(
                     0f32 ..= max_vo,
-                    0 ..= FOURIER_HEIGHT as i32 - 1,
+                    rect.top() ..= rect.bottom() - 1,
                 ),
             )
         })
@@ -320,19 +921,20 @@ fn draw_fourier(canvas: &mut Canvas<Window>, scores: &Scores) {
 // Various graphs, mostly for debugging.
 
 #[allow(dead_code)]
-pub fn draw_pure_dissonance_graph(canvas: &mut Canvas<Window>, _: &Scores) {
+pub fn draw_pure_dissonance_graph(canvas: &mut Canvas<Window>, rect: Rect, _: &Scores) {
+    let width = rect.width() as i32;
     // Draw data points
     let points = [50f32, 100f32, 200f32, 400f32, 800f32, 1600f32]
         .iter()
         .cloned()
         .map(|f_1| {
-            (0..FOURIER_WIDTH as i32)
+            (0..width)
                 .zip(std::iter::repeat(f_1))
                 .map(|(x, f_1)| {
-                    let factor = 3f32 * x as f32 / FOURIER_WIDTH as f32;
+                    let factor = 3f32 * x as f32 / width as f32;
                     let yf = 1f32 - crate::dissonance::dissonance(f_1, f_1 * factor);
-                    let y = (yf * FOURIER_HEIGHT as f32) as i32;
-                    Point::new(x, y)
+                    let y = (yf * rect.height() as f32) as i32;
+                    Point::new(rect.left() + x, rect.top() + y)
                 })
         })
         .flatten()
@@ -340,7 +942,7 @@ pub fn draw_pure_dissonance_graph(canvas: &mut Canvas<Window>, _: &Scores) {
 
     for (points, i) in points
         .iter()
-        .chunks(FOURIER_WIDTH as usize)
+        .chunks(width as usize)
         .into_iter()
         .zip((0..255).step_by(255 / 6))
     {
@@ -351,23 +953,25 @@ pub fn draw_pure_dissonance_graph(canvas: &mut Canvas<Window>, _: &Scores) {
 }
 
 #[allow(dead_code)]
-pub fn draw_notes(canvas: &mut Canvas<Window>, scores: &Scores) {
+pub fn draw_notes(canvas: &mut Canvas<Window>, rect: Rect, scores: &Scores, theme: Theme) {
+    let width = rect.width();
+
     let mut notes = scores.note_scores.to_vec();
     notes.truncate(crate::notes::NOTE_COUNT - 12);
     notes.normalize();
 
-    let points = (0..FOURIER_WIDTH)
+    let points = (0..width)
         .map(|x| {
-            let i = x.map_interval(0..=FOURIER_WIDTH-1, 0..=notes.len() - 1);
+            let i = x.map_interval(0..=width-1, 0..=notes.len() - 1);
             let y = notes[i].map_interval_rev(
                 0f32 ..= 1f32,
-                0 ..= FOURIER_HEIGHT as i32 - 1,
+                rect.top() ..= rect.bottom() - 1,
             );
-            Point::new(x as i32, y)
+            Point::new(rect.left() + x as i32, y)
         })
         .collect_vec();
 
-    canvas.set_draw_color(Color::RGB(255, 255, 255));
+    canvas.set_draw_color(Color::from(theme.foreground()));
 
     canvas.draw_lines(points.as_slice()).unwrap();
 
@@ -375,14 +979,14 @@ pub fn draw_notes(canvas: &mut Canvas<Window>, scores: &Scores) {
     notes.truncate(crate::notes::NOTE_COUNT - 12);
     notes.normalize();
 
-    let points = (0..FOURIER_WIDTH)
+    let points = (0..width)
         .map(|x| {
-            let i = x.map_interval(0 ..= FOURIER_WIDTH-1, 0 ..= notes.len() - 1);
+            let i = x.map_interval(0 ..= width-1, 0 ..= notes.len() - 1);
             let y = notes[i].map_interval_rev(
                 0f32 ..= 1f32,
-                0 ..= FOURIER_HEIGHT as i32 - 1,
+                rect.top() ..= rect.bottom() - 1,
             );
-            Point::new(x as i32, y)
+            Point::new(rect.left() + x as i32, y)
         })
         .collect_vec();
 
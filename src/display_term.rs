@@ -1,75 +1,507 @@
 // The terminal display loop
 
-use crate::display::DisplayOptions;
-use crate::notes::Note::*;
+use crate::chart::ChartCommand;
+use crate::colors::{self, ColorMap, Theme};
+use crate::display::{CircleOrder, DisplayOptions, Notation};
+use crate::instrument::Fretboard;
+use crate::messages;
 use crate::notes::{Note, NOTE_COUNT};
 use crate::scores::Scores;
+use crate::stats::Monitor;
+use crate::tools::Normalizable;
 
 use std::io;
-use std::io::BufWriter;
+use std::io::Stdout;
 use std::io::Write;
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-// Number of notes in line
-const GUITAR_STRING_LENGTH: usize = 44;
-// Every string defined by their note (E2 to E4)
-const GUITAR_STRINGS: [Note; 6] = [E2, A2, D3, G3, B3, E4];
+use termion::cursor::HideCursor;
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::{IntoRawMode, RawTerminal};
+use termion::screen::AlternateScreen;
 
-// Clear terminal and display guitar
-fn guitar(scores: &[f32; NOTE_COUNT], options: DisplayOptions) {
-    // Create buffer to avoid flicker
-    let mut buffer = BufWriter::new(io::stdout());
+// Width of a note, and of the open string with its bar
+const NOTE_WIDTH: u16 = 3;
+const OPEN_WIDTH: u16 = NOTE_WIDTH + 1;
 
-    // Add the clear screen message to the buffer
-    if options.clear_term {
-        write!(
-            &mut buffer,
-            "{}",
-            termion::cursor::Up(GUITAR_STRINGS.len() as u16 + 1)
-        )
-        .unwrap();
+// The size used when the terminal can't be queried
+const DEFAULT_SIZE: (u16, u16) = (80, 24);
+
+// How often keys are read while no frame arrives
+const KEY_POLL: Duration = Duration::from_millis(50);
+
+const HELP: &str =
+    "q: quit  \u{2190}\u{2192}: scroll  home: nut  n: notation  c: colours  t: theme  s: stats";
+const CHART_HELP: &str = "[ ]: previous and next beat";
+
+// The number of cells in the colour legend
+const LEGEND_WIDTH: usize = 24;
+
+// The terminal in raw mode, restored when dropped
+// Shared with the panic hook, so that a panic doesn't leave the terminal unusable
+type Terminal = Arc<Mutex<Option<HideCursor<AlternateScreen<RawTerminal<Stdout>>>>>>;
+
+// The frets visible in the terminal, within those of the fretboard
+// The open strings are always shown
+#[derive(Clone, Copy, Debug)]
+struct FretWindow {
+    fretboard: Fretboard,
+    // The first fret displayed after the open string
+    first: usize,
+    // The number of frets displayed after the open string
+    count: usize,
+}
+
+impl FretWindow {
+    // Show as many frets as fit in the width, keeping the first fret if possible
+    fn new(width: u16, first: usize, fretboard: Fretboard) -> FretWindow {
+        let count = (width.saturating_sub(OPEN_WIDTH) / NOTE_WIDTH) as usize;
+        let count = count.min(fretboard.column_count() - 1);
+        FretWindow {
+            fretboard,
+            first: 0,
+            count,
+        }
+        .scroll_to(first)
+    }
+
+    // Move the window by a number of frets
+    fn scroll(self, frets: isize) -> FretWindow {
+        self.scroll_to((self.first as isize + frets).max(0) as usize)
+    }
+
+    // Move the window to a fret, without leaving the fretboard
+    fn scroll_to(self, first: usize) -> FretWindow {
+        let last = self.fretboard.last + 1 - self.count;
+        let first = first.clamp(self.fretboard.first, last);
+        FretWindow { first, ..self }
+    }
+
+    // The frets to display, starting with the open string
+    fn frets(self) -> impl Iterator<Item = usize> {
+        std::iter::once(0).chain(self.first..self.first + self.count)
+    }
+}
+
+// Write the guitar to the buffer, followed by optional status lines
+// Every line ends with the given line break, and clears what remains of the previous frame
+fn guitar(
+    buffer: &mut Vec<u8>,
+    scores: &[f32; NOTE_COUNT],
+    options: DisplayOptions,
+    window: FretWindow,
+    status: &[&str],
+    newline: &str,
+) {
+    let clear = termion::clear::UntilNewline;
+    // The colours of the text around the guitar
+    let style = theme_style(options.theme);
+    write!(buffer, "{}", style).unwrap();
+
+    // Left handed fretboards start from the right
+    let mut frets = window.frets().collect::<Vec<_>>();
+    if options.left_handed {
+        frets.reverse();
+    }
+    // The highest string is at the top, as in tablatures, unless flipped
+    let fretboard = options.fretboard;
+    let mut strings = fretboard.strings();
+    if !options.flip_strings {
+        strings.reverse();
     }
 
     // Display the fret count
-    write!(&mut buffer, " 0 |").unwrap();
-    for i in 1..GUITAR_STRING_LENGTH {
-        write!(&mut buffer, "{:^3}", i).unwrap();
+    for &fret in frets.iter() {
+        match (fret, options.left_handed) {
+            (0, false) => write!(buffer, " 0 |").unwrap(),
+            (0, true) => write!(buffer, "| 0 ").unwrap(),
+            _ => write!(buffer, "{:^3}", fret).unwrap(),
+        }
     }
-    writeln!(&mut buffer).unwrap();
+    write!(buffer, "{}{}", clear, newline).unwrap();
 
     // For every guitar strings
-    for &string in GUITAR_STRINGS.iter().rev() {
-        // For every note on that string
-        for note in string.iter_from().take(GUITAR_STRING_LENGTH) {
+    for string in strings {
+        // For every visible note on that string
+        for &fret in frets.iter() {
+            let note = fretboard.note(string, fret);
+            // Add the bar to differentiate the zero 'fret' from the rest
+            if fret == 0 && options.left_handed {
+                write!(buffer, "{}|", style).unwrap();
+            }
             // Get note name and calculated score
             let name = options.notation.get_name(note);
-            let score = scores[note as usize];
-            let score = score.max(0f32).min(1f32);
+            let color = options.colormap.get(scores[note as usize]);
             // Write the name with the appropriate color
-            let gradient = (score * 255f32) as u8;
             write!(
-                &mut buffer,
-                "\x1b[30;48;2;{red};{green};{blue}m{name}",
-                red = gradient,
-                green = (255 - gradient),
-                blue = gradient / 4,
-                name = name
+                buffer,
+                "{}{}{}",
+                foreground(colors::text_color(color)),
+                background(color),
+                name
             )
             .unwrap();
-            // Add the bar to differentiate the zero 'fret' from the rest
-            if string == note {
-                write!(&mut buffer, "\x1b[0;0m|").unwrap();
+            if fret == 0 && !options.left_handed {
+                write!(buffer, "{}|", style).unwrap();
             }
         }
-        writeln!(&mut buffer, "\x1b[0;0m").unwrap();
+        write!(buffer, "{}{}{}", style, clear, newline).unwrap();
+    }
+
+    // Display the inlays, two dots for octaves
+    for &fret in frets.iter() {
+        let inlay = match fretboard.inlays(fret) {
+            0 => "   ",
+            1 => " \u{2022} ",
+            _ => "\u{2022} \u{2022}",
+        };
+        match (fret, options.left_handed) {
+            (0, false) => write!(buffer, "{} ", inlay).unwrap(),
+            (0, true) => write!(buffer, " {}", inlay).unwrap(),
+            _ => write!(buffer, "{}", inlay).unwrap(),
+        }
+    }
+    write!(buffer, "{}{}", clear, newline).unwrap();
+
+    for line in status {
+        write!(buffer, "{}{}{}", line, clear, newline).unwrap();
+    }
+    write!(buffer, "{}", termion::style::Reset).unwrap();
+}
+
+// The escape codes setting true colours
+fn foreground((r, g, b): (u8, u8, u8)) -> String {
+    format!("\x1b[38;2;{};{};{}m", r, g, b)
+}
+
+fn background((r, g, b): (u8, u8, u8)) -> String {
+    format!("\x1b[48;2;{};{};{}m", r, g, b)
+}
+
+fn theme_style(theme: Theme) -> String {
+    foreground(theme.foreground()) + &background(theme.background())
+}
+
+// The colours of the scores, from consonant to dissonant
+fn legend(colormap: ColorMap, theme: Theme) -> String {
+    let bar = (0..LEGEND_WIDTH)
+        .map(|i| background(colormap.get(i as f32 / (LEGEND_WIDTH - 1) as f32)) + " ")
+        .collect::<String>();
+    format!("consonant {}{} dissonant", bar, theme_style(theme))
+}
+
+// The 12 pitch classes in the order of the circle, coloured by consonance
+fn circle(scores: &Scores, order: CircleOrder, options: DisplayOptions) -> String {
+    let mut pitch_classes = scores.pitch_classes.to_vec();
+    pitch_classes.normalize();
+    let notes = Note::iter().take(12).collect::<Vec<_>>();
+    let cells = order
+        .pitch_classes()
+        .iter()
+        .map(|&class| {
+            let color = options.colormap.get(pitch_classes[class]);
+            format!(
+                "{}{}{}",
+                foreground(colors::text_color(color)),
+                background(color),
+                options.notation.get_name(notes[class])
+            )
+        })
+        .collect::<String>();
+    format!("Circle: {}{}", cells, theme_style(options.theme))
+}
+
+// The status lines below the guitar
+fn status_lines(
+    scores: &Scores,
+    monitor: &Monitor,
+    options: DisplayOptions,
+    help: bool,
+) -> Vec<String> {
+    let mut lines = Vec::new();
+    if let Some(label) = &scores.label {
+        lines.push(label.clone());
+    }
+    if !scores.suggestions.is_empty() {
+        let suggestions = options.notation.format_suggestions(&scores.suggestions);
+        lines.push(format!("Best: {}", suggestions));
+    }
+    if let Some(order) = options.circle {
+        lines.push(circle(scores, order, options));
+    }
+    if monitor.visible {
+        lines.push(monitor.lines().join("  "));
+    }
+    if help {
+        lines.push(legend(options.colormap, options.theme));
+        lines.push(HELP.to_string());
+        if scores.label.is_some() {
+            lines.push(CHART_HELP.to_string());
+        }
     }
-    buffer.flush().unwrap();
+    lines
 }
 
-// Simply feeds the scores into the guitar display
-pub fn display(receiver: Receiver<Scores>, options: DisplayOptions) -> Result<(), String> {
+// Takes over the terminal, and feeds the scores into the guitar display
+// With the noclear option, every frame is printed below the previous one instead
+// The keys move through the chart if one is followed
+pub fn display(
+    receiver: Receiver<Scores>,
+    options: DisplayOptions,
+    monitor: Monitor,
+    chart: Option<Sender<ChartCommand>>,
+) -> Result<(), String> {
+    if options.suggestions_only {
+        suggestions(receiver, options)
+    } else if options.clear_term {
+        interactive(receiver, options, monitor, chart)
+    } else {
+        print(receiver, options, monitor)
+    }
+}
+
+// Print the suggested notes whenever they change, and nothing else
+fn suggestions(receiver: Receiver<Scores>, options: DisplayOptions) -> Result<(), String> {
+    let stdout = io::stdout();
+    let mut previous = Vec::new();
     for scores in receiver.into_iter() {
-        guitar(&scores.note_scores, options);
+        let notes = scores
+            .suggestions
+            .iter()
+            .map(|suggestion| suggestion.note)
+            .collect::<Vec<_>>();
+        if notes != previous {
+            let line = options.notation.format_suggestions(&scores.suggestions);
+            writeln!(stdout.lock(), "{}", line).map_err(|e| e.to_string())?;
+            previous = notes;
+        }
+    }
+    Ok(())
+}
+
+// Print every frame, without reading the keyboard
+fn print(
+    receiver: Receiver<Scores>,
+    options: DisplayOptions,
+    mut monitor: Monitor,
+) -> Result<(), String> {
+    let stdout = io::stdout();
+    for scores in receiver.into_iter() {
+        let start = Instant::now();
+        let (width, _) = termion::terminal_size().unwrap_or(DEFAULT_SIZE);
+        let window = FretWindow::new(width, 1, options.fretboard);
+        let status = status_lines(&scores, &monitor, options, false);
+        let status = status.iter().map(String::as_str).collect::<Vec<_>>();
+
+        let mut buffer = Vec::new();
+        guitar(
+            &mut buffer,
+            &scores.note_scores,
+            options,
+            window,
+            &status,
+            "\n",
+        );
+        stdout
+            .lock()
+            .write_all(&buffer)
+            .map_err(|e| e.to_string())?;
+        if let Some(stats) = &scores.stats {
+            monitor.record(stats, start.elapsed());
+        }
+    }
+    Ok(())
+}
+
+// Draw every frame in place, and handle keystrokes between frames
+fn interactive(
+    receiver: Receiver<Scores>,
+    mut options: DisplayOptions,
+    mut monitor: Monitor,
+    chart: Option<Sender<ChartCommand>>,
+) -> Result<(), String> {
+    let raw = io::stdout().into_raw_mode().map_err(|e| e.to_string())?;
+    let terminal: Terminal = Arc::new(Mutex::new(Some(HideCursor::from(AlternateScreen::from(
+        raw,
+    )))));
+
+    // Restore the terminal before the panic message is printed
+    let previous_hook = Arc::new(std::panic::take_hook());
+    {
+        let terminal = terminal.clone();
+        let previous_hook = previous_hook.clone();
+        std::panic::set_hook(Box::new(move |info| {
+            if let Ok(mut terminal) = terminal.try_lock() {
+                terminal.take();
+            }
+            previous_hook(info);
+        }));
+    }
+
+    // The other threads can't print over the fretboard
+    messages::capture(true);
+    let result = draw_loop(&terminal, receiver, &mut options, &mut monitor, chart);
+
+    // Put everything back the way it was
+    let _ = std::panic::take_hook();
+    if let Ok(hook) = Arc::try_unwrap(previous_hook) {
+        std::panic::set_hook(hook);
+    }
+    terminal.lock().map_err(|e| e.to_string())?.take();
+    messages::capture(false);
+    // The last message may explain why the analysis stopped
+    if let Some(message) = messages::take() {
+        eprintln!("{}", message);
+    }
+    result
+}
+
+fn draw_loop(
+    terminal: &Terminal,
+    receiver: Receiver<Scores>,
+    options: &mut DisplayOptions,
+    monitor: &mut Monitor,
+    chart: Option<Sender<ChartCommand>>,
+) -> Result<(), String> {
+    let mut keys = termion::async_stdin().keys();
+    let mut size = (0, 0);
+    let mut window = FretWindow::new(DEFAULT_SIZE.0, 1, options.fretboard);
+    let direction = if options.left_handed { -1 } else { 1 };
+    // The message shown before the first frame
+    let mut shown = None;
+
+    loop {
+        // Handle every key pressed since the last frame
+        // Keys are read even without frames, as while the table is built or when audio stalls
+        for key in keys.by_ref() {
+            match key.map_err(|e| e.to_string())? {
+                Key::Char('q') | Key::Esc | Key::Ctrl('c') => return Ok(()),
+                // The arrows move towards the side they point to, even on a mirrored fretboard
+                Key::Left => window = window.scroll(-direction),
+                Key::Right => window = window.scroll(direction),
+                Key::PageUp => window = window.scroll(-(window.count as isize)),
+                Key::PageDown => window = window.scroll(window.count as isize),
+                Key::Home => window = window.scroll_to(0),
+                Key::End => window = window.scroll_to(usize::MAX),
+                Key::Char('n') => {
+                    options.notation = match options.notation {
+                        Notation::English => Notation::Romance,
+                        Notation::Romance => Notation::English,
+                    }
+                }
+                Key::Char('c') => options.colormap = options.colormap.next(),
+                Key::Char('t') => {
+                    options.theme = match options.theme {
+                        Theme::Dark => Theme::Light,
+                        Theme::Light => Theme::Dark,
+                    };
+                    // Paint the whole background again
+                    size = (0, 0);
+                }
+                Key::Char('s') => {
+                    monitor.visible = !monitor.visible;
+                    // The status line may disappear, clear it
+                    size = (0, 0);
+                }
+                Key::Char('[') => {
+                    if let Some(chart) = &chart {
+                        chart.send(ChartCommand::Previous).ok();
+                    }
+                }
+                Key::Char(']') => {
+                    if let Some(chart) = &chart {
+                        chart.send(ChartCommand::Next).ok();
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let scores = match receiver.recv_timeout(KEY_POLL) {
+            Ok(scores) => scores,
+            Err(RecvTimeoutError::Timeout) => {
+                // Until the first frame, show what the analysis is doing
+                let message = messages::recent();
+                if size == (0, 0) && message != shown {
+                    if let Some(message) = &message {
+                        waiting(terminal, *options, message)?;
+                    }
+                    shown = message;
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        let start = Instant::now();
+
+        let mut buffer = Vec::new();
+
+        // Clear everything and fit the frets again when the terminal is resized
+        let new_size = termion::terminal_size().unwrap_or(DEFAULT_SIZE);
+        if new_size != size {
+            size = new_size;
+            window = FretWindow::new(size.0, window.first, options.fretboard);
+            write!(
+                buffer,
+                "{}{}",
+                theme_style(options.theme),
+                termion::clear::All
+            )
+            .unwrap();
+        }
+        write!(buffer, "{}", termion::cursor::Goto(1, 1)).unwrap();
+
+        // Only show the status lines that fit, the latest message first
+        let mut status = status_lines(&scores, monitor, *options, true);
+        if let Some(message) = messages::recent() {
+            status.insert(0, message);
+        }
+        // The fretboard takes a line for every string, and two for the frets and the inlays
+        let strings = options.fretboard.instrument.strings().len();
+        let room = (size.1 as usize).saturating_sub(strings + 2);
+        let status = status
+            .iter()
+            .take(room)
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+
+        guitar(
+            &mut buffer,
+            &scores.note_scores,
+            *options,
+            window,
+            &status,
+            "\r\n",
+        );
+
+        // Write the frame at once to avoid flicker
+        if let Some(terminal) = terminal.lock().map_err(|e| e.to_string())?.as_mut() {
+            terminal.write_all(&buffer).map_err(|e| e.to_string())?;
+            terminal.flush().map_err(|e| e.to_string())?;
+        }
+
+        if let Some(stats) = &scores.stats {
+            monitor.record(stats, start.elapsed());
+        }
+    }
+}
+
+// Show the latest message on an empty screen, before the first frame
+fn waiting(terminal: &Terminal, options: DisplayOptions, message: &str) -> Result<(), String> {
+    if let Some(terminal) = terminal.lock().map_err(|e| e.to_string())?.as_mut() {
+        write!(
+            terminal,
+            "{}{}{}{}",
+            theme_style(options.theme),
+            termion::clear::All,
+            termion::cursor::Goto(1, 1),
+            message
+        )
+        .and_then(|_| terminal.flush())
+        .map_err(|e| e.to_string())?;
     }
     Ok(())
 }
@@ -12,6 +12,7 @@ Further improvements include a more scientific data source, varying instruments.
 
 use crate::frequency::Frequency;
 use crate::notes::{Note, NOTE_COUNT};
+use crate::tools::parallel_map;
 use itertools::Itertools;
 use std::f32::consts;
 
@@ -61,7 +62,8 @@ pub fn dissonance_opt(f_1: f32, s_1: f32, f_2: f32, s_2: f32) -> f32 {
 }
 
 // Returns a 2D array mapping played notes and frequency index to dissonance score
-pub fn dissonance_scores(heard: &[Frequency]) -> Vec<Vec<f32>> {
+// Notes are split between threads, which doesn't change the result
+pub fn dissonance_scores(heard: &[Frequency], threads: usize) -> Vec<Vec<f32>> {
     // Note that the intensity of the 'heard' frequency is ignored here
     // We are only building a table of the scores of those frequencies
 
@@ -78,25 +80,58 @@ pub fn dissonance_scores(heard: &[Frequency]) -> Vec<Vec<f32>> {
         })
         .collect_vec();
 
-    // Prepare the 2D array
-    let mut scores = vec![Vec::with_capacity(heard.len()); NOTE_COUNT];
-
     // For every note the user could play
-    for note in Note::iter() {
-        let played = harmonics[note as usize];
+    let notes = Note::iter().collect_vec();
+    parallel_map(NOTE_COUNT, threads, |i| {
+        note_scores(&heard_buffered, &harmonics[notes[i] as usize])
+    })
+}
 
-        // For every played frequencies, cache the same s value
-        let played_buffered = played
+// Returns the dissonance of a few weighted partials to every note, without a lookup table
+// Cheaper than the table when there are far fewer partials than fft bins
+pub fn sparse_dissonance_scores(
+    partials: &[Frequency],
+    harmonics: &Harmonics,
+    threads: usize,
+) -> Vec<f32> {
+    // For each partial, cache the 's' value of the PL curve
+    let partials_buffered = partials
+        .iter()
+        .cloned()
+        .map(|f| {
+            let s = D_S / (S1 * f.value + S2);
+            (f, s)
+        })
+        .collect_vec();
+
+    // For every note the user could play, weigh the dissonance by the partial intensity
+    let notes = Note::iter().collect_vec();
+    parallel_map(NOTE_COUNT, threads, |i| {
+        note_scores(&partials_buffered, &harmonics[notes[i] as usize])
             .iter()
-            .cloned()
-            .map(|f| {
-                let s = D_S / (S1 * f.value + S2);
-                (f, s)
-            })
-            .collect_vec();
-
-        // For every frequency heard, calculate the dissonance to the note
-        for (f_h, s_h) in heard_buffered.iter().cloned() {
+            .zip(partials.iter())
+            .map(|(score, f)| score * f.intensity)
+            .sum()
+    })
+}
+
+// Returns the dissonance of every heard frequency to a single played note
+fn note_scores(heard_buffered: &[(Frequency, f32)], played: &[Frequency]) -> Vec<f32> {
+    // For every played frequencies, cache the same s value
+    let played_buffered = played
+        .iter()
+        .cloned()
+        .map(|f| {
+            let s = D_S / (S1 * f.value + S2);
+            (f, s)
+        })
+        .collect_vec();
+
+    // For every frequency heard, calculate the dissonance to the note
+    heard_buffered
+        .iter()
+        .cloned()
+        .map(|(f_h, s_h)| {
             // Accumulator for the dissonance to the note
             let mut heard_score = 0f32;
 
@@ -108,20 +143,21 @@ pub fn dissonance_scores(heard: &[Frequency]) -> Vec<Vec<f32>> {
                 // Add the dissonance scores to the heard frequency score
                 heard_score += res * f_p.intensity;
             }
-            // Push to the lookup table
-            scores[note as usize].push(heard_score);
-        }
-    }
-    scores
+            heard_score
+        })
+        .collect_vec()
 }
 
 // The number of harmonics to generate on one side of the main frequency
 const HARMONIC_COUNT: usize = 300;
 const FC: usize = HARMONIC_COUNT * 2 + 1;
 
+// The frequency components of every note
+pub type Harmonics = [[Frequency; FC]; NOTE_COUNT];
+
 // Get a simulated instrument's frequency components
-pub fn get_notes_harmonics() -> [[Frequency; FC]; NOTE_COUNT] {
-    let mut array: [[Frequency; FC]; NOTE_COUNT] = [[Frequency::default(); FC]; NOTE_COUNT];
+pub fn get_notes_harmonics() -> Harmonics {
+    let mut array: Harmonics = [[Frequency::default(); FC]; NOTE_COUNT];
 
     for note in Note::iter() {
         let f = note.freq();
@@ -150,3 +186,8 @@ pub fn get_notes_harmonics() -> [[Frequency; FC]; NOTE_COUNT] {
     }
     array
 }
+
+// The fundamental of a simulated note followed by its harmonics, without the lower partials
+pub fn note_partials(harmonics: &Harmonics, note: Note) -> &[Frequency] {
+    &harmonics[note as usize][HARMONIC_COUNT..]
+}
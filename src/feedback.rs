@@ -0,0 +1,265 @@
+// Plays the most consonant notes, so that they can be heard instead of watched
+
+use std::f32::consts::PI;
+use std::sync::{Arc, Mutex};
+
+use itertools::Itertools;
+use sdl2::audio::AudioCallback;
+
+use crate::dissonance;
+use crate::notes::{Note, NOTE_COUNT};
+use crate::scores::{Register, Scores, SuggestionOptions};
+
+// The number of partials synthesised for every note, the strongest ones of the virtual instrument
+const PARTIAL_COUNT: usize = 24;
+
+// The time for a note to fade in or out, in seconds
+const DRONE_FADE: f32 = 0.3;
+const ARPEGGIO_FADE: f32 = 0.02;
+
+// The length of every note of an arpeggio, in seconds
+const ARPEGGIO_STEP: f32 = 0.25;
+
+// Voices quieter than this are stopped
+const SILENCE: f32 = 1e-4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FeedbackMode {
+    // Every note at once
+    Drone,
+    // One note after the other
+    Arpeggio,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FeedbackOptions {
+    pub mode: FeedbackMode,
+    // The number of notes played
+    pub count: usize,
+    // The output amplitude, from 0 to 1
+    pub volume: f32,
+    // The notes are picked in the octave around the center of the register
+    pub register: Register,
+}
+
+impl FeedbackOptions {
+    // The most consonant notes of the octave around the register, best first
+    fn select(self, scores: &Scores) -> Vec<Note> {
+        let center = self.register.center as usize;
+        let lowest = Note::iter().nth(center.saturating_sub(6)).unwrap();
+        let highest = lowest.iter_from().nth(11).unwrap_or(Note::B9);
+        let selection = SuggestionOptions {
+            count: self.count,
+            fold: false,
+            lowest,
+            highest,
+        };
+        selection
+            .suggest(&scores.note_scores, &scores.pitch_classes)
+            .iter()
+            .map(|suggestion| suggestion.note)
+            .collect()
+    }
+}
+
+// Shares the notes to play between the analysis thread and the audio callback
+// The notes are a fixed-size array, so that the callback copies them without allocating
+#[derive(Clone)]
+pub struct Feedback {
+    options: FeedbackOptions,
+    notes: Arc<Mutex<[bool; NOTE_COUNT]>>,
+}
+
+impl Feedback {
+    pub fn new(options: FeedbackOptions) -> Feedback {
+        Feedback {
+            options,
+            notes: Arc::new(Mutex::new([false; NOTE_COUNT])),
+        }
+    }
+
+    // Play the best notes of the latest scores
+    pub fn update(&self, scores: &Scores) {
+        let mut notes = [false; NOTE_COUNT];
+        for note in self.options.select(scores) {
+            notes[note as usize] = true;
+        }
+        if let Ok(mut shared) = self.notes.lock() {
+            *shared = notes;
+        }
+    }
+
+    // Build the audio callback, for the given sample rate
+    pub fn synth(&self, rate: i32) -> Synth {
+        Synth::new(self.options, self.notes.clone(), rate as f32)
+    }
+}
+
+// The voice of a note, silent until the note is played
+#[derive(Clone, Copy)]
+struct Voice {
+    // The phase of every partial of the timbre of the note
+    phases: [f32; PARTIAL_COUNT],
+    gain: f32,
+    target: f32,
+}
+
+impl Voice {
+    fn silent(&self) -> bool {
+        self.target == 0f32 && self.gain == 0f32
+    }
+
+    fn sample(&mut self, timbre: &[(f32, f32)]) -> f32 {
+        let mut sample = 0f32;
+        for ((step, amplitude), phase) in timbre.iter().zip(self.phases.iter_mut()) {
+            sample += amplitude * phase.sin();
+            *phase = (*phase + step) % (2f32 * PI);
+        }
+        sample * self.gain
+    }
+}
+
+// The audio callback, synthesising the notes with the timbre of the virtual instrument
+// Everything is allocated beforehand, the callback only copies the notes played
+pub struct Synth {
+    options: FeedbackOptions,
+    notes: Arc<Mutex<[bool; NOTE_COUNT]>>,
+    // The partials of every note, precomputed as phase increments and amplitudes
+    timbres: Vec<Vec<(f32, f32)>>,
+    // The voice of every note
+    voices: Vec<Voice>,
+    // The notes currently played
+    playing: [bool; NOTE_COUNT],
+    // How much the gain of a voice moves towards its target every sample
+    fade: f32,
+    // The position in the arpeggio, and the samples left before the next note
+    step: usize,
+    step_samples: usize,
+    countdown: usize,
+}
+
+impl Synth {
+    fn new(options: FeedbackOptions, notes: Arc<Mutex<[bool; NOTE_COUNT]>>, rate: f32) -> Synth {
+        // Keep the strongest audible partials of every note
+        let harmonics = Box::new(dissonance::get_notes_harmonics());
+        let timbres = harmonics
+            .iter()
+            .map(|partials| {
+                let partials = partials
+                    .iter()
+                    .filter(|f| f.value >= 20f32 && f.value < rate / 2f32)
+                    .sorted_by(|a, b| b.intensity.partial_cmp(&a.intensity).unwrap())
+                    .take(PARTIAL_COUNT)
+                    .collect_vec();
+                // Keep every note at the same loudness
+                let total = partials.iter().map(|f| f.intensity).sum::<f32>();
+                partials
+                    .iter()
+                    .map(|f| (2f32 * PI * f.value / rate, f.intensity / total))
+                    .collect_vec()
+            })
+            .collect_vec();
+        let fade = match options.mode {
+            FeedbackMode::Drone => DRONE_FADE,
+            FeedbackMode::Arpeggio => ARPEGGIO_FADE,
+        };
+        let voice = Voice {
+            phases: [0f32; PARTIAL_COUNT],
+            gain: 0f32,
+            target: 0f32,
+        };
+        Synth {
+            options,
+            notes,
+            timbres,
+            voices: vec![voice; NOTE_COUNT],
+            playing: [false; NOTE_COUNT],
+            fade: 1f32 / (fade * rate),
+            step: 0,
+            step_samples: (ARPEGGIO_STEP * rate) as usize,
+            countdown: 0,
+        }
+    }
+
+    // Fade a note in, starting its voice from the first phase if it was silent
+    fn start(&mut self, note: usize) {
+        let voice = &mut self.voices[note];
+        if voice.silent() {
+            voice.phases = [0f32; PARTIAL_COUNT];
+        }
+        voice.target = 1f32;
+    }
+
+    // Choose the notes faded in and out
+    fn update(&mut self) {
+        match self.options.mode {
+            FeedbackMode::Drone => {
+                for note in 0..NOTE_COUNT {
+                    if self.playing[note] {
+                        self.start(note);
+                    } else {
+                        self.voices[note].target = 0f32;
+                    }
+                }
+            }
+            FeedbackMode::Arpeggio => {
+                // Move to the next note at every step, from the lowest one
+                if self.countdown == 0 {
+                    self.countdown = self.step_samples;
+                    for voice in self.voices.iter_mut() {
+                        voice.target = 0f32;
+                    }
+                    let count = self.playing.iter().filter(|&&playing| playing).count();
+                    if count > 0 {
+                        self.step = (self.step + 1) % count;
+                        let note = (0..NOTE_COUNT)
+                            .filter(|&note| self.playing[note])
+                            .nth(self.step)
+                            .unwrap();
+                        self.start(note);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl AudioCallback for Synth {
+    type Channel = f32;
+
+    fn callback(&mut self, output: &mut [f32]) {
+        // Never wait for the analysis thread, the notes will be updated next time
+        let changed = match self.notes.try_lock() {
+            Ok(notes) if *notes != self.playing => Some(*notes),
+            _ => None,
+        };
+        if let Some(notes) = changed {
+            self.playing = notes;
+            if self.options.mode == FeedbackMode::Drone {
+                self.update();
+            }
+        }
+
+        let scale = self.options.volume / self.options.count.max(1) as f32;
+        for sample in output.iter_mut() {
+            if self.options.mode == FeedbackMode::Arpeggio {
+                self.update();
+            }
+            self.countdown = self.countdown.saturating_sub(1);
+
+            let mut value = 0f32;
+            for (voice, timbre) in self.voices.iter_mut().zip(self.timbres.iter()) {
+                if voice.silent() {
+                    continue;
+                }
+                voice.gain += (voice.target - voice.gain).clamp(-self.fade, self.fade);
+                value += voice.sample(timbre);
+                // Silence the voices that faded out
+                if voice.target == 0f32 && voice.gain < SILENCE {
+                    voice.gain = 0f32;
+                }
+            }
+            *sample = value * scale;
+        }
+    }
+}
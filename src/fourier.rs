@@ -1,5 +1,7 @@
 // Standard
+use std::path::PathBuf;
 use std::sync::mpsc::Sender;
+use std::time::Instant;
 use std::vec;
 
 // Tools
@@ -11,8 +13,17 @@ use rustfft::FFTplanner;
 
 //Crate
 use crate::audio_buffer::AudioBuffer;
+use crate::cache::{self, CacheKey};
+use crate::feedback::Feedback;
 use crate::frequency::Frequency;
-use crate::scores::{ScoreCalculator, Scores};
+use crate::messages;
+use crate::midi::MidiOutput;
+use crate::osc::Osc;
+use crate::scores::{PartialSelection, Register, ScoreCalculator, Scores, SuggestionOptions};
+use crate::session::ScoreRecording;
+use crate::stats::FrameStats;
+use crate::subtraction::Subtraction;
+use crate::web::WebServer;
 
 
 #[derive(Clone, Copy, Debug, Default)]
@@ -23,34 +34,189 @@ pub struct ScoringOptions {
     pub zpadding: u32,
     // The time for the perceived dissonance to drop by half
     pub halflife: f32,
+    // The number of threads used to calculate scores
+    pub threads: usize,
+    // If set, only score the strongest partials instead of using a lookup table
+    pub sparse: Option<PartialSelection>,
+    // The notes listed with the scores
+    pub suggestions: SuggestionOptions,
+    // The octaves weighing the most in the pitch class scores
+    pub register: Register,
+    // If the note the player holds is removed from the partials scored
+    pub subtract: bool,
+}
+
+// Where the scores go besides the displays
+#[derive(Default)]
+pub struct Outputs {
+    // Plays the best notes
+    pub feedback: Option<Feedback>,
+    pub midi: Option<MidiOutput>,
+    // Streams the scores, and receives changes to the options
+    pub osc: Option<Osc>,
+    // Streams the scores to the browsers
+    pub web: Option<WebServer>,
+    // Saves the scores of every frame
+    pub recording: Option<ScoreRecording>,
+}
+
+// Fill the suggestions and pitch classes, and pass the scores to the outputs
+// time is the position of the frame in the stream, in seconds
+// The options changed remotely are updated for the next frame
+pub fn publish(
+    scores: &mut Scores,
+    options: &mut ScoringOptions,
+    outputs: &mut Outputs,
+    time: f64,
+) {
+    scores.pitch_classes = options.register.fold(&scores.note_scores);
+    scores.suggestions = options
+        .suggestions
+        .suggest(&scores.note_scores, &scores.pitch_classes);
+    if let Some(feedback) = &outputs.feedback {
+        feedback.update(scores);
+    }
+    if let Some(midi) = &mut outputs.midi {
+        midi.update(scores, time);
+    }
+    if let Some(osc) = &outputs.osc {
+        osc.send(scores);
+        osc.update_options(options);
+    }
+    if let Some(web) = &outputs.web {
+        web.send(scores);
+    }
+    if let Some(recording) = &mut outputs.recording {
+        recording.write(scores, time);
+    }
 }
 
 // Receives audio input, start FFT on most recent data and send results
-pub fn fourier_thread(buffer: AudioBuffer, sender: Sender<Scores>, options:ScoringOptions) {
+// The notes of the played buffer, if any, are shown without being scored
+// The dissonance table is cached in cache_dir if set
+pub fn fourier_thread(
+    buffer: AudioBuffer,
+    played: Option<AudioBuffer>,
+    sender: Sender<Scores>,
+    mut options: ScoringOptions,
+    cache_dir: Option<PathBuf>,
+    mut outputs: Outputs,
+) {
     // The FFT pool, allows for optimized yet flexible data sizes
     let mut planner = FFTplanner::<f32>::new(false);
     // The audio buffer, to get uniformly-sized audio packets
     let mut buffer = buffer;
 
-    println!("Gathering noise profile and buffering instrument");
+    messages::info("Gathering noise profile and buffering instrument");
     // Get the first first few seconds of recording
-    let vec = buffer.take().unwrap();
+    let frame = buffer.take().unwrap();
     // Extract frequencies to serve as mask
-    let fourier = fourier_analysis(&vec[..], &mut planner, None, options);
+    let fourier = fourier_analysis(&frame.samples[..], &mut planner, None, options);
     let mask = Some(fourier.as_slice());
-    // Create a dissonance calculator from the frequencies
-    let mut calculator = ScoreCalculator::new(fourier.as_slice());
+    // The player's input gets its own mask
+    let mut played = played.map(|mut buffer| {
+        let frame = buffer.take().unwrap();
+        let mask = fourier_analysis(&frame.samples[..], &mut planner, None, options);
+        (buffer, mask)
+    });
+    // Create a dissonance calculator from the frequencies, or their cached table
+    let mut calculator = match options.sparse {
+        Some(selection) => ScoreCalculator::sparse(selection, options.threads),
+        None => {
+            let key = CacheKey::new(
+                &fourier,
+                options.frequency,
+                frame.samples.len(),
+                options.zpadding,
+            );
+            let table =
+                cache::load_or_build(cache_dir.as_deref(), &key, &fourier, options.threads);
+            ScoreCalculator::from_table(table, options.threads)
+        }
+    };
+    // Removes the player's note from the partials scored, if asked
+    let subtraction = if options.subtract {
+        let suggestions = options.suggestions;
+        Some(Subtraction::new(suggestions.lowest, suggestions.highest))
+    } else {
+        None
+    };
+    // The position of the previous frame in the stream
+    let mut timestamp = frame.timestamp;
+    // The number of samples dropped by the audio callback
+    let mut overruns = 0;
 
     // Start analysis loop
-    println!("Starting analysis");
+    messages::info("Starting analysis");
     // While audio buffer can still output data
-    while let Some(vec) = buffer.take() {
+    let mut start = Instant::now();
+    while let Some(frame) = buffer.take() {
+        let wait = start.elapsed();
         // Apply fft and extract frequencies
-        let fourier = fourier_analysis(&vec[..], &mut planner, mask, options);
+        let start_fourier = Instant::now();
+        let fourier = fourier_analysis(&frame.samples[..], &mut planner, mask, options);
+        // The same window of the player's input, both buffers being filled together
+        let played_fourier = match &mut played {
+            Some((buffer, played_mask)) => match buffer.take() {
+                Some(frame) => Some(fourier_analysis(
+                    &frame.samples[..],
+                    &mut planner,
+                    Some(played_mask.as_slice()),
+                    options,
+                )),
+                None => break,
+            },
+            None => None,
+        };
+        let fourier_time = start_fourier.elapsed();
+        // Get the audio time elapsed since the previous frame
+        let seconds = (frame.timestamp - timestamp) as f32 / options.frequency as f32;
+        timestamp = frame.timestamp;
         // Calculate dissonance of each note
-        let scores = calculator.calculate(fourier, options.halflife);
-        // Send
-        sender.send(scores).ok();
+        let start_scoring = Instant::now();
+        // The player's note is still shown, but not scored
+        let (fourier, played_fourier) = match &subtraction {
+            Some(subtraction) => {
+                let mut scored = fourier.clone();
+                subtraction.subtract(&mut scored);
+                (scored, played_fourier.or(Some(fourier)))
+            }
+            None => (fourier, played_fourier),
+        };
+        let mut scores = match &played_fourier {
+            Some(played_fourier) => {
+                calculator.calculate_apart(fourier, played_fourier, seconds, options.halflife)
+            }
+            None => calculator.calculate(fourier, seconds, options.halflife),
+        };
+        let time = frame.timestamp as f64 / options.frequency as f64;
+        publish(&mut scores, &mut options, &mut outputs, time);
+        // The partials scored may have been changed remotely
+        if let Some(selection) = options.sparse {
+            calculator.set_selection(selection);
+        }
+        scores.stats = Some(FrameStats {
+            timestamp: frame.timestamp,
+            captured: frame.captured,
+            wait,
+            fourier: fourier_time,
+            scoring: start_scoring.elapsed(),
+            overruns: buffer.overruns(),
+            underruns: buffer.underruns(),
+        });
+        // Warn if audio had to be dropped since last frame
+        if buffer.overruns() > overruns {
+            overruns = buffer.overruns();
+            messages::warn(&format!(
+                "Audio buffer overrun, {} samples lost so far",
+                overruns
+            ));
+        }
+        // Send, and stop once the display is closed
+        if sender.send(scores).is_err() {
+            break;
+        }
+        start = Instant::now();
     }
 }
 
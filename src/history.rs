@@ -0,0 +1,75 @@
+// Keeps the recent scores, to be browsed while paused
+
+use std::collections::VecDeque;
+
+pub struct ScoreHistory {
+    // The scores of every recent frame, oldest first
+    frames: VecDeque<Vec<f32>>,
+    capacity: usize,
+    // The number of frames between the newest one and the last displayed one
+    offset: usize,
+    paused: bool,
+}
+
+impl ScoreHistory {
+    pub fn new(capacity: usize) -> ScoreHistory {
+        ScoreHistory {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+            offset: 0,
+            paused: false,
+        }
+    }
+
+    pub fn push(&mut self, frame: Vec<f32>) {
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame);
+        // Keep the same frames in view while paused
+        if self.paused {
+            self.offset = (self.offset + 1).min(self.frames.len() - 1);
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Go back to the live view
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.offset = 0;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        if self.paused {
+            self.resume();
+        } else {
+            self.paused = true;
+        }
+    }
+
+    // Move the view by a number of frames, negative values going back in time
+    pub fn scrub(&mut self, frames: isize) {
+        if self.frames.is_empty() {
+            return;
+        }
+        self.paused = true;
+        let offset = self.offset as isize - frames;
+        self.offset = offset.max(0).min(self.frames.len() as isize - 1) as usize;
+    }
+
+    // The frames to display in a view of the given width, oldest first
+    // Missing frames at the start of the recording are None
+    pub fn view(&self, width: usize) -> impl Iterator<Item = Option<&Vec<f32>>> {
+        let end = self.frames.len() as isize - self.offset as isize;
+        (end - width as isize..end).map(move |i| {
+            if i < 0 {
+                None
+            } else {
+                self.frames.get(i as usize)
+            }
+        })
+    }
+}
@@ -0,0 +1,145 @@
+// The instruments drawn by the displays, and the part of their neck displayed
+
+use crate::notes::Note;
+use crate::notes::Note::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instrument {
+    Guitar,
+    Acoustic,
+    Classical,
+    Bass,
+    Ukulele,
+}
+
+// The names accepted on the command line
+pub const INSTRUMENT_NAMES: [&str; 5] = ["guitar", "acoustic", "classical", "bass", "ukulele"];
+
+impl Instrument {
+    pub fn from_name(name: &str) -> Option<Instrument> {
+        match name {
+            "guitar" => Some(Instrument::Guitar),
+            "acoustic" => Some(Instrument::Acoustic),
+            "classical" => Some(Instrument::Classical),
+            "bass" => Some(Instrument::Bass),
+            "ukulele" => Some(Instrument::Ukulele),
+            _ => None,
+        }
+    }
+
+    // The open strings, from the one at the bottom of a tablature
+    pub fn strings(self) -> &'static [Note] {
+        match self {
+            Instrument::Guitar | Instrument::Acoustic | Instrument::Classical => {
+                &[E2, A2, D3, G3, B3, E4]
+            }
+            Instrument::Bass => &[E1, A1, D2, G2],
+            Instrument::Ukulele => &[G4, C4, E4, A4],
+        }
+    }
+
+    // The number of frets on the neck
+    pub fn frets(self) -> usize {
+        match self {
+            Instrument::Guitar => 24,
+            Instrument::Acoustic => 20,
+            Instrument::Classical => 19,
+            Instrument::Bass => 20,
+            Instrument::Ukulele => 15,
+        }
+    }
+}
+
+// The part of the neck displayed, the open strings are always shown
+// Frets are counted from the capo, which acts as the nut
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fretboard {
+    pub instrument: Instrument,
+    // The fret the capo is on, zero without one
+    pub capo: usize,
+    // The first and last frets displayed after the open strings
+    pub first: usize,
+    pub last: usize,
+}
+
+impl Fretboard {
+    // Show the whole neck above the capo, if it fits
+    pub fn new(instrument: Instrument, capo: usize) -> Fretboard {
+        Fretboard {
+            instrument,
+            capo,
+            first: 1,
+            last: instrument.frets().saturating_sub(capo).max(1),
+        }
+    }
+
+    // Only show some frets, clamped to the neck
+    pub fn window(self, first: usize, last: usize) -> Fretboard {
+        let frets = self.frets();
+        let first = first.clamp(1, frets);
+        let last = last.clamp(first, frets);
+        Fretboard {
+            first,
+            last,
+            ..self
+        }
+    }
+
+    // The number of frets above the capo
+    pub fn frets(self) -> usize {
+        self.instrument.frets().saturating_sub(self.capo).max(1)
+    }
+
+    // The displayed frets, starting with the open strings
+    pub fn columns(self) -> impl Iterator<Item = usize> + Clone {
+        std::iter::once(0).chain(self.first..=self.last)
+    }
+
+    // The number of displayed frets, including the open strings
+    pub fn column_count(self) -> usize {
+        self.last - self.first + 2
+    }
+
+    // The position of a displayed fret, from the open strings
+    pub fn column(self, fret: usize) -> usize {
+        if fret == 0 {
+            0
+        } else {
+            fret + 1 - self.first
+        }
+    }
+
+    // The note of every string with the capo, from the one at the bottom of a tablature
+    pub fn strings(self) -> Vec<Note> {
+        self.instrument
+            .strings()
+            .iter()
+            .map(|&string| self.note(string, self.capo))
+            .collect()
+    }
+
+    // The note played on a string, the capo already being applied to it
+    pub fn note(self, string: Note, fret: usize) -> Note {
+        string.iter_from().nth(fret).unwrap_or(B9)
+    }
+
+    // The number of inlay dots of a fret, counted on the neck rather than from the capo
+    pub fn inlays(self, fret: usize) -> usize {
+        if fret == 0 {
+            return 0;
+        }
+        match (self.capo + fret) % 12 {
+            0 => 2,
+            3 | 5 | 7 | 9 => 1,
+            _ => 0,
+        }
+    }
+
+    // The lowest and highest notes of the displayed frets
+    pub fn range(self) -> (Note, Note) {
+        let strings = self.strings();
+        let lowest = strings.iter().min_by_key(|&&note| note as usize).unwrap();
+        let highest = strings.iter().max_by_key(|&&note| note as usize).unwrap();
+        (*lowest, self.note(*highest, self.last))
+    }
+}
@@ -1,31 +1,216 @@
 // Standard
-use std::sync::mpsc::{channel, Sender};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
 
 // Parser
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 
 // SDL2
-use sdl2::audio::{AudioCallback, AudioSpecDesired};
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::AudioSubsystem;
 
 // Crate
 mod audio_buffer;
+mod benchmark;
+mod cache;
+mod chart;
+mod colors;
+mod devices;
 mod display;
 mod display_sdl;
 mod display_term;
 mod dissonance;
+mod feedback;
 mod fourier;
 mod frequency;
+mod history;
+mod instrument;
+mod messages;
+mod midi;
+mod midi_input;
+mod musicxml;
 mod notes;
+mod osc;
+mod ring_buffer;
 mod scores;
+mod session;
+mod spectrogram;
+mod stats;
+mod subtraction;
 mod tools;
+mod web;
 
 use self::audio_buffer::{AudioBuffer, BufferOptions};
-use self::display::DisplayOptions;
-use self::scores::Scores;
+use self::chart::{Chart, ChartCommand, ChartOptions};
+use self::colors::{ColorMap, Theme, COLOR_MAP_NAMES, THEME_NAMES};
+use self::devices::InputChannels;
+use self::display::{CircleOrder, DisplayOptions, Notation, SpectrogramOptions};
+use self::feedback::{Feedback, FeedbackMode, FeedbackOptions, Synth};
+use self::fourier::Outputs;
+use self::instrument::{Fretboard, Instrument, INSTRUMENT_NAMES};
+use self::midi::{MidiFile, MidiOptions, MidiOutput, MidiPort, RawPort};
+use self::midi_input::MidiSource;
+use self::osc::{Osc, OscOptions};
+use self::ring_buffer::{Consumer, Producer};
+use self::scores::{PartialSelection, Register, Scores, SuggestionOptions};
+use self::session::{Player, ScoreRecording};
+use self::stats::Monitor;
+use self::web::WebServer;
+
+// The sample rate requested to the audio driver, unless --rate is given
+const SAMPLE_RATE: i32 = 88200;
+
+// The time left to the analysis to stop its outputs once the display is closed
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
 
 fn main() -> Result<(), String> {
-    // Parse args
-    let matches = App::new("ImproVe")
+    let matches = parse_arguments();
+    let disp_opt = display_options(&matches);
+    let mut buf_opt = buffer_options(&matches)?;
+
+    // Get the zero-padding factor
+    let zpadding = matches
+        .value_of("zpadding")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap();
+
+    // Get the dissonance half-life
+    let halflife = matches
+        .value_of("halflife")
+        .unwrap()
+        .parse::<f32>()
+        .unwrap();
+
+    // Get the number of scoring threads
+    let threads = matches
+        .value_of("threads")
+        .map(|s| s.parse::<usize>().unwrap())
+        .unwrap_or_else(tools::default_threads);
+
+    // Get the sparse scoring options, if any
+    let sparse = matches.value_of("sparse").map(|s| PartialSelection {
+        count: s.parse::<usize>().unwrap(),
+        threshold: matches
+            .value_of("threshold")
+            .map(|s| s.parse::<f32>().unwrap()),
+    });
+
+    // Get the listed notes, within the range of the fretboard
+    let (lowest, highest) = disp_opt.fretboard.range();
+    let suggestions = SuggestionOptions {
+        count: matches
+            .value_of("suggest")
+            .map_or(0, |s| s.parse::<usize>().unwrap()),
+        fold: !matches.is_present("peroctave"),
+        lowest,
+        highest,
+    };
+
+    // Get the octaves weighing the most in the pitch classes
+    let register = Register {
+        center: Notation::parse_note(matches.value_of("register").unwrap()).unwrap(),
+        width: matches
+            .value_of("registerwidth")
+            .unwrap()
+            .parse::<f32>()
+            .unwrap(),
+    };
+
+    // Get the sample rate of the capture
+    let rate = matches
+        .value_of("rate")
+        .map_or(SAMPLE_RATE, |rate| rate.parse::<i32>().unwrap());
+
+    // Run the benchmark instead of the analysis if asked
+    if matches.is_present("benchmark") {
+        let scoring_options = fourier::ScoringOptions {
+            frequency: rate,
+            zpadding,
+            halflife,
+            threads,
+            sparse,
+            suggestions,
+            register,
+            subtract: false,
+        };
+        return benchmark::run(buf_opt.resolution, scoring_options);
+    }
+
+    // Get the dissonance table cache directory
+    let cache_dir = if matches.is_present("nocache") {
+        None
+    } else {
+        matches
+            .value_of("cachedir")
+            .map(std::path::PathBuf::from)
+            .or_else(cache::default_dir)
+    };
+
+    // Get the performance monitor, opening the log file if needed
+    let monitor = Monitor::new(matches.is_present("stats"), matches.value_of("statslog"))?;
+
+    // Get the SDL objects
+    let sdl_context = sdl2::init()?;
+    let audio_subsystem = sdl_context.audio()?;
+    println!(
+        "Capture Driver = {}",
+        audio_subsystem.current_audio_driver()
+    );
+    if matches.is_present("listdevices") {
+        devices::list(&audio_subsystem);
+        return Ok(());
+    }
+
+    // The devices of the input stay open until the end of the run
+    let input = open_input(&matches, &audio_subsystem, rate, buf_opt.resolution)?;
+    let frequency = input.frequency;
+    buf_opt.rate = frequency as u32;
+    let (outputs, _playback_device) = open_outputs(
+        &matches,
+        &audio_subsystem,
+        disp_opt,
+        suggestions,
+        register,
+        input.recording.map(|samples| (samples, frequency)),
+    )?;
+
+    let scoring_options = fourier::ScoringOptions {
+        frequency,
+        zpadding,
+        halflife,
+        threads,
+        sparse,
+        suggestions,
+        register,
+        subtract: matches.is_present("subtractself"),
+    };
+
+    // The channel to get data from the analysis thread
+    let (score_sender, score_receiver) = channel::<Scores>();
+    let (stopped, chart_commands) = start_analysis(
+        input.source,
+        score_sender,
+        scoring_options,
+        buf_opt,
+        cache_dir,
+        outputs,
+    );
+
+    let result = if matches.is_present("terminal") || matches.is_present("suggestonly") {
+        display_term::display(score_receiver, disp_opt, monitor, chart_commands)
+    } else {
+        display_sdl::display(sdl_context, score_receiver, disp_opt, monitor, chart_commands)
+    };
+    // Let the analysis notice the display is closed, so that its outputs stop the notes still on
+    stopped.recv_timeout(SHUTDOWN_TIMEOUT).ok();
+    result
+}
+
+// The options given on the command line
+fn parse_arguments() -> ArgMatches<'static> {
+    App::new("ImproVe")
         .version("0.1")
         .author("Louis Garczynski <louis.roc@gmail.com>")
         .about("Real-time improvisation suggestions")
@@ -94,6 +279,79 @@ fn main() -> Result<(), String> {
                     Err(_) => Err("Argument is not a float".to_owned()),
                 }),
         )
+        .arg(
+            Arg::with_name("threads")
+                .short("j")
+                .long("threads")
+                .value_name("UINT")
+                .help(
+                    "The number of threads used to calculate scores\n\
+                     Defaults to the number of cores\n",
+                )
+                .next_line_help(true)
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(1..=256) => Ok(()),
+                    Ok(_) => Err("Argument out of range: (1 .. 256)".to_owned()),
+                    Err(_) => Err("Argument is not an unsigned int".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("benchmark")
+                .long("benchmark")
+                .help(
+                    "Compare serial and multithreaded scoring on synthetic data, then exit\n\
+                     Uses the resolution, zpadding and threads options\n",
+                )
+                .next_line_help(true),
+        )
+        .arg(
+            Arg::with_name("sparse")
+                .short("k")
+                .long("sparse")
+                .value_name("UINT")
+                .help(
+                    "Only score the strongest partials of every frame\n\
+                     Skips the lookup table, for large resolutions and zpadding\n",
+                )
+                .next_line_help(true)
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(1..=4096) => Ok(()),
+                    Ok(_) => Err("Argument out of range: (1 .. 4096)".to_owned()),
+                    Err(_) => Err("Argument is not an unsigned int".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("threshold")
+                .long("threshold")
+                .value_name("DB")
+                .help(
+                    "With --sparse, ignore partials quieter than the strongest one\n\
+                     by more than this many decibels\n",
+                )
+                .next_line_help(true)
+                .requires("sparse")
+                .validator(|s| match s.parse::<f32>() {
+                    Ok(f) if f >= 0.0 => Ok(()),
+                    Ok(_) => Err("Argument cannot be negative".to_owned()),
+                    Err(_) => Err("Argument is not a float".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("cachedir")
+                .long("cache-dir")
+                .value_name("DIR")
+                .help(
+                    "Where to store the dissonance tables between runs\n\
+                     Defaults to $XDG_CACHE_HOME/improve or ~/.cache/improve\n",
+                )
+                .next_line_help(true),
+        )
+        .arg(
+            Arg::with_name("nocache")
+                .long("no-cache")
+                .conflicts_with("cachedir")
+                .help("Always rebuild the dissonance table\n"),
+        )
         .arg(
             Arg::with_name("discard")
                 .short("d")
@@ -106,6 +364,24 @@ fn main() -> Result<(), String> {
                 .long("overlap")
                 .help("Allows the program to reuse data if the latency is too low\n"),
         )
+        .arg(
+            Arg::with_name("hop")
+                .short("p")
+                .long("hop")
+                .value_name("UINT")
+                .help(
+                    "Number of samples between the start of two analyzed frames\n\
+                     Frames overlap when lower than the resolution\n\
+                     Gives a fixed analysis rate, independent of the audio driver\n",
+                )
+                .next_line_help(true)
+                .conflicts_with("overlap")
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(1..=1_048_576) => Ok(()),
+                    Ok(_) => Err("Argument out of range: (1 .. 1048576)".to_owned()),
+                    Err(_) => Err("Argument is not an unsigned int".to_owned()),
+                }),
+        )
         .arg(
             Arg::with_name("terminal")
                 .short("t")
@@ -118,20 +394,559 @@ fn main() -> Result<(), String> {
                 .long("noclear")
                 .help("Prevents the program from using termios\n"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("suggest")
+                .short("l")
+                .long("suggest")
+                .value_name("UINT")
+                .help(
+                    "List the most consonant notes, with their score\n\
+                     Every octave of a note is averaged, see --per-octave\n",
+                )
+                .next_line_help(true)
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(1..=48) => Ok(()),
+                    Ok(_) => Err("Argument out of range: (1 .. 48)".to_owned()),
+                    Err(_) => Err("Argument is not an unsigned int".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("peroctave")
+                .long("per-octave")
+                .help("With --suggest, list every note with its octave\n")
+                .next_line_help(true)
+                .requires("suggest"),
+        )
+        .arg(
+            Arg::with_name("suggestonly")
+                .long("suggest-only")
+                .help(
+                    "Only print the suggestions, one line per frame\n\
+                     For scripts and screen readers, implies --terminal\n",
+                )
+                .next_line_help(true)
+                .requires("suggest"),
+        )
+        .arg(
+            Arg::with_name("circle")
+                .short("w")
+                .long("circle")
+                .value_name("ORDER")
+                .help(
+                    "Display the 12 pitch classes around a circle, coloured by consonance\n\
+                     In chromatic order or along the circle of fifths\n",
+                )
+                .next_line_help(true)
+                .possible_values(&["chromatic", "fifths"]),
+        )
+        .arg(
+            Arg::with_name("register")
+                .long("register")
+                .value_name("NOTE")
+                .help(
+                    "The center of the octaves weighing the most in the circle and the \
+                     suggestions, as in E4\n",
+                )
+                .next_line_help(true)
+                .default_value("E4")
+                .validator(|s| match Notation::parse_note(&s) {
+                    Some(_) => Ok(()),
+                    None => Err("Argument is not a note with its octave".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("registerwidth")
+                .long("register-width")
+                .value_name("OCTAVES")
+                .help("The distance from the register at which octaves weigh half as much\n")
+                .next_line_help(true)
+                .default_value("1")
+                .validator(|s| match s.parse::<f32>() {
+                    Ok(f) if f > 0.0 => Ok(()),
+                    Ok(_) => Err("Argument must be positive".to_owned()),
+                    Err(_) => Err("Argument is not a float".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("instrument")
+                .short("i")
+                .long("instrument")
+                .value_name("NAME")
+                .help("The instrument drawn, with its strings and frets\n")
+                .next_line_help(true)
+                .possible_values(&INSTRUMENT_NAMES)
+                .default_value("guitar"),
+        )
+        .arg(
+            Arg::with_name("capo")
+                .long("capo")
+                .value_name("FRET")
+                .help("Put a capo on a fret, frets are then counted from it\n")
+                .next_line_help(true)
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(1..=12) => Ok(()),
+                    Ok(_) => Err("Argument out of range: (1 .. 12)".to_owned()),
+                    Err(_) => Err("Argument is not an unsigned int".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("frets")
+                .long("frets")
+                .value_name("FIRST-LAST")
+                .help(
+                    "Only display some frets besides the open strings, as in 5-12\n\
+                     Frets are counted from the capo\n",
+                )
+                .next_line_help(true)
+                .validator(|s| match parse_fret_range(&s) {
+                    Some(_) => Ok(()),
+                    None => Err("Argument is not a fret range, as in 5-12".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("lefthanded")
+                .long("left-handed")
+                .help("Mirror the fretboard, with the nut on the right\n")
+                .next_line_help(true),
+        )
+        .arg(
+            Arg::with_name("flipstrings")
+                .long("flip-strings")
+                .help(
+                    "Put the lowest string at the top of the fretboard\n\
+                     By default it is at the bottom, as in tablatures\n",
+                )
+                .next_line_help(true),
+        )
+        .arg(
+            Arg::with_name("feedback")
+                .short("a")
+                .long("feedback")
+                .value_name("MODE")
+                .help(
+                    "Play the most consonant notes quietly, as a drone or an arpeggio\n\
+                     They are picked in the octave around --register\n\
+                     Use headphones, or the mic hears them too\n",
+                )
+                .next_line_help(true)
+                .possible_values(&["drone", "arpeggio"]),
+        )
+        .arg(
+            Arg::with_name("feedbacknotes")
+                .long("feedback-notes")
+                .value_name("UINT")
+                .help("The number of notes played by --feedback\n")
+                .next_line_help(true)
+                .default_value("3")
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(1..=12) => Ok(()),
+                    Ok(_) => Err("Argument out of range: (1 .. 12)".to_owned()),
+                    Err(_) => Err("Argument is not an unsigned int".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("feedbackvolume")
+                .long("feedback-volume")
+                .value_name("VOLUME")
+                .help("The volume of --feedback, from 0 to 1\n")
+                .next_line_help(true)
+                .default_value("0.1")
+                .validator(|s| match s.parse::<f32>() {
+                    Ok(f) if (0.0..=1.0).contains(&f) => Ok(()),
+                    Ok(_) => Err("Argument out of range: (0 .. 1)".to_owned()),
+                    Err(_) => Err("Argument is not a float".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("midiin")
+                .long("midi-in")
+                .value_name("SOURCE")
+                .help(
+                    "Read the notes played from MIDI instead of listening to the audio\n\
+                     A .mid file is played back in real time, anything else is read as a raw\n\
+                     MIDI device or a named pipe, as in /dev/snd/midiC1D0\n",
+                )
+                .next_line_help(true)
+                .conflicts_with("chart"),
+        )
+        .arg(
+            Arg::with_name("listdevices")
+                .long("list-devices")
+                .help("List the capture and playback devices, then exit\n")
+                .next_line_help(true),
+        )
+        .arg(
+            Arg::with_name("device")
+                .long("device")
+                .value_name("NAME|INDEX")
+                .help(
+                    "The capture device, by its index or (a part of) its name as listed by\n\
+                     --list-devices, instead of the default one\n",
+                )
+                .next_line_help(true),
+        )
+        .arg(
+            Arg::with_name("rate")
+                .long("rate")
+                .value_name("HZ")
+                .help("The sample rate of the capture, 88200 by default\n")
+                .next_line_help(true)
+                .validator(|s| match s.parse::<i32>() {
+                    Ok(rate) if (8000..=192_000).contains(&rate) => Ok(()),
+                    Ok(_) => Err("Argument out of range: (8000 .. 192000)".to_owned()),
+                    Err(_) => Err("Argument is not an unsigned int".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("channel")
+                .long("channel")
+                .value_name("mix|CHANNEL")
+                .help(
+                    "The channel of the capture analysed, from 1 as on an audio interface, or\n\
+                     mix to mix every channel down\n",
+                )
+                .next_line_help(true)
+                .default_value("mix")
+                .validator(|s| match InputChannels::from_name(&s) {
+                    Some(_) => Ok(()),
+                    None => Err(format!(
+                        "The channel must be mix or from 1 to {}",
+                        devices::MAX_CHANNELS
+                    )),
+                }),
+        )
+        .arg(
+            Arg::with_name("accompaniment")
+                .long("accompaniment")
+                .value_name("CHANNELS")
+                .help(
+                    "The channels of the backing band, as 1,2, which are mixed and scored\n\
+                     Needs --self, and replaces --channel\n",
+                )
+                .next_line_help(true)
+                .use_delimiter(true)
+                .requires("self")
+                .conflicts_with_all(&["midiin", "chart", "replay"])
+                .validator(validate_channel),
+        )
+        .arg(
+            Arg::with_name("self")
+                .long("self")
+                .value_name("CHANNELS")
+                .help(
+                    "The channels of the player, which are only used to show the notes played\n\
+                     and detect their pitch, so that they don't sway the suggestions\n",
+                )
+                .next_line_help(true)
+                .use_delimiter(true)
+                .requires("accompaniment")
+                .validator(validate_channel),
+        )
+        .arg(
+            Arg::with_name("subtractself")
+                .long("subtract-self")
+                .help(
+                    "Find the note being played, as the one with the loudest partial, and\n\
+                     remove its partials before scoring, so that the suggestions follow the\n\
+                     accompaniment rather than what was just played\n",
+                )
+                .next_line_help(true)
+                .conflicts_with_all(&["midiin", "chart", "self"]),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .value_name("FILE.wav")
+                .help(
+                    "Save the audio analysed to a WAV file, and the scores of every frame to a\n\
+                     csv file of the same name, as session.wav and session.csv\n",
+                )
+                .next_line_help(true)
+                // A replay mixes every channel down, which would score the player's own part
+                .conflicts_with_all(&["midiin", "chart", "accompaniment", "self"])
+                .validator(|s| {
+                    if s.to_lowercase().ends_with(".wav") {
+                        Ok(())
+                    } else {
+                        Err("The recording must be a .wav file".to_owned())
+                    }
+                }),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .value_name("FILE.wav")
+                .help(
+                    "Play a WAV file through the speakers and analyse it instead of the audio\n\
+                     input, to compare the options on the same passage\n",
+                )
+                .next_line_help(true)
+                .conflicts_with_all(&["midiin", "chart"]),
+        )
+        .arg(
+            Arg::with_name("chart")
+                .long("chart")
+                .value_name("FILE")
+                .help(
+                    "Analyse every beat of a .mid or MusicXML chart ahead of time, then follow it\n\
+                     at its tempo instead of listening to the audio\n\
+                     The [ and ] keys move to the previous and next beat\n",
+                )
+                .next_line_help(true),
+        )
+        .arg(
+            Arg::with_name("chartmanual")
+                .long("chart-manual")
+                .help("Only move through the chart with the [ and ] keys\n")
+                .next_line_help(true)
+                .requires("chart"),
+        )
+        .arg(
+            Arg::with_name("chartlookahead")
+                .long("chart-lookahead")
+                .value_name("SECONDS")
+                .help("Show the scores of every beat of the chart this early\n")
+                .next_line_help(true)
+                .requires("chart")
+                .default_value("0")
+                .validator(|s| match s.parse::<f64>() {
+                    Ok(f) if f >= 0.0 => Ok(()),
+                    Ok(_) => Err("Argument must be positive".to_owned()),
+                    Err(_) => Err("Argument is not a float".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("midiport")
+                .long("midi-port")
+                .value_name("DEVICE")
+                .help(
+                    "Send MIDI to a raw device or a named pipe, as in /dev/snd/midiC1D0\n\
+                     The snd-virmidi module provides virtual ports for other programs\n",
+                )
+                .next_line_help(true),
+        )
+        .arg(
+            Arg::with_name("midifile")
+                .long("midi-file")
+                .value_name("FILE")
+                .help("Write the MIDI messages to a Standard MIDI File\n")
+                .next_line_help(true),
+        )
+        .arg(
+            Arg::with_name("midimessages")
+                .long("midi-messages")
+                .value_name("MESSAGES")
+                .help(
+                    "The MIDI messages sent, separated by commas\n\
+                     notes plays the most consonant notes, cc sends the score of every pitch class\n\
+                     as controllers 20 (C) to 31 (B), the most consonant ones being the highest\n",
+                )
+                .next_line_help(true)
+                .possible_values(&["notes", "cc"])
+                .use_delimiter(true)
+                .default_value("notes"),
+        )
+        .arg(
+            Arg::with_name("midinotes")
+                .long("midi-notes")
+                .value_name("UINT")
+                .help("The number of notes played on MIDI, within the range of the fretboard\n")
+                .next_line_help(true)
+                .default_value("3")
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(1..=48) => Ok(()),
+                    Ok(_) => Err("Argument out of range: (1 .. 48)".to_owned()),
+                    Err(_) => Err("Argument is not an unsigned int".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("midichannel")
+                .long("midi-channel")
+                .value_name("UINT")
+                .help("The MIDI channel of the messages\n")
+                .next_line_help(true)
+                .default_value("1")
+                .validator(|s| match s.parse::<u32>() {
+                    Ok(1..=16) => Ok(()),
+                    Ok(_) => Err("Argument out of range: (1 .. 16)".to_owned()),
+                    Err(_) => Err("Argument is not an unsigned int".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("oscsend")
+                .long("osc-send")
+                .value_name("HOST:PORT")
+                .help(
+                    "Send the scores, the values, the loudest note, the chord heard and the\n\
+                     suggestions of every frame as OSC messages over UDP\n",
+                )
+                .next_line_help(true),
+        )
+        .arg(
+            Arg::with_name("osclisten")
+                .long("osc-listen")
+                .value_name("[HOST:]PORT")
+                .help(
+                    "Change the scoring options with OSC messages received on this address\n\
+                     /halflife, /suggest, /per-octave, /register, /register-width, /partials\n\
+                     and /threshold take the same values as their options\n",
+                )
+                .next_line_help(true),
+        )
+        .arg(
+            Arg::with_name("oscprefix")
+                .long("osc-prefix")
+                .value_name("ADDRESS")
+                .help("The start of every OSC address\n")
+                .next_line_help(true)
+                .default_value("/improve")
+                .validator(|s| {
+                    if s.starts_with('/') && !s.ends_with('/') {
+                        Ok(())
+                    } else {
+                        Err("The prefix must start with a / and not end with one".to_owned())
+                    }
+                }),
+        )
+        .arg(
+            Arg::with_name("web")
+                .long("web")
+                .value_name("[HOST:]PORT")
+                .help(
+                    "Serve a page drawing the fretboard and the keyboard on this address, as\n\
+                     8080 for http://localhost:8080, and stream the scores to every browser\n\
+                     opening it\n",
+                )
+                .next_line_help(true),
+        )
+        .arg(
+            Arg::with_name("scorecolors")
+                .long("colors")
+                .value_name("MAP")
+                .help(
+                    "The colour map of the scores, from consonant to dissonant\n\
+                     viridis, cividis and contrast are readable with colour blindness\n",
+                )
+                .next_line_help(true)
+                .possible_values(&COLOR_MAP_NAMES)
+                .default_value("classic"),
+        )
+        .arg(
+            Arg::with_name("theme")
+                .long("theme")
+                .value_name("THEME")
+                .help("The colours of the background and the text\n")
+                .next_line_help(true)
+                .possible_values(&THEME_NAMES)
+                .default_value("dark"),
+        )
+        .arg(
+            Arg::with_name("spectrogram")
+                .short("g")
+                .long("spectrogram")
+                .help("Display a scrolling spectrogram in the SDL display\n"),
+        )
+        .arg(
+            Arg::with_name("colormap")
+                .long("spectrogram-colors")
+                .value_name("MAP")
+                .help("The colour map of the spectrogram\n")
+                .next_line_help(true)
+                .possible_values(&COLOR_MAP_NAMES)
+                .default_value("magma"),
+        )
+        .arg(
+            Arg::with_name("dbrange")
+                .long("spectrogram-range")
+                .value_name("DB")
+                .help("The loudness range of the spectrogram, in decibels\n")
+                .next_line_help(true)
+                .default_value("80")
+                .validator(|s| match s.parse::<f32>() {
+                    Ok(f) if f > 0.0 => Ok(()),
+                    Ok(_) => Err("Argument must be positive".to_owned()),
+                    Err(_) => Err("Argument is not a float".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("history")
+                .short("y")
+                .long("history")
+                .help(
+                    "Display the recent scores of every note in the SDL display\n\
+                     Space pauses, the arrows and page keys scrub through time\n",
+                )
+                .next_line_help(true),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .short("s")
+                .long("stats")
+                .help(
+                    "Display the analysis timings and the latency\n\
+                     Can be toggled with F3 in the SDL display\n",
+                )
+                .next_line_help(true),
+        )
+        .arg(
+            Arg::with_name("statslog")
+                .long("stats-log")
+                .value_name("FILE")
+                .help("Write the timings of every frame to a csv file\n")
+                .next_line_help(true),
+        )
+        .get_matches()
+}
+
+// Get the display options
+fn display_options(matches: &ArgMatches) -> DisplayOptions {
     // Get notation convention
     let notation = match matches.value_of("notation").unwrap() {
-        "e" => display::Notation::English,
-        _ => display::Notation::Romance,
+        "e" => Notation::English,
+        _ => Notation::Romance,
     };
-    // Get display option
-    let disp_opt = DisplayOptions {
+    // Get spectrogram options
+    let spectrogram = if matches.is_present("spectrogram") {
+        Some(SpectrogramOptions {
+            colormap: ColorMap::from_name(matches.value_of("colormap").unwrap()).unwrap(),
+            db_range: matches
+                .value_of("dbrange")
+                .unwrap()
+                .parse::<f32>()
+                .unwrap(),
+        })
+    } else {
+        None
+    };
+    // Get the fretboard
+    let instrument = Instrument::from_name(matches.value_of("instrument").unwrap()).unwrap();
+    let capo = matches
+        .value_of("capo")
+        .map_or(0, |s| s.parse::<usize>().unwrap());
+    let mut fretboard = Fretboard::new(instrument, capo);
+    if let Some((first, last)) = matches.value_of("frets").and_then(parse_fret_range) {
+        fretboard = fretboard.window(first, last);
+    }
+    DisplayOptions {
         notation,
         clear_term: !matches.is_present("noclear"),
-        instrument: (),
-    };
+        fretboard,
+        colormap: ColorMap::from_name(matches.value_of("scorecolors").unwrap()).unwrap(),
+        theme: Theme::from_name(matches.value_of("theme").unwrap()).unwrap(),
+        spectrogram,
+        history: matches.is_present("history"),
+        suggestions_only: matches.is_present("suggestonly"),
+        circle: matches.value_of("circle").map(|order| match order {
+            "fifths" => CircleOrder::Fifths,
+            _ => CircleOrder::Chromatic,
+        }),
+        left_handed: matches.is_present("lefthanded"),
+        flip_strings: matches.is_present("flipstrings"),
+    }
+}
 
-    // Get audio buffering options
+// Get audio buffering options, the rate being set once the input is open
+fn buffer_options(matches: &ArgMatches) -> Result<BufferOptions, String> {
     let mut buf_opt = BufferOptions::default();
     // Get number of values to read in a single FFT
     buf_opt.resolution = matches
@@ -143,86 +958,494 @@ fn main() -> Result<(), String> {
     buf_opt.discard = matches.is_present("discard");
     // Check if values can be analyzed multiple times if input is too slow
     buf_opt.overlap = matches.is_present("overlap");
+    // Get the number of samples between two frames, if any
+    buf_opt.hop = matches
+        .value_of("hop")
+        .map(|s| s.parse::<usize>().unwrap());
+    if let Some(hop) = buf_opt.hop {
+        if hop > buf_opt.resolution {
+            return Err("Hop size cannot be larger than the resolution".to_owned());
+        }
+    }
+    Ok(buf_opt)
+}
 
-    // Get the zero-padding factor
-    let zpadding = matches
-        .value_of("zpadding")
-        .unwrap()
-        .parse::<u32>()
-        .unwrap();
+// Where the notes analysed come from
+enum Source {
+    // The buffers of the audio captured or replayed, the second one being the player's own
+    Audio(Vec<Consumer>),
+    Midi(MidiSource),
+    Chart(Chart, ChartOptions),
+}
 
-    // Get the dissonance half-life
-    let halflife = matches
-        .value_of("halflife")
-        .unwrap()
-        .parse::<f32>()
-        .unwrap();
+// The input of the analysis, and the audio devices kept open while it runs
+struct Input {
+    source: Source,
+    // The sample rate given by the driver, or the one asked without audio
+    frequency: i32,
+    // The samples to save, if asked
+    recording: Option<Consumer>,
+    _capture_device: Option<AudioDevice<Recorder>>,
+    _replay_device: Option<AudioDevice<Player>>,
+}
 
-    // The channel to get data from audio callback and back
-    let (audio_sender, audio_receiver) = channel::<Vec<f32>>();
-    let (score_sender, score_receiver) = channel::<Scores>();
+// Open the input given on the command line, the default capture device if none
+fn open_input(
+    matches: &ArgMatches,
+    audio: &AudioSubsystem,
+    rate: i32,
+    resolution: usize,
+) -> Result<Input, String> {
+    let mut input = Input {
+        source: Source::Audio(Vec::new()),
+        frequency: rate,
+        recording: None,
+        _capture_device: None,
+        _replay_device: None,
+    };
 
-    // Get the SDL objects
-    let sdl_context = sdl2::init()?;
-    let audio_subsystem = sdl_context.audio()?;
-    println!(
-        "Capture Driver = {}",
-        audio_subsystem.current_audio_driver()
-    );
-    println!(
-        "Capture Spec = {:?}",
-        audio_subsystem.audio_playback_device_name(0)
-    );
+    // Read the notes from MIDI or a chart if asked, instead of recording
+    if let Some(path) = matches.value_of("midiin") {
+        input.source = Source::Midi(MidiSource::open(path)?);
+        return Ok(input);
+    }
+    if let Some(path) = matches.value_of("chart") {
+        let chart_options = ChartOptions {
+            manual: matches.is_present("chartmanual"),
+            lookahead: matches
+                .value_of("chartlookahead")
+                .unwrap()
+                .parse::<f64>()
+                .unwrap(),
+        };
+        input.source = Source::Chart(Chart::open(path)?, chart_options);
+        return Ok(input);
+    }
+
+    // Replay a recording instead of capturing if asked
+    let record = matches.value_of("record");
+    let replay = matches.value_of("replay");
+    if record.is_some() && record == replay {
+        return Err("Cannot record over the file being replayed".to_owned());
+    }
+    match replay {
+        Some(path) => open_replay(&mut input, audio, path, resolution, record.is_some())?,
+        None => open_capture(
+            &mut input,
+            matches,
+            audio,
+            rate,
+            resolution,
+            record.is_some(),
+        )?,
+    }
+    Ok(input)
+}
+
+// The buffers of an audio input, holding at least a second of audio, and always a few frames
+fn audio_buffer(resolution: usize, rate: i32) -> (Producer, Consumer) {
+    ring_buffer::ring_buffer((resolution * 4).max(rate as usize))
+}
+
+// The buffer of the samples recorded, read while the file is written
+fn recording_buffer(rate: i32) -> (Producer, Consumer) {
+    ring_buffer::ring_buffer(rate as usize * session::RECORDING_SECONDS)
+}
 
+// Capture the audio of a device, checking it has the channels and the rate asked
+fn open_capture(
+    input: &mut Input,
+    matches: &ArgMatches,
+    audio: &AudioSubsystem,
+    rate: i32,
+    resolution: usize,
+    record: bool,
+) -> Result<(), String> {
     // Set the desired specs
+    let device = match matches.value_of("device") {
+        Some(device) => Some(devices::find_capture(audio, device)?),
+        None => None,
+    };
+    let channels = |name| {
+        matches
+            .values_of(name)
+            .map(|channels| channels.filter_map(devices::parse_channel).collect::<Vec<_>>())
+    };
+    let input_channels = match (channels("accompaniment"), channels("self")) {
+        (Some(accompaniment), Some(played)) => {
+            if matches.occurrences_of("channel") > 0 {
+                return Err("--channel can't be used with --accompaniment and --self".to_owned());
+            }
+            InputChannels::Split {
+                accompaniment,
+                played,
+            }
+        }
+        _ => InputChannels::from_name(matches.value_of("channel").unwrap()).unwrap(),
+    };
     let desired_spec = AudioSpecDesired {
-        freq: Some(88200),
-        channels: Some(1),
+        freq: Some(rate),
+        channels: Some(input_channels.channel_count()),
         samples: None,
     };
 
+    let device_name = device.as_deref().unwrap_or("default");
+    println!("Capture Device = {}", device_name);
+    // The driver converts the audio to the spec asked, which can't make up a missing channel
+    let (native_rate, native_channels) = devices::native_spec(
+        audio,
+        device.as_deref(),
+        rate,
+        input_channels.channel_count(),
+    )
+    .map_err(|e| format!("Could not open the {} capture device: {}", device_name, e))?;
+    if let Some(highest) = input_channels.highest() {
+        if highest >= native_channels as usize {
+            return Err(format!(
+                "The {} capture device has {} channels, there is no channel {}",
+                device_name,
+                native_channels,
+                highest + 1
+            ));
+        }
+    }
+    // The default rate is converted from any other, a rate asked for must be the device's
+    if matches.occurrences_of("rate") > 0 && native_rate != rate {
+        return Err(format!(
+            "The {} capture device records at {} Hz, not {} Hz, see --rate",
+            device_name, native_rate, rate
+        ));
+    }
+
     // Build the callback object and start recording
-    let mut received_spec = None;
+    let mut consumers = Vec::new();
+    let capture_device = audio
+        .open_capture(device.as_deref(), &desired_spec, |spec| {
+            println!("Capture Spec = {:?}", spec);
+            input.frequency = spec.freq;
+            // Every source is analysed from its own buffer
+            let sources = input_channels
+                .sources()
+                .into_iter()
+                .map(|source| {
+                    let (producer, consumer) = audio_buffer(resolution, spec.freq);
+                    consumers.push(consumer);
+                    (source, producer)
+                })
+                .collect();
+            Recorder {
+                sources,
+                channels: spec.channels as usize,
+                samples: Vec::with_capacity(spec.samples as usize),
+                recording: if record {
+                    let (producer, consumer) = recording_buffer(spec.freq);
+                    input.recording = Some(consumer);
+                    Some(producer)
+                } else {
+                    None
+                },
+            }
+        })
+        .map_err(|e| {
+            format!(
+                "Could not open the {} capture device at {} Hz with {} channels: {}",
+                device_name,
+                rate,
+                input_channels.channel_count(),
+                e
+            )
+        })?;
+    capture_device.resume();
+    input.source = Source::Audio(consumers);
+    input._capture_device = Some(capture_device);
+    Ok(())
+}
 
-    let capture_device = audio_subsystem.open_capture(None, &desired_spec, |spec| {
-        println!("Capture Spec = {:?}", spec);
-        received_spec = Some(spec);
-        Recorder { audio_sender }
+// Play a recording, analysing it as it is heard
+fn open_replay(
+    input: &mut Input,
+    audio: &AudioSubsystem,
+    path: &str,
+    resolution: usize,
+    record: bool,
+) -> Result<(), String> {
+    let (rate, samples) = session::read_wav(path)?;
+    let desired_spec = AudioSpecDesired {
+        freq: Some(rate),
+        channels: Some(1),
+        samples: None,
+    };
+    let mut consumers = Vec::new();
+    let device = audio.open_playback(None, &desired_spec, |spec| {
+        println!("Replay Spec = {:?}", spec);
+        input.frequency = spec.freq;
+        let (producer, consumer) = audio_buffer(resolution, spec.freq);
+        consumers.push(consumer);
+        Player {
+            samples,
+            position: 0,
+            producer: Some(producer),
+            recording: if record {
+                let (producer, consumer) = recording_buffer(spec.freq);
+                input.recording = Some(consumer);
+                Some(producer)
+            } else {
+                None
+            },
+        }
     })?;
-    let frequency = received_spec.unwrap().freq;
+    device.resume();
+    input.source = Source::Audio(consumers);
+    input._replay_device = Some(device);
+    Ok(())
+}
 
-    capture_device.resume();
+// Open the outputs given on the command line, with the device playing the feedback if asked
+// The recording is the samples to save and their rate
+fn open_outputs(
+    matches: &ArgMatches,
+    audio: &AudioSubsystem,
+    disp_opt: DisplayOptions,
+    suggestions: SuggestionOptions,
+    register: Register,
+    recording: Option<(Consumer, i32)>,
+) -> Result<(Outputs, Option<AudioDevice<Synth>>), String> {
+    // Save the audio and the scores if asked
+    let recording = match (matches.value_of("record"), recording) {
+        (Some(path), Some((samples, rate))) => {
+            session::record_audio(path, rate as u32, samples)?;
+            let scores_path = session::scores_path(path);
+            println!("Recording to {} and {}", path, scores_path);
+            Some(ScoreRecording::create(&scores_path)?)
+        }
+        // Nothing is captured from MIDI or a chart
+        _ => None,
+    };
 
-    // Build audio receiver and aggrgator
-    let buffer = AudioBuffer::new(audio_receiver, buf_opt);
+    // Play the best notes if asked, the device plays as long as it lives
+    let feedback = matches.value_of("feedback").map(|mode| {
+        Feedback::new(FeedbackOptions {
+            mode: match mode {
+                "arpeggio" => FeedbackMode::Arpeggio,
+                _ => FeedbackMode::Drone,
+            },
+            count: matches
+                .value_of("feedbacknotes")
+                .unwrap()
+                .parse::<usize>()
+                .unwrap(),
+            volume: matches
+                .value_of("feedbackvolume")
+                .unwrap()
+                .parse::<f32>()
+                .unwrap(),
+            register,
+        })
+    });
+    let playback_device = match &feedback {
+        Some(feedback) => {
+            let desired_spec = AudioSpecDesired {
+                freq: Some(SAMPLE_RATE / 2),
+                channels: Some(1),
+                samples: None,
+            };
+            let device = audio.open_playback(None, &desired_spec, |spec| {
+                println!("Playback Spec = {:?}", spec);
+                feedback.synth(spec.freq)
+            })?;
+            device.resume();
+            Some(device)
+        }
+        None => None,
+    };
 
-    let scoring_options = fourier::ScoringOptions {
-        frequency,
-        zpadding,
-        halflife,
+    // Send MIDI if a port or a file is given
+    let mut midi_ports: Vec<Box<dyn MidiPort>> = Vec::new();
+    if let Some(path) = matches.value_of("midiport") {
+        midi_ports.push(Box::new(RawPort::open(path)?));
+    }
+    if let Some(path) = matches.value_of("midifile") {
+        midi_ports.push(Box::new(MidiFile::create(path)?));
+    }
+    let midi = if midi_ports.is_empty() {
+        None
+    } else {
+        let messages = matches.values_of("midimessages").unwrap().collect::<Vec<_>>();
+        let options = MidiOptions {
+            notes: messages.contains(&"notes"),
+            controls: messages.contains(&"cc"),
+            channel: matches
+                .value_of("midichannel")
+                .unwrap()
+                .parse::<u8>()
+                .unwrap()
+                - 1,
+            selection: SuggestionOptions {
+                count: matches
+                    .value_of("midinotes")
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap(),
+                fold: false,
+                ..suggestions
+            },
+        };
+        Some(MidiOutput::new(options, midi_ports))
     };
 
-    // Start the data analysis
-    std::thread::spawn(move || {
-        fourier::fourier_thread(buffer, score_sender, scoring_options);
-    });
+    // Stream the scores and receive changes over OSC if asked
+    let osc = if matches.is_present("oscsend") || matches.is_present("osclisten") {
+        let osc = Osc::new(OscOptions {
+            target: matches.value_of("oscsend").map(str::to_owned),
+            listen: matches.value_of("osclisten").map(bind_address),
+            prefix: matches.value_of("oscprefix").unwrap().to_owned(),
+        })?;
+        if let Some(address) = osc.listening() {
+            println!("Listening for OSC messages on {}", address);
+        }
+        Some(osc)
+    } else {
+        None
+    };
+
+    // Serve the fretboard to the browsers if asked
+    let web = match matches.value_of("web") {
+        Some(address) => Some(WebServer::start(&bind_address(address), disp_opt)?),
+        None => None,
+    };
 
-    if matches.is_present("terminal") {
-        display_term::display(score_receiver, disp_opt)
+    let outputs = Outputs {
+        feedback,
+        midi,
+        osc,
+        web,
+        recording,
+    };
+    Ok((outputs, playback_device))
+}
+
+// Start the data analysis of the source, on its own thread
+// The thread drops the sender of the receiver returned when it stops, along with the outputs,
+// and a chart is also given the commands of the display
+fn start_analysis(
+    source: Source,
+    score_sender: Sender<Scores>,
+    scoring_options: fourier::ScoringOptions,
+    buf_opt: BufferOptions,
+    cache_dir: Option<PathBuf>,
+    outputs: Outputs,
+) -> (Receiver<()>, Option<Sender<ChartCommand>>) {
+    let (running, stopped) = channel::<()>();
+    let mut chart_commands = None;
+    match source {
+        Source::Midi(source) => {
+            std::thread::spawn(move || {
+                let _running = running;
+                midi_input::midi_thread(source, score_sender, scoring_options, outputs);
+            });
+        }
+        Source::Chart(chart, chart_options) => {
+            let (command_sender, command_receiver) = channel();
+            chart_commands = Some(command_sender);
+            std::thread::spawn(move || {
+                let _running = running;
+                chart::chart_thread(
+                    chart,
+                    command_receiver,
+                    score_sender,
+                    scoring_options,
+                    chart_options,
+                    outputs,
+                );
+            });
+        }
+        Source::Audio(consumers) => {
+            // Build audio receiver and aggrgator
+            // The player's own channels are analysed apart, if any
+            let mut consumers = consumers.into_iter();
+            let buffer = AudioBuffer::new(consumers.next().unwrap(), buf_opt.clone());
+            let played = consumers.next().map(|consumer| AudioBuffer::new(consumer, buf_opt));
+            std::thread::spawn(move || {
+                let _running = running;
+                fourier::fourier_thread(
+                    buffer,
+                    played,
+                    score_sender,
+                    scoring_options,
+                    cache_dir,
+                    outputs,
+                );
+            });
+        }
+    }
+    (stopped, chart_commands)
+}
+
+// Check a channel number, from 1
+fn validate_channel(s: String) -> Result<(), String> {
+    match devices::parse_channel(&s) {
+        Some(_) => Ok(()),
+        None => Err(format!(
+            "The channels must be from 1 to {}",
+            devices::MAX_CHANNELS
+        )),
+    }
+}
+
+// A port alone listens on every interface
+fn bind_address(address: &str) -> String {
+    if address.parse::<u16>().is_ok() {
+        format!("0.0.0.0:{}", address)
     } else {
-        display_sdl::display(sdl_context, score_receiver, disp_opt)
+        address.to_owned()
+    }
+}
+
+// Parse a range of frets, as in 5-12
+fn parse_fret_range(s: &str) -> Option<(usize, usize)> {
+    let mut bounds = s.splitn(2, '-').map(|s| s.trim().parse::<usize>().ok());
+    match (bounds.next()??, bounds.next()??) {
+        (first, last) if 1 <= first && first <= last => Some((first, last)),
+        _ => None,
     }
 }
 
-// Audio callback object, simply allocates and transfers to a sender
+// Audio callback object, simply copies into the preallocated ring buffers
+// Every buffer gets the mix of some channels of the interleaved samples
+// The samples of the only buffer are also copied to the recording, if any
 struct Recorder {
-    audio_sender: Sender<Vec<f32>>,
+    sources: Vec<(Vec<usize>, Producer)>,
+    channels: usize,
+    // The samples of a source, kept to avoid allocating in the callback
+    samples: Vec<f32>,
+    recording: Option<Producer>,
 }
 
 impl AudioCallback for Recorder {
     type Channel = f32;
 
     fn callback(&mut self, input: &mut [f32]) {
-        self.audio_sender.send(input.to_owned()).ok();
+        let Recorder {
+            sources,
+            channels,
+            samples,
+            recording,
+        } = self;
+        for (i, (source, producer)) in sources.iter_mut().enumerate() {
+            let mixed = if *channels > 1 {
+                samples.clear();
+                samples.extend(input.chunks_exact(*channels).map(|frame| {
+                    source.iter().map(|&channel| frame[channel]).sum::<f32>() / source.len() as f32
+                }));
+                &samples[..]
+            } else {
+                &input[..]
+            };
+            producer.push(mixed);
+            // The channels are not split when recording
+            if let (0, Some(recording)) = (i, recording.as_mut()) {
+                recording.push(mixed);
+            }
+        }
     }
 }
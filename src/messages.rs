@@ -0,0 +1,60 @@
+// Messages from the threads running beside the display
+// They are printed, except while the terminal display is drawn in place, where printing would
+// scroll the fretboard away, the display showing the latest one on its status line instead
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How long a message stays on the status line
+const SHOWN_FOR: Duration = Duration::from_secs(5);
+
+// Set while the terminal display owns the screen
+static CAPTURED: AtomicBool = AtomicBool::new(false);
+
+// The latest message captured, and when it was sent
+static LATEST: Mutex<Option<(String, Instant)>> = Mutex::new(None);
+
+// Keep the messages for the display rather than printing them
+pub fn capture(captured: bool) {
+    CAPTURED.store(captured, Ordering::Release);
+}
+
+// Try to store a message for the display, returning false when it should be printed
+fn store(message: &str) -> bool {
+    if !CAPTURED.load(Ordering::Acquire) {
+        return false;
+    }
+    if let Ok(mut latest) = LATEST.lock() {
+        *latest = Some((message.to_owned(), Instant::now()));
+    }
+    true
+}
+
+// A progress message, printed to stdout
+pub fn info(message: &str) {
+    if !store(message) {
+        println!("{}", message);
+    }
+}
+
+// A warning or an error, printed to stderr
+pub fn warn(message: &str) {
+    if !store(message) {
+        eprintln!("{}", message);
+    }
+}
+
+// The message captured in the last few seconds, if any
+pub fn recent() -> Option<String> {
+    let latest = LATEST.lock().ok()?;
+    match &*latest {
+        Some((message, sent)) if sent.elapsed() < SHOWN_FOR => Some(message.clone()),
+        _ => None,
+    }
+}
+
+// The last message captured, to be printed once the display gives the screen back
+pub fn take() -> Option<String> {
+    LATEST.lock().ok()?.take().map(|(message, _)| message)
+}
@@ -0,0 +1,352 @@
+// Sends the most consonant notes and the pitch class scores as MIDI, to ports or a file
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+
+use crate::messages;
+use crate::scores::{Scores, SuggestionOptions};
+
+// The velocity of the notes played
+const VELOCITY: u8 = 100;
+
+// The controller carrying the score of C, the other pitch classes follow it
+// 20 to 31 are not assigned to anything by the MIDI specification
+const FIRST_CONTROLLER: u8 = 20;
+
+// Standard MIDI File timing, 480 ticks per beat at the default 120 beats per minute
+const TICKS_PER_BEAT: u16 = 480;
+const TICKS_PER_SECOND: f64 = 960.0;
+
+// The position of the length of the track in the file
+const TRACK_LENGTH_OFFSET: u64 = 18;
+const END_OF_TRACK: [u8; 4] = [0x00, 0xFF, 0x2F, 0x00];
+
+// Where the messages go, a port or a file
+pub trait MidiPort: Send {
+    // Send the messages of a frame, at a time in seconds from the start of the stream
+    fn send(&mut self, seconds: f64, messages: &[Vec<u8>]) -> io::Result<()>;
+}
+
+// A raw MIDI device or a named pipe, as in /dev/snd/midiC1D0
+// The snd-virmidi module provides local virtual ports that other programs can read
+pub struct RawPort {
+    file: File,
+}
+
+impl RawPort {
+    pub fn open(path: &str) -> Result<RawPort, String> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .map_err(|e| format!("Could not open {}: {}", path, e))?;
+        Ok(RawPort { file })
+    }
+}
+
+impl MidiPort for RawPort {
+    fn send(&mut self, _seconds: f64, messages: &[Vec<u8>]) -> io::Result<()> {
+        for message in messages {
+            self.file.write_all(message)?;
+        }
+        self.file.flush()
+    }
+}
+
+// A single track Standard MIDI File
+// The file is complete after every frame, so that a headless run can be stopped at any time
+pub struct MidiFile {
+    file: File,
+    // The length of the track, including its end
+    length: u32,
+    // The time of the last event
+    ticks: u64,
+}
+
+impl MidiFile {
+    pub fn create(path: &str) -> Result<MidiFile, String> {
+        let mut file =
+            File::create(path).map_err(|e| format!("Could not create {}: {}", path, e))?;
+        let mut header = Vec::new();
+        header.extend_from_slice(b"MThd");
+        header.extend_from_slice(&6u32.to_be_bytes());
+        // Format 0, with a single track
+        header.extend_from_slice(&0u16.to_be_bytes());
+        header.extend_from_slice(&1u16.to_be_bytes());
+        header.extend_from_slice(&TICKS_PER_BEAT.to_be_bytes());
+        header.extend_from_slice(b"MTrk");
+        header.extend_from_slice(&(END_OF_TRACK.len() as u32).to_be_bytes());
+        header.extend_from_slice(&END_OF_TRACK);
+        file.write_all(&header)
+            .and_then(|_| file.flush())
+            .map_err(|e| format!("Could not write {}: {}", path, e))?;
+        Ok(MidiFile {
+            file,
+            length: END_OF_TRACK.len() as u32,
+            ticks: 0,
+        })
+    }
+}
+
+// Append a number as a MIDI variable length quantity
+fn write_quantity(bytes: &mut Vec<u8>, value: u64) {
+    let mut groups = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        groups.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.extend(groups.iter().rev());
+}
+
+impl MidiPort for MidiFile {
+    fn send(&mut self, seconds: f64, messages: &[Vec<u8>]) -> io::Result<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let ticks = ((seconds * TICKS_PER_SECOND) as u64).max(self.ticks);
+        let mut bytes = Vec::new();
+        for (i, message) in messages.iter().enumerate() {
+            let delta = if i == 0 { ticks - self.ticks } else { 0 };
+            write_quantity(&mut bytes, delta);
+            bytes.extend_from_slice(message);
+        }
+        bytes.extend_from_slice(&END_OF_TRACK);
+        self.ticks = ticks;
+
+        // Replace the end of the track, then update its length
+        self.file
+            .seek(SeekFrom::End(-(END_OF_TRACK.len() as i64)))?;
+        self.file.write_all(&bytes)?;
+        self.length += (bytes.len() - END_OF_TRACK.len()) as u32;
+        self.file.seek(SeekFrom::Start(TRACK_LENGTH_OFFSET))?;
+        self.file.write_all(&self.length.to_be_bytes())?;
+        self.file.flush()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MidiOptions {
+    // Send note on and off messages for the most consonant notes
+    pub notes: bool,
+    // Send a control change for the score of every pitch class
+    pub controls: bool,
+    // The channel of every message, from 0 to 15
+    pub channel: u8,
+    // The notes played
+    pub selection: SuggestionOptions,
+}
+
+// The notes still on are stopped when the output is dropped, at the time of the last frame
+pub struct MidiOutput {
+    options: MidiOptions,
+    ports: Vec<Box<dyn MidiPort>>,
+    // The notes currently on
+    playing: Vec<u8>,
+    // The last value of every controller
+    controls: [Option<u8>; 12],
+    // The time of the last frame, in seconds
+    seconds: f64,
+}
+
+impl MidiOutput {
+    pub fn new(options: MidiOptions, ports: Vec<Box<dyn MidiPort>>) -> MidiOutput {
+        MidiOutput {
+            options,
+            ports,
+            playing: Vec::new(),
+            controls: [None; 12],
+            seconds: 0f64,
+        }
+    }
+
+    // Send the changes since the previous frame, at a time in seconds from the start of the stream
+    pub fn update(&mut self, scores: &Scores, seconds: f64) {
+        let channel = self.options.channel & 0x0F;
+        let mut messages = Vec::new();
+
+        if self.options.notes {
+            let notes: Vec<u8> = self
+                .options
+                .selection
+                .suggest(&scores.note_scores, &scores.pitch_classes)
+                .iter()
+                .map(|suggestion| suggestion.note as usize + 12)
+                .filter(|&number| number < 128)
+                .map(|number| number as u8)
+                .collect();
+            for &note in self.playing.iter().filter(|note| !notes.contains(note)) {
+                messages.push(vec![0x80 | channel, note, 0]);
+            }
+            for &note in notes.iter().filter(|note| !self.playing.contains(note)) {
+                messages.push(vec![0x90 | channel, note, VELOCITY]);
+            }
+            self.playing = notes;
+        }
+
+        if self.options.controls {
+            // The most consonant pitch class gets the highest value
+            let classes = &scores.pitch_classes;
+            let min = classes.iter().cloned().fold(f32::INFINITY, f32::min);
+            let max = classes.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+            for (class, &score) in classes.iter().enumerate() {
+                let value = if max > min {
+                    ((max - score) / (max - min) * 127f32).round() as u8
+                } else {
+                    0
+                };
+                if self.controls[class] != Some(value) {
+                    self.controls[class] = Some(value);
+                    messages.push(vec![0xB0 | channel, FIRST_CONTROLLER + class as u8, value]);
+                }
+            }
+        }
+
+        self.seconds = seconds;
+        self.send(&messages);
+    }
+
+    // Stop using the ports that failed, rather than reporting it at every frame
+    fn send(&mut self, messages: &[Vec<u8>]) {
+        let seconds = self.seconds;
+        self.ports
+            .retain_mut(|port| match port.send(seconds, messages) {
+                Ok(()) => true,
+                Err(e) => {
+                    messages::warn(&format!("MIDI output stopped: {}", e));
+                    false
+                }
+            });
+    }
+}
+
+impl Drop for MidiOutput {
+    fn drop(&mut self) {
+        let channel = self.options.channel & 0x0F;
+        let messages = self
+            .playing
+            .iter()
+            .map(|&note| vec![0x80 | channel, note, 0])
+            .collect::<Vec<_>>();
+        self.send(&messages);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notes::{Note, NOTE_COUNT};
+    use std::sync::{Arc, Mutex};
+
+    // The messages of every frame, with its time
+    type Frames = Vec<(f64, Vec<Vec<u8>>)>;
+
+    // Keeps the frames sent, shared with the test
+    #[derive(Clone, Default)]
+    struct MemoryPort {
+        frames: Arc<Mutex<Frames>>,
+    }
+
+    impl MidiPort for MemoryPort {
+        fn send(&mut self, seconds: f64, messages: &[Vec<u8>]) -> io::Result<()> {
+            if !messages.is_empty() {
+                self.frames
+                    .lock()
+                    .unwrap()
+                    .push((seconds, messages.to_vec()));
+            }
+            Ok(())
+        }
+    }
+
+    // Scores where the given notes are the most consonant, best first
+    fn scores(best: &[usize]) -> Scores {
+        let mut note_scores = [1f32; NOTE_COUNT];
+        for (i, &note) in best.iter().enumerate() {
+            note_scores[note] = i as f32 / 10f32;
+        }
+        Scores {
+            note_scores,
+            note_values: [0f32; NOTE_COUNT],
+            fourier: Vec::new(),
+            stats: None,
+            suggestions: Vec::new(),
+            pitch_classes: [0f32; 12],
+            label: None,
+        }
+    }
+
+    fn quantity(value: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_quantity(&mut bytes, value);
+        bytes
+    }
+
+    #[test]
+    fn variable_length_quantities() {
+        assert_eq!(quantity(0), [0x00]);
+        assert_eq!(quantity(0x40), [0x40]);
+        assert_eq!(quantity(0x7F), [0x7F]);
+        assert_eq!(quantity(0x80), [0x81, 0x00]);
+        assert_eq!(quantity(0x2000), [0xC0, 0x00]);
+        assert_eq!(quantity(0x3FFF), [0xFF, 0x7F]);
+        assert_eq!(quantity(0x4000), [0x81, 0x80, 0x00]);
+        assert_eq!(quantity(0x0FFF_FFFF), [0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn standard_midi_file() {
+        let path = std::env::temp_dir().join(format!("improve-test-{}.mid", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut file = MidiFile::create(path).unwrap();
+        file.send(0.5, &[vec![0x90, 60, 100], vec![0x90, 64, 100]])
+            .unwrap();
+        file.send(0.75, &[]).unwrap();
+        file.send(1.0, &[vec![0x80, 60, 0]]).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).ok();
+
+        let mut expected =
+            b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x01\xE0MTrk\x00\x00\x00\x12".to_vec();
+        expected.extend_from_slice(&[0x83, 0x60, 0x90, 60, 100]);
+        expected.extend_from_slice(&[0x00, 0x90, 64, 100]);
+        expected.extend_from_slice(&[0x83, 0x60, 0x80, 60, 0]);
+        expected.extend_from_slice(&END_OF_TRACK);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn notes_on_and_off() {
+        let port = MemoryPort::default();
+        let options = MidiOptions {
+            notes: true,
+            controls: false,
+            channel: 2,
+            selection: SuggestionOptions {
+                count: 2,
+                fold: false,
+                lowest: Note::iter().next().unwrap(),
+                highest: Note::B9,
+            },
+        };
+        let mut output = MidiOutput::new(options, vec![Box::new(port.clone())]);
+        // The notes from C4, MIDI numbers being 12 above the note indices
+        output.update(&scores(&[48, 52]), 0.0);
+        output.update(&scores(&[48, 52]), 0.25);
+        output.update(&scores(&[52, 55]), 0.5);
+        drop(output);
+
+        let frames = port.frames.lock().unwrap().clone();
+        assert_eq!(
+            frames,
+            vec![
+                (
+                    0.0,
+                    vec![vec![0x92, 60, VELOCITY], vec![0x92, 64, VELOCITY]]
+                ),
+                (0.5, vec![vec![0x82, 60, 0], vec![0x92, 67, VELOCITY]]),
+                // The notes still on are stopped when the output is dropped
+                (0.5, vec![vec![0x82, 64, 0], vec![0x82, 67, 0]]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,636 @@
+// Reads the notes played from MIDI instead of analysing audio
+// The sounding notes are turned into the partials of the virtual instrument, and scored directly
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use itertools::Itertools;
+
+use crate::dissonance::{self, Harmonics};
+use crate::fourier::{self, Outputs, ScoringOptions};
+use crate::frequency::Frequency;
+use crate::messages;
+use crate::notes::Note;
+use crate::scores::{ScoreCalculator, Scores};
+use crate::stats::FrameStats;
+
+// The time between two frames when no note changes, so that the scores still fade
+const FRAME: Duration = Duration::from_millis(30);
+
+// The partials of every note, from its fundamental
+const PARTIAL_COUNT: usize = 16;
+
+// The percussion channel, its notes are not pitches
+const DRUMS: u8 = 9;
+
+// The controller of the sustain pedal
+const SUSTAIN: u8 = 64;
+
+// The default tempo of a Standard MIDI File, in microseconds per beat
+const DEFAULT_TEMPO: u32 = 500_000;
+
+// The messages changing the sounding notes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiEvent {
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    NoteOff { channel: u8, note: u8 },
+    Sustain { channel: u8, on: bool },
+}
+
+// The number of data bytes following a channel status byte
+fn data_length(status: u8) -> usize {
+    match status & 0xF0 {
+        0xC0 | 0xD0 => 1,
+        _ => 2,
+    }
+}
+
+// Decode a channel message, ignoring the ones that don't change the sounding notes
+fn decode(status: u8, data: &[u8]) -> Option<MidiEvent> {
+    let channel = status & 0x0F;
+    match (status & 0xF0, data) {
+        (0x90, &[note, velocity]) if velocity > 0 => Some(MidiEvent::NoteOn {
+            channel,
+            note,
+            velocity,
+        }),
+        (0x80, &[note, _]) | (0x90, &[note, _]) => Some(MidiEvent::NoteOff { channel, note }),
+        (0xB0, &[SUSTAIN, value]) => Some(MidiEvent::Sustain {
+            channel,
+            on: value >= 64,
+        }),
+        _ => None,
+    }
+}
+
+// Where the notes come from
+pub enum MidiSource {
+    // A raw MIDI device or a named pipe, read as it is played
+    Port(File),
+    // The events of a Standard MIDI File and their time in seconds, played back in real time
+    Song(Vec<(f64, MidiEvent)>),
+}
+
+impl MidiSource {
+    // Read a Standard MIDI File if the path ends with .mid, or open a raw MIDI device otherwise
+    pub fn open(path: &str) -> Result<MidiSource, String> {
+        let lower = path.to_lowercase();
+        if lower.ends_with(".mid") || lower.ends_with(".midi") {
+            let mut bytes = Vec::new();
+            File::open(path)
+                .and_then(|mut file| file.read_to_end(&mut bytes))
+                .map_err(|e| format!("Could not read {}: {}", path, e))?;
+            let events = parse_song(&bytes).map_err(|e| format!("{}: {}", path, e))?;
+            Ok(MidiSource::Song(events))
+        } else {
+            let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?;
+            Ok(MidiSource::Port(file))
+        }
+    }
+
+    // Send the events as they happen, from their own thread
+    fn start(self) -> Receiver<MidiEvent> {
+        let (sender, receiver) = channel();
+        match self {
+            MidiSource::Port(file) => std::thread::spawn(move || read_port(file, sender)),
+            MidiSource::Song(events) => std::thread::spawn(move || play_song(events, sender)),
+        };
+        receiver
+    }
+}
+
+// Decode the messages of a live port, which can use running status
+fn read_port(file: File, sender: Sender<MidiEvent>) {
+    let mut status = None;
+    let mut data = Vec::new();
+    for byte in BufReader::new(file).bytes() {
+        let byte = match byte {
+            Ok(byte) => byte,
+            Err(e) => {
+                messages::warn(&format!("MIDI input stopped: {}", e));
+                return;
+            }
+        };
+        match byte {
+            // Real time messages can happen anywhere, even inside another message
+            0xF8..=0xFF => {}
+            // System messages cancel the running status, their data is ignored
+            0xF0..=0xF7 => {
+                status = None;
+                data.clear();
+            }
+            0x80..=0xEF => {
+                status = Some(byte);
+                data.clear();
+            }
+            _ => {
+                if let Some(status) = status {
+                    data.push(byte);
+                    if data.len() == data_length(status) {
+                        if let Some(event) = decode(status, &data) {
+                            if sender.send(event).is_err() {
+                                return;
+                            }
+                        }
+                        data.clear();
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Send the events of a song at their time
+fn play_song(events: Vec<(f64, MidiEvent)>, sender: Sender<MidiEvent>) {
+    let start = Instant::now();
+    for (time, event) in events {
+        let time = Duration::from_secs_f64(time);
+        let elapsed = start.elapsed();
+        if time > elapsed {
+            std::thread::sleep(time - elapsed);
+        }
+        if sender.send(event).is_err() {
+            return;
+        }
+    }
+}
+
+// Reads the big-endian numbers of a Standard MIDI File
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let end = self.position + count;
+        if end > self.bytes.len() {
+            return Err("Truncated MIDI file".to_owned());
+        }
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn peek(&self) -> Result<u8, String> {
+        self.bytes
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| "Truncated MIDI file".to_owned())
+    }
+
+    fn number(&mut self, count: usize) -> Result<u32, String> {
+        Ok(self
+            .take(count)?
+            .iter()
+            .fold(0, |number, &byte| number << 8 | byte as u32))
+    }
+
+    // A variable length quantity, 7 bits per byte
+    fn quantity(&mut self) -> Result<u32, String> {
+        let mut number = 0;
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            number = number << 7 | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(number);
+            }
+        }
+        Err("Invalid MIDI file, a quantity is too long".to_owned())
+    }
+}
+
+// What happens at a tick of a track
+#[derive(Debug, PartialEq)]
+pub enum TrackEvent {
+    Midi(MidiEvent),
+    // A new tempo, in microseconds per beat
+    Tempo(u32),
+    // A new meter, in beats per bar and the note value of a beat, as 4 for quarter notes
+    TimeSignature(u32, u32),
+    // The end of a track, which can come after its last note
+    End,
+}
+
+// Read the events of a track, and their time in ticks
+fn parse_track(bytes: &[u8]) -> Result<Vec<(u64, TrackEvent)>, String> {
+    let mut reader = Reader { bytes, position: 0 };
+    let mut events = Vec::new();
+    let mut tick = 0u64;
+    let mut status = None;
+    while reader.position < bytes.len() {
+        tick += reader.quantity()? as u64;
+        match reader.peek()? {
+            // Meta and system exclusive events cancel the running status
+            0xFF => {
+                status = None;
+                reader.byte()?;
+                let kind = reader.byte()?;
+                let length = reader.quantity()? as usize;
+                let data = reader.take(length)?;
+                match (kind, data) {
+                    (0x51, &[a, b, c]) => {
+                        let tempo = (a as u32) << 16 | (b as u32) << 8 | c as u32;
+                        events.push((tick, TrackEvent::Tempo(tempo)));
+                    }
+                    (0x58, &[beats, unit, ..]) => {
+                        let signature = TrackEvent::TimeSignature(beats as u32, 1 << unit.min(6));
+                        events.push((tick, signature));
+                    }
+                    (0x2F, _) => {
+                        events.push((tick, TrackEvent::End));
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            0xF0 | 0xF7 => {
+                status = None;
+                reader.byte()?;
+                let length = reader.quantity()? as usize;
+                reader.take(length)?;
+            }
+            byte => {
+                // Without a status byte, the previous one is used again
+                if byte >= 0x80 {
+                    status = Some(reader.byte()?);
+                }
+                let status = status.ok_or("Invalid MIDI file, data without a status")?;
+                let data = reader.take(data_length(status))?;
+                if let Some(event) = decode(status, data) {
+                    events.push((tick, TrackEvent::Midi(event)));
+                }
+            }
+        }
+    }
+    Ok(events)
+}
+
+// Read the events of every track of a Standard MIDI File, in the order they happen
+// Returns the division of the file, the number of ticks per beat unless it is SMPTE timed
+pub fn read_song(bytes: &[u8]) -> Result<(u16, Vec<(u64, TrackEvent)>), String> {
+    let mut reader = Reader { bytes, position: 0 };
+    if reader.take(4)? != b"MThd" {
+        return Err("Not a Standard MIDI File".to_owned());
+    }
+    let length = reader.number(4)? as usize;
+    let header = reader.take(length)?;
+    if header.len() < 6 {
+        return Err("Invalid MIDI file header".to_owned());
+    }
+    let division = (header[4] as u16) << 8 | header[5] as u16;
+    // Either ticks per beat, or frames per second and ticks per frame
+    if division & 0x7FFF == 0 || (division & 0x8000 != 0 && division & 0xFF == 0) {
+        return Err("Invalid MIDI file division".to_owned());
+    }
+
+    // Merge the tracks, the tempo changes being usually alone in the first one
+    let mut events = Vec::new();
+    while reader.position < bytes.len() {
+        let kind = reader.take(4)?;
+        let length = reader.number(4)? as usize;
+        let chunk = reader.take(length)?;
+        // Unknown chunks are to be ignored
+        if kind == b"MTrk" {
+            events.extend(parse_track(chunk)?);
+        }
+    }
+    // The sort is stable, so the events of a tick stay in their track order
+    events.sort_by_key(|&(tick, _)| tick);
+    Ok((division, events))
+}
+
+// Read the note events of a Standard MIDI File, and their time in seconds
+fn parse_song(bytes: &[u8]) -> Result<Vec<(f64, MidiEvent)>, String> {
+    let (division, events) = read_song(bytes)?;
+
+    // Convert ticks to seconds
+    let mut seconds = 0f64;
+    let mut last_tick = 0u64;
+    let mut tempo = DEFAULT_TEMPO;
+    let mut song = Vec::new();
+    for (tick, event) in events {
+        let ticks = (tick - last_tick) as f64;
+        seconds += if division & 0x8000 != 0 {
+            // SMPTE time, in frames per second and ticks per frame
+            let fps = -((division >> 8) as u8 as i8) as f64;
+            ticks / (fps * (division & 0xFF) as f64)
+        } else {
+            ticks * tempo as f64 / 1e6 / division as f64
+        };
+        last_tick = tick;
+        match event {
+            TrackEvent::Midi(event) => song.push((seconds, event)),
+            TrackEvent::Tempo(new_tempo) => tempo = new_tempo,
+            TrackEvent::TimeSignature(..) | TrackEvent::End => {}
+        }
+    }
+    Ok(song)
+}
+
+// The notes sounding, and their velocity
+#[derive(Default)]
+pub struct Sounding {
+    // The velocity of every note, by channel and MIDI number
+    pub notes: HashMap<(u8, u8), u8>,
+    // The channels with the sustain pedal down
+    sustain: HashSet<u8>,
+    // The notes released while the pedal is down, which keep sounding until it is up
+    held: HashSet<(u8, u8)>,
+}
+
+impl Sounding {
+    pub fn apply(&mut self, event: MidiEvent) {
+        match event {
+            MidiEvent::NoteOn {
+                channel,
+                note,
+                velocity,
+            } if channel != DRUMS => {
+                self.held.remove(&(channel, note));
+                self.notes.insert((channel, note), velocity);
+            }
+            MidiEvent::NoteOff { channel, note } if channel != DRUMS => {
+                if self.sustain.contains(&channel) {
+                    self.held.insert((channel, note));
+                } else {
+                    self.notes.remove(&(channel, note));
+                }
+            }
+            MidiEvent::Sustain { channel, on: true } => {
+                self.sustain.insert(channel);
+            }
+            MidiEvent::Sustain { channel, on: false } => {
+                self.sustain.remove(&channel);
+                for key in self.held.iter().filter(|key| key.0 == channel) {
+                    self.notes.remove(key);
+                }
+                self.held.retain(|key| key.0 != channel);
+            }
+            _ => {}
+        }
+    }
+
+    // The partials of the virtual instrument playing every sounding note, lowest first
+    fn partials(&self, harmonics: &Harmonics) -> Vec<Frequency> {
+        partials(
+            self.notes
+                .iter()
+                .map(|(&(_, note), &velocity)| (note, velocity)),
+            harmonics,
+        )
+    }
+}
+
+// The partials of the virtual instrument playing MIDI notes and their velocity, lowest first
+pub fn partials(notes: impl Iterator<Item = (u8, u8)>, harmonics: &Harmonics) -> Vec<Frequency> {
+    notes
+        .filter_map(|(number, velocity)| {
+            // MIDI notes start at C-1
+            let note = Note::iter().nth((number as usize).checked_sub(12)?)?;
+            let gain = (velocity as f32 / 127f32).powi(2);
+            Some((note, gain))
+        })
+        .flat_map(|(note, gain)| {
+            dissonance::note_partials(harmonics, note)
+                .iter()
+                .take(PARTIAL_COUNT)
+                .map(move |f| Frequency {
+                    value: f.value,
+                    intensity: f.intensity * gain,
+                })
+        })
+        .sorted_by(|a, b| a.value.partial_cmp(&b.value).unwrap())
+        .collect()
+}
+
+// Scores the notes read from MIDI, as soon as they change
+pub fn midi_thread(
+    source: MidiSource,
+    sender: Sender<Scores>,
+    mut options: ScoringOptions,
+    mut outputs: Outputs,
+) {
+    let events = source.start();
+    let harmonics = Box::new(dissonance::get_notes_harmonics());
+    let mut calculator = ScoreCalculator::exact(options.threads);
+    let mut sounding = Sounding::default();
+
+    messages::info("Starting analysis");
+    let start = Instant::now();
+    let mut previous = start;
+    let mut ended = false;
+    loop {
+        // Wake up at the first change, and take the ones that came with it
+        let start_wait = Instant::now();
+        match events.recv_timeout(FRAME) {
+            Ok(event) => {
+                sounding.apply(event);
+                for event in events.try_iter() {
+                    sounding.apply(event);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            // The song is over, the notes still on are released and the run ends with them
+            Err(RecvTimeoutError::Disconnected) => {
+                sounding = Sounding::default();
+                ended = true;
+            }
+        }
+        let wait = start_wait.elapsed();
+        let now = Instant::now();
+        let seconds = (now - previous).as_secs_f32();
+        previous = now;
+
+        let start_scoring = Instant::now();
+        let partials = sounding.partials(&harmonics);
+        let mut scores = calculator.calculate(partials, seconds, options.halflife);
+        // The partials are not a spectrum, and can't be displayed as one
+        scores.fourier = Vec::new();
+        let time = (now - start).as_secs_f64();
+        fourier::publish(&mut scores, &mut options, &mut outputs, time);
+        scores.stats = Some(FrameStats {
+            timestamp: (time * options.frequency as f64) as u64,
+            captured: now,
+            wait,
+            fourier: Duration::default(),
+            scoring: start_scoring.elapsed(),
+            overruns: 0,
+            underruns: 0,
+        });
+        if sender.send(scores).is_err() || ended {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A track chunk of the given events, ended
+    fn track(events: &[u8]) -> Vec<u8> {
+        let mut chunk = b"MTrk".to_vec();
+        chunk.extend_from_slice(&(events.len() as u32 + 4).to_be_bytes());
+        chunk.extend_from_slice(events);
+        chunk.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+        chunk
+    }
+
+    // A Standard MIDI File of format 1
+    fn song(division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = b"MThd\x00\x00\x00\x06\x00\x01".to_vec();
+        bytes.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&division.to_be_bytes());
+        for events in tracks {
+            bytes.extend(track(events));
+        }
+        bytes
+    }
+
+    fn times(song: &[(f64, MidiEvent)]) -> Vec<f64> {
+        song.iter().map(|&(time, _)| time).collect()
+    }
+
+    fn on(channel: u8, note: u8) -> MidiEvent {
+        MidiEvent::NoteOn {
+            channel,
+            note,
+            velocity: 100,
+        }
+    }
+
+    fn off(channel: u8, note: u8) -> MidiEvent {
+        MidiEvent::NoteOff { channel, note }
+    }
+
+    #[test]
+    fn running_status() {
+        let events = parse_track(&[
+            0x00, 0x91, 60, 100, // A note on
+            0x10, 64, 100, // Another, with the same status
+            0x10, 60, 0, // A note on without velocity is a note off
+            0x00, 0xB1, SUSTAIN, 127, // A new status
+            0x08, SUSTAIN, 0, // Released
+            0x00, 0xFF, 0x2F, 0x00, // The end, the rest is ignored
+            0x00, 0x91, 62, 100,
+        ])
+        .unwrap();
+        assert_eq!(
+            events,
+            vec![
+                (0, TrackEvent::Midi(on(1, 60))),
+                (16, TrackEvent::Midi(on(1, 64))),
+                (32, TrackEvent::Midi(off(1, 60))),
+                (
+                    32,
+                    TrackEvent::Midi(MidiEvent::Sustain {
+                        channel: 1,
+                        on: true
+                    })
+                ),
+                (
+                    40,
+                    TrackEvent::Midi(MidiEvent::Sustain {
+                        channel: 1,
+                        on: false
+                    })
+                ),
+                (40, TrackEvent::End),
+            ]
+        );
+        assert!(parse_track(&[0x00, 60, 100]).is_err());
+        assert!(parse_track(&[0x00, 0x90, 60]).is_err());
+        // Meta and system exclusive events cancel the running status
+        let meta = [
+            0x00, 0x90, 60, 100, 0x00, 0xFF, 0x01, 0x02, b'h', b'i', 0x00, 62, 100,
+        ];
+        assert!(parse_track(&meta).is_err());
+        let sysex = [
+            0x00, 0x90, 60, 100, 0x00, 0xF0, 0x02, 0x01, 0xF7, 0x00, 62, 100,
+        ];
+        assert!(parse_track(&sysex).is_err());
+        let restated = [0x00, 0xF0, 0x02, 0x01, 0xF7, 0x00, 0x90, 62, 100];
+        assert_eq!(
+            parse_track(&restated).unwrap(),
+            vec![(0, TrackEvent::Midi(on(0, 62)))]
+        );
+    }
+
+    #[test]
+    fn tempo_changes() {
+        let bytes = song(
+            480,
+            &[
+                // 120 beats per minute, then 60 after a beat
+                &[
+                    0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, 0x83, 0x60, 0xFF, 0x51, 0x03, 0x0F,
+                    0x42, 0x40,
+                ],
+                // A note on every beat
+                &[
+                    0x00, 0x90, 60, 100, 0x83, 0x60, 0x80, 60, 0, 0x83, 0x60, 0x90, 62, 100,
+                ],
+            ],
+        );
+        let song = parse_song(&bytes).unwrap();
+        assert_eq!(times(&song), vec![0.0, 0.5, 1.5]);
+        assert_eq!(song[2].1, on(0, 62));
+    }
+
+    #[test]
+    fn smpte_division() {
+        // 25 frames per second of 40 ticks, so 1000 ticks per second
+        let division = ((-25i8 as u8 as u16) << 8) | 40;
+        let bytes = song(division, &[&[0x00, 0x90, 60, 100, 0x87, 0x68, 0x80, 60, 0]]);
+        assert_eq!(times(&parse_song(&bytes).unwrap()), vec![0.0, 1.0]);
+        // Frames without ticks
+        let division = (-25i8 as u8 as u16) << 8;
+        assert!(parse_song(&song(division, &[&[0x00, 0x90, 60, 100]])).is_err());
+    }
+
+    #[test]
+    fn invalid_songs() {
+        assert!(parse_song(b"RIFF").is_err());
+        assert!(parse_song(&song(0, &[&[0x00, 0x90, 60, 100]])).is_err());
+        let mut truncated = song(480, &[&[0x00, 0x90, 60, 100]]);
+        truncated.truncate(truncated.len() - 6);
+        assert!(parse_song(&truncated).is_err());
+    }
+
+    #[test]
+    fn sustain_pedal() {
+        let mut sounding = Sounding::default();
+        let notes =
+            |sounding: &Sounding| sounding.notes.keys().cloned().sorted().collect::<Vec<_>>();
+        sounding.apply(on(0, 60));
+        sounding.apply(MidiEvent::Sustain {
+            channel: 0,
+            on: true,
+        });
+        // Released notes keep sounding while the pedal is down, on its channel only
+        sounding.apply(off(0, 60));
+        sounding.apply(on(0, 64));
+        sounding.apply(off(0, 64));
+        sounding.apply(on(1, 67));
+        sounding.apply(off(1, 67));
+        assert_eq!(notes(&sounding), vec![(0, 60), (0, 64)]);
+        // A note played again is not released with the pedal
+        sounding.apply(on(0, 60));
+        sounding.apply(MidiEvent::Sustain {
+            channel: 0,
+            on: false,
+        });
+        assert_eq!(notes(&sounding), vec![(0, 60)]);
+        // Drums are not pitches
+        sounding.apply(on(DRUMS, 38));
+        assert_eq!(notes(&sounding), vec![(0, 60)]);
+    }
+}
@@ -0,0 +1,434 @@
+// Reads the bars and notes of an uncompressed MusicXML score
+// Only the elements giving the pitch and timing of the notes are read, the rest is skipped
+
+use crate::chart::{Bar, Span, Tune};
+
+// The velocity of every note, MusicXML dynamics are ignored
+const VELOCITY: u8 = 80;
+
+// The position of the notes of the natural steps in an octave
+const STEPS: [(&str, i32); 7] = [
+    ("C", 0),
+    ("D", 2),
+    ("E", 4),
+    ("F", 5),
+    ("G", 7),
+    ("A", 9),
+    ("B", 11),
+];
+
+// An element of the document, with the text following it
+struct Tag<'a> {
+    name: &'a str,
+    attributes: &'a str,
+    // If this is the end of an element
+    closing: bool,
+    // If the element has no content, as in <chord/>
+    empty: bool,
+    text: &'a str,
+}
+
+impl<'a> Tag<'a> {
+    // The value of an attribute, as in tempo="120"
+    fn attribute(&self, name: &str) -> Option<&'a str> {
+        let attributes = self.attributes;
+        attributes.match_indices(name).find_map(|(start, _)| {
+            // A whole name, as in tempo but not in xtempo
+            if !attributes[..start].ends_with(char::is_whitespace) {
+                return None;
+            }
+            let value = attributes[start + name.len()..].trim_start();
+            let value = value.strip_prefix('=')?.trim_start();
+            let quote = match value.chars().next()? {
+                quote @ ('"' | '\'') => quote,
+                _ => return None,
+            };
+            let value = &value[1..];
+            Some(&value[..value.find(quote)?])
+        })
+    }
+}
+
+// Split a document into its elements, skipping the declarations and the comments
+fn tags(xml: &str) -> Vec<Tag<'_>> {
+    let mut tags = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start..];
+        let end = if rest.starts_with("<!--") {
+            rest.find("-->").map(|end| end + 3)
+        } else {
+            rest.find('>').map(|end| end + 1)
+        };
+        let end = match end {
+            Some(end) => end,
+            None => break,
+        };
+        let content = &rest[1..end - 1];
+        rest = &rest[end..];
+        if content.starts_with('?') || content.starts_with('!') {
+            continue;
+        }
+        let text = &rest[..rest.find('<').unwrap_or(rest.len())];
+        let closing = content.starts_with('/');
+        let empty = content.ends_with('/');
+        let content = content.trim_start_matches('/').trim_end_matches('/');
+        let (name, attributes) = match content.find(char::is_whitespace) {
+            Some(split) => (&content[..split], &content[split..]),
+            None => (content, ""),
+        };
+        tags.push(Tag {
+            name,
+            attributes,
+            closing,
+            empty,
+            text: text.trim(),
+        });
+    }
+    tags
+}
+
+// The note being read
+#[derive(Default)]
+struct Note {
+    chord: bool,
+    rest: bool,
+    grace: bool,
+    step: Option<i32>,
+    alter: i32,
+    octave: Option<i32>,
+    duration: f64,
+}
+
+// The bars and notes of the score, every part being played at once
+pub fn read(xml: &str) -> Result<Tune, String> {
+    let tags = tags(xml);
+    if !tags
+        .iter()
+        .any(|tag| tag.name == "score-partwise" && !tag.closing)
+    {
+        return Err("Not a partwise MusicXML score".to_owned());
+    }
+
+    let mut tune = Tune::default();
+    // The elements the current one is in
+    let mut parents: Vec<&str> = Vec::new();
+    // The part being read, the bars are taken from the first one
+    let mut part = 0;
+    // The number of divisions in a quarter note
+    let mut divisions = 1f64;
+    let (mut beats, mut unit) = (4u32, 4u32);
+    // The position in quarter notes, and the start of the current bar
+    let mut position = 0f64;
+    let mut bar_start = 0f64;
+    let mut bar_end = 0f64;
+    // The start of the previous note, for the next notes of a chord
+    let mut note_start = 0f64;
+    let mut note = Note::default();
+    let mut duration = 0f64;
+
+    for tag in tags.iter() {
+        if tag.closing {
+            parents.pop();
+        } else {
+            if !tag.empty {
+                parents.push(tag.name);
+            }
+            let parent = parents.iter().rev().nth(if tag.empty { 0 } else { 1 });
+            match (tag.name, parent) {
+                ("part", _) => {
+                    position = 0f64;
+                    bar_end = 0f64;
+                }
+                ("measure", _) => {
+                    bar_start = bar_end;
+                    position = bar_start;
+                }
+                ("divisions", _) => divisions = tag.text.parse::<f64>().unwrap_or(1f64).max(1e-6),
+                // Compound meters as 3+2 are added up
+                ("beats", Some(&"time")) => {
+                    beats = tag
+                        .text
+                        .split('+')
+                        .filter_map(|beats| beats.trim().parse::<u32>().ok())
+                        .sum::<u32>()
+                        .max(1)
+                }
+                ("beat-type", Some(&"time")) => unit = tag.text.parse::<u32>().unwrap_or(4).max(1),
+                ("sound", _) => {
+                    if let Some(tempo) = tag.attribute("tempo").and_then(|t| t.parse::<f64>().ok())
+                    {
+                        if part == 0 && tempo > 0f64 {
+                            tune.tempos.push((position, tempo));
+                        }
+                    }
+                }
+                ("note", _) => note = Note::default(),
+                ("chord", Some(&"note")) => note.chord = true,
+                ("rest", Some(&"note")) => note.rest = true,
+                ("grace", Some(&"note")) => note.grace = true,
+                ("step", Some(&"pitch")) => {
+                    note.step = STEPS
+                        .iter()
+                        .find(|step| step.0 == tag.text)
+                        .map(|step| step.1)
+                }
+                ("alter", Some(&"pitch")) => {
+                    note.alter = tag.text.parse::<f64>().unwrap_or(0f64).round() as i32
+                }
+                ("octave", Some(&"pitch")) => note.octave = tag.text.parse::<i32>().ok(),
+                ("duration", Some(&"note")) => {
+                    note.duration = tag.text.parse::<f64>().unwrap_or(0f64) / divisions
+                }
+                ("duration", _) => duration = tag.text.parse::<f64>().unwrap_or(0f64) / divisions,
+                _ => {}
+            }
+        }
+
+        // Act at the end of the elements
+        if tag.closing || tag.empty {
+            match tag.name {
+                "note" if tag.closing && !note.grace => {
+                    let start = if note.chord { note_start } else { position };
+                    if !note.chord {
+                        note_start = position;
+                        position += note.duration;
+                    }
+                    if let (false, Some(step), Some(octave)) = (note.rest, note.step, note.octave) {
+                        let number = (octave + 1) * 12 + step + note.alter;
+                        if (0..128).contains(&number) {
+                            tune.spans.push(Span {
+                                start,
+                                end: start + note.duration,
+                                note: number as u8,
+                                velocity: VELOCITY,
+                            });
+                        }
+                    }
+                    bar_end = bar_end.max(position);
+                }
+                "backup" => position -= duration,
+                "forward" => {
+                    position += duration;
+                    bar_end = bar_end.max(position);
+                }
+                "measure" => {
+                    // A bar shorter than its meter, as a pickup, only has the beats it needs
+                    let beat_length = 4f64 / unit as f64;
+                    if bar_end <= bar_start {
+                        bar_end = bar_start + beats as f64 * beat_length;
+                    }
+                    if part == 0 {
+                        let length = ((bar_end - bar_start) / beat_length - 1e-6).ceil();
+                        tune.bars.push(Bar {
+                            start: bar_start,
+                            beats: (length as u32).clamp(1, beats),
+                            beat_length,
+                        });
+                    }
+                }
+                "part" => part += 1,
+                _ => {}
+            }
+        }
+    }
+    Ok(tune)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chart::Chart;
+
+    // A score of a single part, in 4/4 unless the first bar says otherwise
+    fn score(measures: &[&str]) -> String {
+        parts(&[measures])
+    }
+
+    fn parts(parts: &[&[&str]]) -> String {
+        let mut xml = String::from(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 3.1 Partwise//EN\">\n\
+             <score-partwise version=\"3.1\">",
+        );
+        for (i, measures) in parts.iter().enumerate() {
+            xml.push_str(&format!("<part id=\"P{}\">", i + 1));
+            for (j, measure) in measures.iter().enumerate() {
+                xml.push_str(&format!(
+                    "<measure number=\"{}\">{}</measure>",
+                    j + 1,
+                    measure
+                ));
+            }
+            xml.push_str("</part>");
+        }
+        xml.push_str("</score-partwise>");
+        xml
+    }
+
+    fn note(step: &str, octave: i32, duration: u32) -> String {
+        format!(
+            "<note><pitch><step>{}</step><octave>{}</octave></pitch>\
+             <duration>{}</duration><type>quarter</type></note>",
+            step, octave, duration
+        )
+    }
+
+    fn spans(tune: &Tune) -> Vec<(f64, f64, u8)> {
+        let mut spans = tune
+            .spans
+            .iter()
+            .map(|span| (span.start, span.end, span.note))
+            .collect::<Vec<_>>();
+        spans.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        spans
+    }
+
+    fn bars(tune: &Tune) -> Vec<(f64, u32, f64)> {
+        tune.bars
+            .iter()
+            .map(|bar| (bar.start, bar.beats, bar.beat_length))
+            .collect()
+    }
+
+    #[test]
+    fn attributes() {
+        let tag = |attributes| Tag {
+            name: "sound",
+            attributes,
+            closing: false,
+            empty: true,
+            text: "",
+        };
+        assert_eq!(tag(r#" tempo="90""#).attribute("tempo"), Some("90"));
+        assert_eq!(
+            tag(" dynamics='80' tempo = '90'").attribute("tempo"),
+            Some("90")
+        );
+        assert_eq!(
+            tag(r#" xtempo="60" tempo="90""#).attribute("tempo"),
+            Some("90")
+        );
+        assert_eq!(tag(r#" xtempo="60""#).attribute("tempo"), None);
+        // Malformed values
+        assert_eq!(tag(" tempo=éà90éà").attribute("tempo"), None);
+        assert_eq!(tag(" tempo=90").attribute("tempo"), None);
+        assert_eq!(tag(r#" tempo="90"#).attribute("tempo"), None);
+        assert_eq!(tag(" tempo=").attribute("tempo"), None);
+    }
+
+    #[test]
+    fn backup_and_forward() {
+        let measure = format!(
+            "<attributes><divisions>2</divisions></attributes>{}\
+             <backup><duration>8</duration></backup>\
+             <forward><duration>4</duration></forward>{}",
+            note("C", 5, 8),
+            note("E", 4, 4)
+        );
+        let tune = read(&score(&[&measure, &note("D", 4, 8)])).unwrap();
+        assert_eq!(
+            spans(&tune),
+            vec![(0.0, 4.0, 72), (2.0, 4.0, 64), (4.0, 8.0, 62)]
+        );
+        assert_eq!(bars(&tune), vec![(0.0, 4, 1.0), (4.0, 4, 1.0)]);
+    }
+
+    #[test]
+    fn chords() {
+        let measure = format!(
+            "{}<note><chord/><pitch><step>E</step><octave>4</octave></pitch>\
+             <duration>1</duration></note>\
+             <note><chord/><pitch><step>F</step><alter>1</alter><octave>4</octave></pitch>\
+             <duration>1</duration></note>\
+             <note><grace/><pitch><step>A</step><octave>4</octave></pitch></note>\
+             <note><rest/><duration>1</duration></note>{}{}",
+            note("C", 4, 1),
+            note("D", 4, 1),
+            note("B", 3, 1)
+        );
+        let tune = read(&score(&[&measure])).unwrap();
+        assert_eq!(
+            spans(&tune),
+            vec![
+                (0.0, 1.0, 60),
+                (0.0, 1.0, 64),
+                (0.0, 1.0, 66),
+                (2.0, 3.0, 62),
+                (3.0, 4.0, 59),
+            ]
+        );
+    }
+
+    #[test]
+    fn pickup_bar() {
+        let pickup = format!(
+            "<attributes><divisions>1</divisions>\
+             <time><beats>3</beats><beat-type>4</beat-type></time></attributes>{}",
+            note("G", 4, 1)
+        );
+        let tune = read(&score(&[&pickup, &note("C", 5, 3), ""])).unwrap();
+        assert_eq!(
+            bars(&tune),
+            vec![(0.0, 1, 1.0), (1.0, 3, 1.0), (4.0, 3, 1.0)]
+        );
+        let chart = Chart::from_tune(&tune);
+        assert_eq!(chart.beats.len(), 7);
+        assert_eq!(chart.beats[0].notes, vec![(67, VELOCITY)]);
+        assert_eq!(chart.beats[1].notes, vec![(72, VELOCITY)]);
+        assert_eq!(chart.beats[6].notes, vec![]);
+    }
+
+    #[test]
+    fn compound_meter() {
+        let measure = format!(
+            "<attributes><divisions>2</divisions>\
+             <time><beats>3+2</beats><beat-type>8</beat-type></time></attributes>{}{}",
+            note("A", 3, 3),
+            note("E", 4, 2)
+        );
+        let tune = read(&score(&[&measure])).unwrap();
+        assert_eq!(bars(&tune), vec![(0.0, 5, 0.5)]);
+        assert_eq!(spans(&tune), vec![(0.0, 1.5, 57), (1.5, 2.5, 64)]);
+    }
+
+    #[test]
+    fn tempo_change() {
+        let first = format!("<sound tempo=\"60\"/>{}", note("C", 4, 4));
+        let second = format!(
+            "<direction><sound tempo='90'/></direction>{}",
+            note("C", 4, 4)
+        );
+        // The tempo of the other parts is ignored
+        let other = format!("<sound tempo=\"200\"/>{}", note("E", 4, 4));
+        let tune = read(&parts(&[&[&first, &second], &[&other]])).unwrap();
+        assert_eq!(tune.tempos, vec![(0.0, 60.0), (4.0, 90.0)]);
+        assert_eq!(spans(&tune).len(), 3);
+
+        let chart = Chart::from_tune(&tune);
+        let starts = chart
+            .beats
+            .iter()
+            .map(|beat| beat.start)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            starts,
+            vec![
+                0.0,
+                1.0,
+                2.0,
+                3.0,
+                4.0,
+                4.0 + 2.0 / 3.0,
+                4.0 + 4.0 / 3.0,
+                6.0
+            ]
+        );
+    }
+
+    #[test]
+    fn not_a_score() {
+        assert!(read("<score-timewise></score-timewise>").is_err());
+        assert!(read("").is_err());
+    }
+}
@@ -41,4 +41,7 @@ impl Note {
     pub fn get_octave_index(self) -> u32 {
         (self as u32) % 12
     }
+    pub fn get_octave(self) -> u32 {
+        (self as u32) / 12
+    }
 }
@@ -0,0 +1,472 @@
+// Streams the scores of every frame over OSC, and changes the scoring options from OSC messages
+
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::display::Notation;
+use crate::fourier::ScoringOptions;
+use crate::messages;
+use crate::notes::Note;
+use crate::scores::{self, Scores};
+
+// The largest packet read by the listener
+const MAX_PACKET: usize = 65536;
+
+// An argument of an OSC message
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+    Bool(bool),
+}
+
+impl OscArg {
+    fn as_f32(&self) -> Option<f32> {
+        match *self {
+            OscArg::Int(i) => Some(i as f32),
+            OscArg::Float(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        match *self {
+            OscArg::Int(i) => Some(i != 0),
+            OscArg::Float(f) => Some(f != 0f32),
+            OscArg::Bool(b) => Some(b),
+            OscArg::Str(_) => None,
+        }
+    }
+}
+
+// Append a string, ended by a null byte and padded to 4 bytes
+fn write_string(bytes: &mut Vec<u8>, string: &str) {
+    bytes.extend_from_slice(string.as_bytes());
+    let padding = 4 - string.len() % 4;
+    bytes.resize(bytes.len() + padding, 0);
+}
+
+// Encode an OSC message
+pub fn encode(address: &str, args: &[OscArg]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_string(&mut bytes, address);
+    let tags = args
+        .iter()
+        .map(|arg| match arg {
+            OscArg::Int(_) => 'i',
+            OscArg::Float(_) => 'f',
+            OscArg::Str(_) => 's',
+            OscArg::Bool(true) => 'T',
+            OscArg::Bool(false) => 'F',
+        })
+        .collect::<String>();
+    write_string(&mut bytes, &format!(",{}", tags));
+    for arg in args {
+        match arg {
+            OscArg::Int(i) => bytes.extend_from_slice(&i.to_be_bytes()),
+            OscArg::Float(f) => bytes.extend_from_slice(&f.to_bits().to_be_bytes()),
+            OscArg::Str(s) => write_string(&mut bytes, s),
+            OscArg::Bool(_) => {}
+        }
+    }
+    bytes
+}
+
+// Reads the padded fields of an OSC packet
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.position..self.position + count)?;
+        self.position += count;
+        Some(bytes)
+    }
+
+    fn word(&mut self) -> Option<[u8; 4]> {
+        let bytes = self.take(4)?;
+        Some([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let rest = self.bytes.get(self.position..)?;
+        let length = rest.iter().position(|&byte| byte == 0)?;
+        let string = String::from_utf8_lossy(&rest[..length]).into_owned();
+        self.take((length / 4 + 1) * 4)?;
+        Some(string)
+    }
+}
+
+// Decode the messages of an OSC packet, opening the bundles
+// Returns None if the packet is malformed
+pub fn decode(packet: &[u8]) -> Option<Vec<(String, Vec<OscArg>)>> {
+    let mut reader = Reader {
+        bytes: packet,
+        position: 0,
+    };
+    let address = reader.string()?;
+    if address == "#bundle" {
+        // The time tag is ignored, every message is applied at once
+        reader.take(8)?;
+        let mut messages = Vec::new();
+        while reader.position < packet.len() {
+            let size = u32::from_be_bytes(reader.word()?) as usize;
+            messages.extend(decode(reader.take(size)?)?);
+        }
+        return Some(messages);
+    }
+
+    let tags = reader.string()?;
+    let mut args = Vec::new();
+    for tag in tags.chars().skip(1) {
+        args.push(match tag {
+            'i' => OscArg::Int(i32::from_be_bytes(reader.word()?)),
+            'f' => OscArg::Float(f32::from_bits(u32::from_be_bytes(reader.word()?))),
+            's' => OscArg::Str(reader.string()?),
+            'T' => OscArg::Bool(true),
+            'F' => OscArg::Bool(false),
+            _ => return None,
+        });
+    }
+    Some(vec![(address, args)])
+}
+
+// A change to the scoring options, requested remotely
+#[derive(Clone, Copy, Debug)]
+pub enum Change {
+    Halflife(f32),
+    Suggest(usize),
+    PerOctave(bool),
+    Register(Note),
+    RegisterWidth(f32),
+    Partials(usize),
+    // The threshold of the sparse scoring, none to keep every partial
+    Threshold(Option<f32>),
+}
+
+impl Change {
+    // The change asked by a message, its address being relative to the prefix
+    fn parse(address: &str, args: &[OscArg]) -> Option<Change> {
+        let arg = args.first()?;
+        let number = arg.as_f32();
+        match address {
+            "/halflife" => Some(Change::Halflife(number?.max(0f32))),
+            "/suggest" => Some(Change::Suggest(number?.clamp(0f32, 48f32) as usize)),
+            "/per-octave" => Some(Change::PerOctave(arg.as_bool()?)),
+            "/register" => {
+                let note = match arg {
+                    OscArg::Str(name) => Notation::parse_note(name)?,
+                    // As a MIDI number
+                    _ => Note::iter().nth((number? as usize).checked_sub(12)?)?,
+                };
+                Some(Change::Register(note))
+            }
+            "/register-width" => number
+                .filter(|&width| width > 0f32)
+                .map(Change::RegisterWidth),
+            "/partials" => Some(Change::Partials(number?.max(1f32) as usize)),
+            "/threshold" => Some(Change::Threshold(Some(number?).filter(|&db| db > 0f32))),
+            _ => None,
+        }
+    }
+
+    pub fn apply(self, options: &mut ScoringOptions) {
+        match self {
+            Change::Halflife(halflife) => options.halflife = halflife,
+            Change::Suggest(count) => options.suggestions.count = count,
+            Change::PerOctave(per_octave) => options.suggestions.fold = !per_octave,
+            Change::Register(center) => options.register.center = center,
+            Change::RegisterWidth(width) => options.register.width = width,
+            Change::Partials(count) => match &mut options.sparse {
+                Some(selection) => selection.count = count,
+                None => messages::warn("OSC: /partials only applies with the -k option"),
+            },
+            Change::Threshold(threshold) => match &mut options.sparse {
+                Some(selection) => selection.threshold = threshold,
+                None => messages::warn("OSC: /threshold only applies with the -k option"),
+            },
+        }
+    }
+}
+
+// Listen for option changes on a UDP address, from a new thread
+// Returns the address bound, which has a port even if none was asked
+fn listen(address: &str, prefix: String) -> Result<(Receiver<Change>, SocketAddr), String> {
+    let socket =
+        UdpSocket::bind(address).map_err(|e| format!("Could not listen on {}: {}", address, e))?;
+    let local = socket.local_addr().map_err(|e| e.to_string())?;
+    let (sender, receiver) = channel();
+    std::thread::spawn(move || receive(socket, prefix, sender));
+    Ok((receiver, local))
+}
+
+fn receive(socket: UdpSocket, prefix: String, sender: Sender<Change>) {
+    let mut packet = vec![0u8; MAX_PACKET];
+    loop {
+        let size = match socket.recv_from(&mut packet) {
+            Ok((size, _)) => size,
+            Err(e) => {
+                messages::warn(&format!("OSC listener stopped: {}", e));
+                return;
+            }
+        };
+        let messages = match decode(&packet[..size]) {
+            Some(messages) => messages,
+            None => {
+                messages::warn("OSC: malformed packet ignored");
+                continue;
+            }
+        };
+        for (address, args) in messages {
+            let change = address
+                .strip_prefix(&prefix)
+                .and_then(|address| Change::parse(address, &args));
+            match change {
+                Some(change) => {
+                    if sender.send(change).is_err() {
+                        return;
+                    }
+                }
+                None => messages::warn(&format!("OSC: unknown message {} {:?}", address, args)),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct OscOptions {
+    // Where the scores are sent
+    pub target: Option<String>,
+    // The address the option changes are received on
+    pub listen: Option<String>,
+    // The start of every address, as in /improve/scores
+    pub prefix: String,
+}
+
+pub struct Osc {
+    socket: Option<(UdpSocket, SocketAddr)>,
+    prefix: String,
+    // The changes received, and the address they are received on
+    changes: Option<(Receiver<Change>, SocketAddr)>,
+}
+
+impl Osc {
+    pub fn new(options: OscOptions) -> Result<Osc, String> {
+        let socket = match &options.target {
+            Some(target) => {
+                let address = target
+                    .to_socket_addrs()
+                    .ok()
+                    .and_then(|mut addresses| addresses.next())
+                    .ok_or_else(|| format!("Invalid OSC address: {}", target))?;
+                let local = if address.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                let socket = UdpSocket::bind(local).map_err(|e| e.to_string())?;
+                Some((socket, address))
+            }
+            None => None,
+        };
+        let changes = match &options.listen {
+            Some(address) => Some(listen(address, options.prefix.clone())?),
+            None => None,
+        };
+        Ok(Osc {
+            socket,
+            prefix: options.prefix,
+            changes,
+        })
+    }
+
+    // The address the changes are received on, if listening
+    pub fn listening(&self) -> Option<SocketAddr> {
+        self.changes.as_ref().map(|&(_, address)| address)
+    }
+
+    // Apply the changes received since the previous frame
+    pub fn update_options(&self, options: &mut ScoringOptions) {
+        if let Some((changes, _)) = &self.changes {
+            for change in changes.try_iter() {
+                change.apply(options);
+            }
+        }
+    }
+
+    // Send the scores, the values, the loudest note, the chord heard and the suggestions
+    pub fn send(&self, scores: &Scores) {
+        let (socket, target) = match &self.socket {
+            Some(socket) => socket,
+            None => return,
+        };
+        let floats = |values: &[f32]| values.iter().map(|&v| OscArg::Float(v)).collect::<Vec<_>>();
+        let notation = Notation::English;
+        let pitch = match scores::detect_pitch(&scores.note_values) {
+            Some(note) => vec![
+                OscArg::Int(note as i32 + 12),
+                OscArg::Str(notation.get_full_name(note)),
+            ],
+            None => vec![OscArg::Int(-1), OscArg::Str(String::new())],
+        };
+        let chord = scores::detect_chord(&scores.note_values).map_or(String::new(), |chord| {
+            format!("{}{}", notation.get_names()[chord.root].trim(), chord.kind)
+        });
+        let suggestions = scores
+            .suggestions
+            .iter()
+            .map(|suggestion| OscArg::Int(suggestion.note as i32 + 12))
+            .collect::<Vec<_>>();
+
+        let messages = [
+            ("/scores", floats(&scores.note_scores)),
+            ("/values", floats(&scores.note_values)),
+            ("/pitch", pitch),
+            ("/chord", vec![OscArg::Str(chord)]),
+            ("/suggestions", suggestions),
+        ];
+        for (address, args) in messages.iter() {
+            let packet = encode(&format!("{}{}", self.prefix, address), args);
+            // Nobody may be listening yet, which is fine
+            socket.send_to(&packet, target).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scores::PartialSelection;
+    use std::time::{Duration, Instant};
+
+    // A bundle of packets, with an immediate time tag
+    fn bundle(packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = b"#bundle\0\0\0\0\0\0\0\0\x01".to_vec();
+        for packet in packets {
+            bytes.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(packet);
+        }
+        bytes
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(
+            encode("/x", &[OscArg::Int(1)]),
+            b"/x\0\0,i\0\0\0\0\0\x01".to_vec()
+        );
+        // Strings of a multiple of 4 bytes still get a null byte
+        assert_eq!(
+            encode("/abc", &[OscArg::Bool(true)]),
+            b"/abc\0\0\0\0,T\0\0".to_vec()
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let args = vec![
+            OscArg::Int(-3),
+            OscArg::Float(0.5),
+            OscArg::Str("abc".to_owned()),
+            OscArg::Str("abcd".to_owned()),
+            OscArg::Str(String::new()),
+            OscArg::Bool(true),
+            OscArg::Bool(false),
+        ];
+        let packet = encode("/improve/scores", &args);
+        assert_eq!(packet.len() % 4, 0);
+        assert_eq!(
+            decode(&packet),
+            Some(vec![("/improve/scores".to_owned(), args)])
+        );
+        assert_eq!(
+            decode(&encode("/empty", &[])),
+            Some(vec![("/empty".to_owned(), vec![])])
+        );
+    }
+
+    #[test]
+    fn bundles() {
+        let first = encode("/a", &[OscArg::Int(1)]);
+        let second = encode("/b", &[OscArg::Float(2.0)]);
+        let third = encode("/c", &[]);
+        let packet = bundle(&[first, bundle(&[second, third])]);
+        assert_eq!(
+            decode(&packet),
+            Some(vec![
+                ("/a".to_owned(), vec![OscArg::Int(1)]),
+                ("/b".to_owned(), vec![OscArg::Float(2.0)]),
+                ("/c".to_owned(), vec![]),
+            ])
+        );
+        assert_eq!(decode(&bundle(&[])), Some(vec![]));
+    }
+
+    #[test]
+    fn malformed_packets() {
+        let packet = encode("/a", &[OscArg::Int(1), OscArg::Str("abc".to_owned())]);
+        // Cut anywhere, the packet is rejected rather than read past its end
+        for length in 0..packet.len() {
+            assert_eq!(decode(&packet[..length]), None, "cut at {}", length);
+        }
+        // An unknown type tag
+        assert_eq!(decode(b"/a\0\0,x\0\0\0\0\0\x01"), None);
+        // A string without its null byte
+        assert_eq!(decode(b"/abc"), None);
+        // A bundle element longer than the bundle
+        let mut packet = bundle(&[encode("/a", &[])]);
+        packet.truncate(packet.len() - 4);
+        assert_eq!(decode(&packet), None);
+    }
+
+    #[test]
+    fn sparse_changes() {
+        let mut options = ScoringOptions::default();
+        Change::Partials(5).apply(&mut options);
+        assert!(options.sparse.is_none());
+        options.sparse = Some(PartialSelection {
+            count: 10,
+            threshold: None,
+        });
+        Change::parse("/partials", &[OscArg::Int(5)])
+            .unwrap()
+            .apply(&mut options);
+        Change::parse("/threshold", &[OscArg::Float(30.0)])
+            .unwrap()
+            .apply(&mut options);
+        let selection = options.sparse.unwrap();
+        assert_eq!(selection.count, 5);
+        assert_eq!(selection.threshold, Some(30.0));
+    }
+
+    #[test]
+    fn listener() {
+        let osc = Osc::new(OscOptions {
+            target: None,
+            listen: Some("127.0.0.1:0".to_owned()),
+            prefix: "/improve".to_owned(),
+        })
+        .unwrap();
+        let address = osc.listening().unwrap();
+        assert_ne!(address.port(), 0);
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // Messages outside the prefix are ignored
+        let packet = bundle(&[
+            encode("/other/halflife", &[OscArg::Float(9.0)]),
+            encode("/improve/halflife", &[OscArg::Float(2.5)]),
+        ]);
+        socket.send_to(&packet, address).unwrap();
+
+        let mut options = ScoringOptions::default();
+        let start = Instant::now();
+        while options.halflife != 2.5 && start.elapsed() < Duration::from_secs(2) {
+            std::thread::sleep(Duration::from_millis(5));
+            osc.update_options(&mut options);
+        }
+        assert_eq!(options.halflife, 2.5);
+    }
+}
@@ -0,0 +1,352 @@
+/*
+
+ring_buffer.rs is a preallocated single-producer/single-consumer queue of audio samples.
+
+The producer lives in the audio callback, and must never allocate or lock.
+The consumer can read samples without removing them, which allows overlapping frames.
+The samples dropped when the buffer is full are remembered where they were dropped, so that the
+consumer knows the position of every sample in the stream.
+
+*/
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+struct Shared {
+    data: Box<[UnsafeCell<f32>]>,
+    // Total number of samples written, only modified by the producer
+    write: AtomicUsize,
+    // Total number of samples consumed, only modified by the consumer
+    read: AtomicUsize,
+    // Number of samples dropped because the buffer was full
+    overruns: AtomicUsize,
+    // The gaps left by the samples dropped, as a queue from the producer to the consumer
+    gaps: Box<[Gap]>,
+    // Total number of gaps sent, only modified by the producer
+    gap_write: AtomicUsize,
+    // Total number of gaps received, only modified by the consumer
+    gap_read: AtomicUsize,
+    // Set when the producer is dropped
+    closed: AtomicBool,
+    // The creation time of the buffer
+    epoch: Instant,
+    // The time of the last write, in nanoseconds since epoch
+    last_push: AtomicU64,
+}
+
+// Some samples dropped, before the sample written at index
+#[derive(Default)]
+struct Gap {
+    index: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+// The gaps waiting for the consumer, a stalled consumer leaving few gaps as the buffer stays full
+const GAPS: usize = 64;
+
+// Each slot is only ever accessed by one side at a time, as delimited by read and write
+unsafe impl Sync for Shared {}
+
+impl Shared {
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+// The writing half, to be moved into the audio callback
+pub struct Producer {
+    shared: Arc<Shared>,
+    // The last gap, sent once samples are written after it, as more can be dropped at its index
+    pending: Option<(usize, usize)>,
+}
+
+// The reading half, owned by the audio buffer
+pub struct Consumer {
+    shared: Arc<Shared>,
+    // Number of reads that had to wait for data
+    underruns: usize,
+    // The position of the oldest sample in the stream, counting the samples dropped before it
+    position: u64,
+}
+
+// Build a ring buffer able to hold capacity samples
+pub fn ring_buffer(capacity: usize) -> (Producer, Consumer) {
+    assert!(capacity > 0);
+    let shared = Arc::new(Shared {
+        data: (0..capacity).map(|_| UnsafeCell::new(0f32)).collect(),
+        write: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+        overruns: AtomicUsize::new(0),
+        gaps: (0..GAPS).map(|_| Gap::default()).collect(),
+        gap_write: AtomicUsize::new(0),
+        gap_read: AtomicUsize::new(0),
+        closed: AtomicBool::new(false),
+        epoch: Instant::now(),
+        last_push: AtomicU64::new(0),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+            pending: None,
+        },
+        Consumer {
+            shared,
+            underruns: 0,
+            position: 0,
+        },
+    )
+}
+
+impl Producer {
+    // Copy as many samples as possible into the buffer, and count the others as overruns
+    pub fn push(&mut self, input: &[f32]) {
+        let shared = &*self.shared;
+        let cap = shared.capacity();
+        let write = shared.write.load(Ordering::Relaxed);
+        let read = shared.read.load(Ordering::Acquire);
+        let free = cap - write.wrapping_sub(read);
+        let n = free.min(input.len());
+        for (i, &sample) in input[..n].iter().enumerate() {
+            // Safety: slots between write and read + cap are not visible to the consumer
+            unsafe {
+                *shared.data[write.wrapping_add(i) % cap].get() = sample;
+            }
+        }
+        // The gap is sent before the samples after it can be read
+        if n > 0 {
+            send_gap(shared, &mut self.pending);
+        }
+        let now = shared.epoch.elapsed().as_nanos() as u64;
+        shared.last_push.store(now, Ordering::Relaxed);
+        shared.write.store(write.wrapping_add(n), Ordering::Release);
+        if n < input.len() {
+            let dropped = input.len() - n;
+            shared.overruns.fetch_add(dropped, Ordering::Relaxed);
+            let index = write.wrapping_add(n);
+            let pending = self.pending;
+            let before = match pending {
+                Some((pending, before)) if pending == index => before,
+                // The queue is full, the older gap is moved to this one
+                Some((_, before)) if !send_gap(shared, &mut self.pending) => before,
+                _ => 0,
+            };
+            self.pending = Some((index, before + dropped));
+        }
+    }
+}
+
+// Send the pending gap to the consumer, returning false if its queue is full
+fn send_gap(shared: &Shared, pending: &mut Option<(usize, usize)>) -> bool {
+    let (index, dropped) = match *pending {
+        Some(gap) => gap,
+        None => return true,
+    };
+    let gap_write = shared.gap_write.load(Ordering::Relaxed);
+    if gap_write.wrapping_sub(shared.gap_read.load(Ordering::Acquire)) == GAPS {
+        return false;
+    }
+    let gap = &shared.gaps[gap_write % GAPS];
+    gap.index.store(index, Ordering::Relaxed);
+    gap.dropped.store(dropped, Ordering::Relaxed);
+    shared
+        .gap_write
+        .store(gap_write.wrapping_add(1), Ordering::Release);
+    *pending = None;
+    true
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+    }
+}
+
+impl Consumer {
+    // Number of samples ready to be read
+    pub fn len(&self) -> usize {
+        let shared = &*self.shared;
+        let write = shared.write.load(Ordering::Acquire);
+        let read = shared.read.load(Ordering::Relaxed);
+        write.wrapping_sub(read)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity()
+    }
+
+    // Wait until at least n samples are ready
+    // Returns false if the producer died before enough samples were written
+    pub fn wait_for(&mut self, n: usize) -> bool {
+        assert!(n <= self.capacity());
+        if self.len() >= n {
+            return true;
+        }
+        self.underruns += 1;
+        while self.len() < n {
+            if self.shared.closed.load(Ordering::Acquire) && self.len() < n {
+                return false;
+            }
+            // Audio arrives in packets of a few milliseconds, no need to spin
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        self.receive_gaps();
+        true
+    }
+
+    // Copy the n oldest samples without consuming them
+    pub fn peek(&self, n: usize) -> Vec<f32> {
+        assert!(n <= self.len());
+        let shared = &*self.shared;
+        let cap = shared.capacity();
+        let read = shared.read.load(Ordering::Relaxed);
+        (0..n)
+            // Safety: slots between read and write are not modified by the producer
+            .map(|i| unsafe { *shared.data[read.wrapping_add(i) % cap].get() })
+            .collect()
+    }
+
+    // Consume the n oldest samples, making room for the producer
+    pub fn skip(&mut self, n: usize) {
+        assert!(n <= self.len());
+        let read = self.shared.read.load(Ordering::Relaxed);
+        self.shared
+            .read
+            .store(read.wrapping_add(n), Ordering::Release);
+        self.position += n as u64;
+        self.receive_gaps();
+    }
+
+    // Add the samples dropped before the oldest sample to its position
+    fn receive_gaps(&mut self) {
+        let shared = &*self.shared;
+        let read = shared.read.load(Ordering::Relaxed);
+        let gap_write = shared.gap_write.load(Ordering::Acquire);
+        let mut gap_read = shared.gap_read.load(Ordering::Relaxed);
+        while gap_read != gap_write {
+            let gap = &shared.gaps[gap_read % GAPS];
+            // Only the gaps up to the oldest sample, the newer ones are still ahead
+            if (read.wrapping_sub(gap.index.load(Ordering::Relaxed)) as isize) < 0 {
+                break;
+            }
+            self.position += gap.dropped.load(Ordering::Relaxed) as u64;
+            gap_read = gap_read.wrapping_add(1);
+        }
+        shared.gap_read.store(gap_read, Ordering::Release);
+    }
+
+    // The position in the stream of the oldest sample, the samples dropped included
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    // The time at which the newest sample was received
+    pub fn received(&self) -> Instant {
+        let nanos = self.shared.last_push.load(Ordering::Relaxed);
+        self.shared.epoch + Duration::from_nanos(nanos)
+    }
+
+    // Number of samples the producer had to drop
+    pub fn overruns(&self) -> usize {
+        self.shared.overruns.load(Ordering::Relaxed)
+    }
+
+    // Number of reads that had to wait for the producer
+    pub fn underruns(&self) -> usize {
+        self.underruns
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_around() {
+        let (mut producer, mut consumer) = ring_buffer(4);
+        producer.push(&[1., 2., 3.]);
+        consumer.skip(2);
+        producer.push(&[4., 5., 6.]);
+        assert_eq!(consumer.len(), 4);
+        assert_eq!(consumer.peek(4), vec![3., 4., 5., 6.]);
+        assert_eq!(consumer.overruns(), 0);
+    }
+
+    #[test]
+    fn overruns() {
+        let (mut producer, mut consumer) = ring_buffer(4);
+        producer.push(&[0., 1., 2., 3., 4., 5.]);
+        assert_eq!(consumer.overruns(), 2);
+        assert_eq!(consumer.peek(4), vec![0., 1., 2., 3.]);
+        // The samples dropped were the newest, so the oldest keep their position
+        consumer.skip(2);
+        assert_eq!(consumer.position(), 2);
+        producer.push(&[6., 7.]);
+        producer.push(&[8.]);
+        assert_eq!(consumer.overruns(), 3);
+        consumer.skip(2);
+        assert_eq!(consumer.position(), 6);
+        assert_eq!(consumer.peek(2), vec![6., 7.]);
+        consumer.skip(2);
+        assert_eq!(consumer.position(), 8);
+    }
+
+    #[test]
+    fn peek_and_skip() {
+        let (mut producer, mut consumer) = ring_buffer(8);
+        producer.push(&[1., 2., 3., 4.]);
+        assert_eq!(consumer.peek(2), vec![1., 2.]);
+        assert_eq!(consumer.peek(3), vec![1., 2., 3.]);
+        assert_eq!(consumer.len(), 4);
+        consumer.skip(3);
+        assert_eq!(consumer.len(), 1);
+        assert_eq!(consumer.position(), 3);
+        assert_eq!(consumer.peek(1), vec![4.]);
+    }
+
+    #[test]
+    fn wait_for_after_the_producer_drops() {
+        let (mut producer, mut consumer) = ring_buffer(8);
+        producer.push(&[1., 2.]);
+        drop(producer);
+        assert!(consumer.wait_for(2));
+        assert!(!consumer.wait_for(3));
+        assert_eq!(consumer.peek(2), vec![1., 2.]);
+    }
+
+    #[test]
+    fn two_threads() {
+        const COUNT: usize = 200_000;
+        let (mut producer, mut consumer) = ring_buffer(4096);
+        // Every sample is its position in the stream
+        // The consumer sleeps when the buffer is empty, so that some of them are dropped too
+        let writer = std::thread::spawn(move || {
+            let mut sample = 0;
+            for size in (1..=97).cycle() {
+                let end = (sample + size).min(COUNT);
+                let packet = (sample..end).map(|i| i as f32).collect::<Vec<_>>();
+                producer.push(&packet);
+                sample = end;
+                if sample == COUNT {
+                    break;
+                }
+                if size % 8 == 0 {
+                    std::thread::sleep(Duration::from_micros(50));
+                }
+            }
+        });
+        let mut read = 0;
+        let mut last = None;
+        while consumer.wait_for(1) {
+            let sample = consumer.peek(1)[0];
+            assert_eq!(sample, consumer.position() as f32);
+            assert!(last.is_none_or(|last| last < sample));
+            last = Some(sample);
+            consumer.skip(1);
+            read += 1;
+        }
+        writer.join().unwrap();
+        assert_eq!(read + consumer.overruns(), COUNT);
+    }
+}
@@ -1,11 +1,14 @@
-use crate::dissonance;
+use crate::dissonance::{self, Harmonics};
 use crate::frequency::Frequency;
 
+use crate::notes::Note::*;
 use crate::notes::{Note, NOTE_COUNT};
+use crate::stats::FrameStats;
+use crate::tools::parallel_map;
 
 use itertools::Itertools;
 
-use std::time::Instant;
+use std::cmp::Reverse;
 
 pub struct Scores {
     // The dissonance score of each note
@@ -13,54 +16,337 @@ pub struct Scores {
     // The intensity of each note
     pub note_values: [f32; NOTE_COUNT],
     pub fourier: Vec<Frequency>,
+    // Performance measurements, filled by the analysis thread
+    pub stats: Option<FrameStats>,
+    // The most consonant notes, best first, filled by the analysis thread
+    pub suggestions: Vec<Suggestion>,
+    // The score of every pitch class from C to B, filled by the analysis thread
+    pub pitch_classes: [f32; 12],
+    // A description of the frame, as its place in a chart
+    pub label: Option<String>,
+}
+
+// The octaves that matter most when folding scores into pitch classes
+#[derive(Clone, Copy, Debug)]
+pub struct Register {
+    // The note at the center of the register
+    pub center: Note,
+    // The distance in octaves at which a note weighs half as much
+    pub width: f32,
+}
+
+impl Default for Register {
+    // The middle of a guitar
+    fn default() -> Register {
+        Register {
+            center: E4,
+            width: 1f32,
+        }
+    }
+}
+
+impl Register {
+    // Average the scores of every octave of each pitch class, weighted by their distance to the center
+    pub fn fold(self, note_scores: &[f32; NOTE_COUNT]) -> [f32; 12] {
+        let mut sums = [0f32; 12];
+        let mut weights = [0f32; 12];
+        for (i, &score) in note_scores.iter().enumerate() {
+            // Flat octaves have no valid scores
+            if score.is_nan() {
+                continue;
+            }
+            let octaves = (i as f32 - self.center as usize as f32) / 12f32 / self.width;
+            let weight = 0.5f32.powf(octaves * octaves);
+            sums[i % 12] += score * weight;
+            weights[i % 12] += weight;
+        }
+        let mut pitch_classes = [0f32; 12];
+        for (i, class) in pitch_classes.iter_mut().enumerate() {
+            *class = sums[i] / weights[i];
+        }
+        pitch_classes
+    }
+}
+
+// A note worth playing right now
+#[derive(Clone, Copy, Debug)]
+pub struct Suggestion {
+    pub note: Note,
+    // The normalised dissonance, lower is better
+    pub score: f32,
+    // If the score is the average of every octave, and the note only names a pitch class
+    pub folded: bool,
+}
+
+// Selects the notes listed as suggestions
+#[derive(Clone, Copy, Debug)]
+pub struct SuggestionOptions {
+    // The number of notes listed, none if zero
+    pub count: usize,
+    // If notes are grouped by pitch class, instead of listed with their octave
+    pub fold: bool,
+    // The range of notes considered
+    pub lowest: Note,
+    pub highest: Note,
+}
+
+impl Default for SuggestionOptions {
+    // The range of a guitar with 24 frets
+    fn default() -> SuggestionOptions {
+        SuggestionOptions {
+            count: 0,
+            fold: true,
+            lowest: E2,
+            highest: E6,
+        }
+    }
+}
+
+impl SuggestionOptions {
+    // List the notes with the lowest scores
+    // Folded suggestions rank the pitch classes, as folded by the register
+    pub fn suggest(
+        self,
+        note_scores: &[f32; NOTE_COUNT],
+        pitch_classes: &[f32; 12],
+    ) -> Vec<Suggestion> {
+        let range = self.lowest as usize..=self.highest as usize;
+        let suggestions = if self.fold {
+            // Every pitch class is named after its lowest note in the range
+            range
+                .clone()
+                .take(12)
+                .map(|i| Suggestion {
+                    note: Note::iter().nth(i).unwrap(),
+                    score: pitch_classes[i % 12],
+                    folded: true,
+                })
+                .collect_vec()
+        } else {
+            Note::iter()
+                .skip(self.lowest as usize)
+                .take(range.count())
+                .map(|note| Suggestion {
+                    note,
+                    score: note_scores[note as usize],
+                    folded: false,
+                })
+                .collect_vec()
+        };
+
+        // Flat octaves have no valid scores
+        suggestions
+            .into_iter()
+            .filter(|suggestion| !suggestion.score.is_nan())
+            .sorted_by(|a, b| a.score.partial_cmp(&b.score).unwrap())
+            .take(self.count)
+            .collect()
+    }
+}
+
+// The chords recognised, by their name after the root and their intervals from it
+const CHORDS: [(&str, &[usize]); 9] = [
+    ("", &[0, 4, 7]),
+    ("m", &[0, 3, 7]),
+    ("7", &[0, 4, 7, 10]),
+    ("maj7", &[0, 4, 7, 11]),
+    ("m7", &[0, 3, 7, 10]),
+    ("dim", &[0, 3, 6]),
+    ("aug", &[0, 4, 8]),
+    ("sus2", &[0, 2, 7]),
+    ("sus4", &[0, 5, 7]),
+];
+
+// A pitch class counts in a chord if it is at least this loud, relative to the loudest one
+const CHORD_THRESHOLD: f32 = 0.3;
+
+// A chord heard, as a pitch class from C and the name of its kind, as "m7"
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Chord {
+    pub root: usize,
+    pub kind: &'static str,
+}
+
+// The loudest note heard, if any
+pub fn detect_pitch(note_values: &[f32; NOTE_COUNT]) -> Option<Note> {
+    let (index, &value) = note_values
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal))?;
+    if value > 0f32 {
+        Note::iter().nth(index)
+    } else {
+        None
+    }
+}
+
+// The chord whose notes are all heard, covering the most of the loud pitch classes
+pub fn detect_chord(note_values: &[f32; NOTE_COUNT]) -> Option<Chord> {
+    let mut classes = [0f32; 12];
+    for (i, &value) in note_values.iter().enumerate() {
+        classes[i % 12] += value;
+    }
+    let loudest = classes.iter().cloned().fold(0f32, f32::max);
+    if loudest <= 0f32 {
+        return None;
+    }
+    let heard = |class: usize| classes[class % 12] >= loudest * CHORD_THRESHOLD;
+
+    (0..12)
+        .flat_map(|root| CHORDS.iter().map(move |&(kind, intervals)| (root, kind, intervals)))
+        .filter(|&(root, _, intervals)| intervals.iter().all(|&i| heard(root + i)))
+        .map(|(root, kind, intervals)| {
+            let weight = intervals.iter().map(|&i| classes[(root + i) % 12]).sum::<f32>();
+            (Chord { root, kind }, weight)
+        })
+        .fold(None, |best: Option<(Chord, f32)>, (chord, weight)| match best {
+            Some((_, best_weight)) if best_weight >= weight => best,
+            _ => Some((chord, weight)),
+        })
+        .map(|(chord, _)| chord)
+}
+
+// Selects the partials used by the sparse scoring
+#[derive(Clone, Copy, Debug)]
+pub struct PartialSelection {
+    // The maximum number of partials kept every frame
+    pub count: usize,
+    // If set, partials quieter than the strongest one by this many decibels are ignored
+    pub threshold: Option<f32>,
+}
+
+impl PartialSelection {
+    // Keep the strongest peaks of the spectrum
+    // Only local maxima are considered, so that one wide peak doesn't fill the selection
+    pub fn select(self, heard: &[Frequency]) -> Vec<Frequency> {
+        let peaks = heard
+            .iter()
+            .enumerate()
+            .filter(|&(i, f)| {
+                let prev = if i > 0 { heard[i - 1].intensity } else { 0f32 };
+                let next = heard.get(i + 1).map_or(0f32, |f| f.intensity);
+                f.intensity > 0f32 && f.intensity >= prev && f.intensity > next
+            })
+            .map(|(_, f)| *f)
+            .sorted_by_key(|f| Reverse(*f))
+            .take(self.count)
+            .collect_vec();
+
+        match (self.threshold, peaks.first()) {
+            (Some(db), Some(loudest)) => {
+                let min = loudest.intensity * 10f32.powf(-db / 10f32);
+                peaks.into_iter().filter(|f| f.intensity >= min).collect()
+            }
+            _ => peaks,
+        }
+    }
+}
+
+// How the dissonance of every note is obtained
+enum Method {
+    // A lookup table of the dissonance of every fft bin to every note
+    Table(Vec<Vec<f32>>),
+    // The dissonance of the strongest partials, calculated every frame
+    Sparse(PartialSelection, Box<Harmonics>),
+    // The dissonance of every partial given, as when they are known rather than heard
+    Exact(Box<Harmonics>),
 }
 
 pub struct ScoreCalculator {
-    dissonance_values: Vec<Vec<f32>>,
+    method: Method,
     prev_score: [f32; NOTE_COUNT],
     prev_values: [f32; NOTE_COUNT],
-    time: Instant,
+    // The number of threads used for the calculations
+    threads: usize,
 }
 
 impl ScoreCalculator {
-    pub fn new(heard: &[Frequency]) -> ScoreCalculator {
-        let dissonance_values = dissonance::dissonance_scores(heard);
+    pub fn new(heard: &[Frequency], threads: usize) -> ScoreCalculator {
+        let dissonance_values = dissonance::dissonance_scores(heard, threads);
+        ScoreCalculator::from_table(dissonance_values, threads)
+    }
+
+    // Build a calculator from an existing lookup table
+    pub fn from_table(dissonance_values: Vec<Vec<f32>>, threads: usize) -> ScoreCalculator {
+        ScoreCalculator::with_method(Method::Table(dissonance_values), threads)
+    }
+
+    // Build a calculator that only scores a few partials, without a lookup table
+    pub fn sparse(selection: PartialSelection, threads: usize) -> ScoreCalculator {
+        let harmonics = Box::new(dissonance::get_notes_harmonics());
+        ScoreCalculator::with_method(Method::Sparse(selection, harmonics), threads)
+    }
 
+    // Build a calculator that scores every partial given, without a lookup table
+    pub fn exact(threads: usize) -> ScoreCalculator {
+        let harmonics = Box::new(dissonance::get_notes_harmonics());
+        ScoreCalculator::with_method(Method::Exact(harmonics), threads)
+    }
+
+    fn with_method(method: Method, threads: usize) -> ScoreCalculator {
         ScoreCalculator {
-            dissonance_values,
+            method,
             prev_score: [0f32; NOTE_COUNT],
             prev_values: [0f32; NOTE_COUNT],
-            time: Instant::now(),
+            threads,
+        }
+    }
+
+    // Change the partials scored by a sparse calculator, as asked remotely
+    pub fn set_selection(&mut self, selection: PartialSelection) {
+        if let Method::Sparse(current, _) = &mut self.method {
+            *current = selection;
         }
     }
 
-    fn calculate_note(&self, heard: &[(usize, Frequency)], note: Note) -> f32 {
+    fn calculate_note(table: &[Vec<f32>], heard: &[(usize, Frequency)], note: Note) -> f32 {
         let mut score = 0f32;
         for &(u, f) in heard.iter() {
-            score += f.intensity * self.dissonance_values[note as usize][u];
+            score += f.intensity * table[note as usize][u];
         }
         score
     }
 
+    // Get the raw dissonance of every note
+    fn calculate_raw_scores(&self, heard: &[Frequency]) -> Vec<f32> {
+        match &self.method {
+            Method::Table(table) => {
+                // Extract indices for lookup table
+                // Sort the array
+                // Possibly skip lower parts for noise reduction
+                let heard_sorted = heard
+                    .iter()
+                    .cloned()
+                    .enumerate()
+                    .sorted_by_key(|(_, f)| *f)
+                    // .skip(heard.len() / 2)
+                    // .skip(heard.len() / 4)
+                    // .skip(heard.len() / 8)
+                    .collect_vec();
+
+                // Get each score, splitting notes between threads
+                let note_list = Note::iter().collect_vec();
+                parallel_map(NOTE_COUNT, self.threads, |i| {
+                    ScoreCalculator::calculate_note(table, heard_sorted.as_slice(), note_list[i])
+                })
+            }
+            Method::Sparse(selection, harmonics) => {
+                let partials = selection.select(heard);
+                dissonance::sparse_dissonance_scores(&partials, harmonics, self.threads)
+            }
+            Method::Exact(harmonics) => {
+                dissonance::sparse_dissonance_scores(heard, harmonics, self.threads)
+            }
+        }
+    }
+
     fn calculate_scores(&mut self, heard: &Vec<Frequency>, factor:f32) -> [f32; NOTE_COUNT] {
         let mut notes = [0f32; NOTE_COUNT];
 
-        // Extract indices for lookup table
-        // Sort the array
-        // Possibly skip lower parts for noise reduction
-        let heard_sorted = heard
-            .iter()
-            .cloned()
-            .enumerate()
-            .sorted_by_key(|(_, f)| *f)
-            // .skip(heard.len() / 2)
-            // .skip(heard.len() / 4)
-            // .skip(heard.len() / 8)
-            .collect_vec();
+        let raw_scores = self.calculate_raw_scores(heard);
 
-        // Get each score, and average with previous value
-        for note in Note::iter() {
-            let score = self.calculate_note(heard_sorted.as_slice(), note);
+        // Average with previous value
+        for (note, score) in Note::iter().zip(raw_scores) {
             notes[note as usize] =
                 score * (1f32 - factor) + self.prev_score[note as usize] * factor;
         }
@@ -108,7 +394,7 @@ impl ScoreCalculator {
 
     // Assign each frequency to a note, and sum their value
     // Allows the dsplay of every perceived note
-    fn calculate_values(&mut self, heard: &Vec<Frequency>, factor:f32) -> [f32; NOTE_COUNT] {
+    fn calculate_values(&mut self, heard: &[Frequency], factor:f32) -> [f32; NOTE_COUNT] {
         let mut note_values = [0f32; NOTE_COUNT];
 
         for f in heard {
@@ -121,28 +407,50 @@ impl ScoreCalculator {
         note_values
     }
 
-    // Analyses a list of perceived frequencies, and returns displayable data
-    pub fn calculate(&mut self, heard: Vec<Frequency>, halflife:f32) -> Scores {
-
+    // How much the previous scores have faded after some time
+    fn fade(seconds: f32, halflife: f32) -> f32 {
         // Time-aware walking average
         // An approximation of second-order beatings
         // Basically approximates how long does the brain keep "hearing" the note
         // Doesn't take into account the different type of dissonance
 
-        // Get time since last call
-        let time_since_last_call = self.time.elapsed();
-        let seconds = time_since_last_call.as_secs() as f32
-            + time_since_last_call.subsec_nanos() as f32 * 1e-9;
-        self.time = Instant::now();
+        // Time is measured in audio samples, so the result doesn't depend on processing speed
         assert!(seconds >= 0f32);
         // Get how much previous score should have faded
-        let factor = 0.5f32.powf(seconds / halflife);
+        let factor = if halflife > 0f32 {
+            0.5f32.powf(seconds / halflife)
+        } else {
+            0f32
+        };
         assert!(factor >= 0f32 && factor <= 1f32);
+        factor
+    }
+
+    // Analyses a list of perceived frequencies, and returns displayable data
+    // seconds is the audio time elapsed since the previous call
+    pub fn calculate(&mut self, heard: Vec<Frequency>, seconds:f32, halflife:f32) -> Scores {
+        let factor = ScoreCalculator::fade(seconds, halflife);
+        let note_values = self.calculate_values(&heard, factor / 5.0);
+        self.scores_with_values(heard, note_values, factor)
+    }
+
+    // Like calculate, but the notes played are kept apart from the ones scored
+    // They are only shown, as when the player has their own input channel
+    pub fn calculate_apart(&mut self, heard: Vec<Frequency>, played: &[Frequency], seconds:f32, halflife:f32) -> Scores {
+        let factor = ScoreCalculator::fade(seconds, halflife);
+        let note_values = self.calculate_values(played, factor / 5.0);
+        self.scores_with_values(heard, note_values, factor)
+    }
 
+    fn scores_with_values(&mut self, heard: Vec<Frequency>, note_values: [f32; NOTE_COUNT], factor: f32) -> Scores {
         Scores {
             note_scores: self.calculate_scores(&heard, factor),
-            note_values: self.calculate_values(&heard, factor / 5.0),
+            note_values,
             fourier: heard,
+            stats: None,
+            suggestions: Vec::new(),
+            pitch_classes: [0f32; 12],
+            label: None,
         }
     }
 }
@@ -0,0 +1,411 @@
+// Records the captured audio and the scores of a session, and replays the audio through the analysis
+// The audio is a WAV file, and the scores a csv file beside it
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use itertools::Itertools;
+use sdl2::audio::AudioCallback;
+
+use crate::display::Notation;
+use crate::messages;
+use crate::notes::Note;
+use crate::ring_buffer::{Consumer, Producer};
+use crate::scores::Scores;
+
+// WAV format tags
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+// The length of the header written, the samples follow it
+const HEADER_LENGTH: u32 = 44;
+// The position of the length of the file and of the samples in the header
+const RIFF_LENGTH_OFFSET: u64 = 4;
+const DATA_LENGTH_OFFSET: u64 = 40;
+// The longest samples a WAV file can hold, its length being 32 bits
+const MAX_DATA_LENGTH: u32 = u32::MAX - (HEADER_LENGTH - 8);
+
+// How often the length in the header is updated
+const PATCH_PERIOD: Duration = Duration::from_secs(1);
+
+// A mono WAV file of 32 bit floats
+// The header is updated every second and when the writer is dropped, so that a run stopped
+// any way only loses the last second
+struct WavWriter {
+    file: File,
+    // The length of the samples written, in bytes
+    length: u32,
+    // When the header was last updated
+    patched: Instant,
+}
+
+impl WavWriter {
+    fn create(path: &str, rate: u32) -> Result<WavWriter, String> {
+        let mut file =
+            File::create(path).map_err(|e| format!("Could not create {}: {}", path, e))?;
+        let mut header = Vec::new();
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(HEADER_LENGTH - 8).to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&FORMAT_FLOAT.to_le_bytes());
+        // A single channel, of 4 bytes per sample
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&rate.to_le_bytes());
+        header.extend_from_slice(&(rate * 4).to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&32u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        file.write_all(&header)
+            .and_then(|_| file.flush())
+            .map_err(|e| format!("Could not write {}: {}", path, e))?;
+        Ok(WavWriter {
+            file,
+            length: 0,
+            patched: Instant::now(),
+        })
+    }
+
+    // Append samples, failing once the file is full
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let length = samples.len() as u64 * 4;
+        if u64::from(self.length) + length > u64::from(MAX_DATA_LENGTH) {
+            return Err(io::Error::other("WAV files are limited to 4 GiB"));
+        }
+        let bytes = samples
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes().to_vec())
+            .collect::<Vec<_>>();
+        self.file.write_all(&bytes)?;
+        self.length += length as u32;
+        if self.patched.elapsed() >= PATCH_PERIOD {
+            self.patch()?;
+        }
+        Ok(())
+    }
+
+    // Write the length of the samples in the header
+    fn patch(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(RIFF_LENGTH_OFFSET))?;
+        self.file
+            .write_all(&(HEADER_LENGTH - 8 + self.length).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(DATA_LENGTH_OFFSET))?;
+        self.file.write_all(&self.length.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.patched = Instant::now();
+        self.file.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        self.patch().ok();
+    }
+}
+
+// The seconds of audio buffered for the recording, while the file is written
+pub const RECORDING_SECONDS: usize = 4;
+
+// Write the samples copied by the audio callbacks to a WAV file, from a new thread
+// The callbacks only copy the samples, and can start before the rate is known
+// The recording ends with the callback
+pub fn record_audio(path: &str, rate: u32, mut samples: Consumer) -> Result<(), String> {
+    let mut writer = WavWriter::create(path, rate)?;
+    let path = path.to_owned();
+    std::thread::spawn(move || {
+        let mut overran = false;
+        while samples.wait_for(1) {
+            let available = samples.peek(samples.len());
+            samples.skip(available.len());
+            if let Err(e) = writer.write(&available) {
+                messages::warn(&format!("Recording to {} stopped: {}", path, e));
+                return;
+            }
+            if !overran && samples.overruns() > 0 {
+                overran = true;
+                messages::warn(&format!(
+                    "Recording to {} has gaps, the file is not written fast enough",
+                    path
+                ));
+            }
+        }
+    });
+    Ok(())
+}
+
+// The scores of every frame, as a csv file
+// Every row has the time of the frame in the recording, the suggestions and the score of every note
+pub struct ScoreRecording {
+    file: BufWriter<File>,
+}
+
+impl ScoreRecording {
+    pub fn create(path: &str) -> Result<ScoreRecording, String> {
+        let file = File::create(path).map_err(|e| format!("Could not create {}: {}", path, e))?;
+        let mut file = BufWriter::new(file);
+        let notation = Notation::English;
+        writeln!(
+            file,
+            "time,suggestions,{}",
+            Note::iter()
+                .map(|note| notation.get_full_name(note))
+                .join(",")
+        )
+        .and_then(|_| file.flush())
+        .map_err(|e| e.to_string())?;
+        Ok(ScoreRecording { file })
+    }
+
+    pub fn write(&mut self, scores: &Scores, time: f64) {
+        let notation = Notation::English;
+        let suggestions = scores
+            .suggestions
+            .iter()
+            .map(|suggestion| {
+                if suggestion.folded {
+                    notation.get_name(suggestion.note).trim().to_owned()
+                } else {
+                    notation.get_full_name(suggestion.note)
+                }
+            })
+            .join(" ");
+        // Notes without a score are left empty
+        let note_scores = scores
+            .note_scores
+            .iter()
+            .map(|score| {
+                if score.is_finite() {
+                    format!("{:.4}", score)
+                } else {
+                    String::new()
+                }
+            })
+            .join(",");
+        // Flushed every row, as the program can exit without dropping the recording
+        writeln!(self.file, "{:.3},{},{}", time, suggestions, note_scores)
+            .and_then(|_| self.file.flush())
+            .ok();
+    }
+}
+
+// The csv file of the scores recorded with a WAV file, as in session.csv for session.wav
+pub fn scores_path(path: &str) -> String {
+    Path::new(path)
+        .with_extension("csv")
+        .to_string_lossy()
+        .into_owned()
+}
+
+// The samples of a WAV file and their rate, the channels being mixed down to one
+pub fn read_wav(path: &str) -> Result<(i32, Vec<f32>), String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    let error = |message: &str| Err(format!("{}: {}", path, message));
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return error("Not a WAV file");
+    }
+
+    // Find the format and the samples among the chunks
+    let mut format = None;
+    let mut data = None;
+    let mut position = 12;
+    while position + 8 <= bytes.len() {
+        let id = &bytes[position..position + 4];
+        let length = u32::from_le_bytes([
+            bytes[position + 4],
+            bytes[position + 5],
+            bytes[position + 6],
+            bytes[position + 7],
+        ]) as usize;
+        let start = position + 8;
+        // A recording stopped early may be shorter than its header says
+        let end = (start + length).min(bytes.len());
+        match id {
+            b"fmt " => format = Some(&bytes[start..end]),
+            b"data" => data = Some(&bytes[start..end]),
+            _ => {}
+        }
+        // Chunks are padded to an even length
+        position = start + length + length % 2;
+    }
+    let (format, data) = match (format, data) {
+        (Some(format), Some(data)) if format.len() >= 16 => (format, data),
+        _ => return error("The WAV file has no samples"),
+    };
+
+    let word = |i: usize| u16::from_le_bytes([format[i], format[i + 1]]);
+    let mut tag = word(0);
+    let channels = word(2) as usize;
+    let rate = u32::from_le_bytes([format[4], format[5], format[6], format[7]]);
+    let bits = word(14);
+    // The actual format of an extensible file starts its sub format
+    if tag == FORMAT_EXTENSIBLE && format.len() >= 26 {
+        tag = word(24);
+    }
+    if channels == 0 || rate == 0 {
+        return error("Invalid WAV header");
+    }
+
+    let sample: fn(&[u8]) -> f32 = match (tag, bits) {
+        (FORMAT_PCM, 8) => |b| (b[0] as f32 - 128f32) / 128f32,
+        (FORMAT_PCM, 16) => |b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768f32,
+        (FORMAT_PCM, 24) => |b| i32::from_le_bytes([0, b[0], b[1], b[2]]) as f32 / 2147483648f32,
+        (FORMAT_PCM, 32) => |b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648f32,
+        (FORMAT_FLOAT, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+        (FORMAT_FLOAT, 64) => {
+            |b| f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32
+        }
+        _ => return error(&format!("Unsupported WAV format {} of {} bits", tag, bits)),
+    };
+    let size = bits as usize / 8;
+    let samples = data
+        .chunks_exact(size * channels)
+        .map(|frame| frame.chunks_exact(size).map(sample).sum::<f32>() / channels as f32)
+        .collect::<Vec<_>>();
+    Ok((rate as i32, samples))
+}
+
+// Plays a recording, passing the samples heard to the analysis as if they were captured
+// The analysis ends with the recording
+pub struct Player {
+    pub samples: Vec<f32>,
+    pub position: usize,
+    pub producer: Option<Producer>,
+    pub recording: Option<Producer>,
+}
+
+impl AudioCallback for Player {
+    type Channel = f32;
+
+    fn callback(&mut self, output: &mut [f32]) {
+        let end = (self.position + output.len()).min(self.samples.len());
+        let played = &self.samples[self.position..end];
+        output[..played.len()].copy_from_slice(played);
+        output[played.len()..]
+            .iter_mut()
+            .for_each(|sample| *sample = 0f32);
+        self.position = end;
+        if let Some(producer) = &mut self.producer {
+            producer.push(played);
+        }
+        if let Some(recording) = &mut self.recording {
+            recording.push(played);
+        }
+        if end == self.samples.len() {
+            self.producer = None;
+            self.recording = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A file in the temporary directory, unique to the test run
+    fn temporary(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("improve-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // A WAV file of 48000 Hz, the format being given by an extensible header if asked
+    fn wav_file(tag: u16, bits: u16, channels: u16, extensible: bool, data: &[u8]) -> Vec<u8> {
+        let rate = 48000u32;
+        let align = channels * bits / 8;
+        let mut format = Vec::new();
+        let header_tag = if extensible { FORMAT_EXTENSIBLE } else { tag };
+        format.extend_from_slice(&header_tag.to_le_bytes());
+        format.extend_from_slice(&channels.to_le_bytes());
+        format.extend_from_slice(&rate.to_le_bytes());
+        format.extend_from_slice(&(rate * u32::from(align)).to_le_bytes());
+        format.extend_from_slice(&align.to_le_bytes());
+        format.extend_from_slice(&bits.to_le_bytes());
+        if extensible {
+            // The valid bits, the channel mask, and the sub format GUID starting with the tag
+            format.extend_from_slice(&22u16.to_le_bytes());
+            format.extend_from_slice(&bits.to_le_bytes());
+            format.extend_from_slice(&0u32.to_le_bytes());
+            format.extend_from_slice(&tag.to_le_bytes());
+            format.extend_from_slice(&[
+                0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+            ]);
+        }
+        let mut file = b"RIFF".to_vec();
+        file.extend_from_slice(&(20 + format.len() as u32 + data.len() as u32).to_le_bytes());
+        file.extend_from_slice(b"WAVEfmt ");
+        file.extend_from_slice(&(format.len() as u32).to_le_bytes());
+        file.extend_from_slice(&format);
+        file.extend_from_slice(b"data");
+        file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        file.extend_from_slice(data);
+        file
+    }
+
+    fn read_bytes(name: &str, bytes: &[u8]) -> Result<(i32, Vec<f32>), String> {
+        let path = temporary(name);
+        std::fs::write(&path, bytes).unwrap();
+        let wav = read_wav(&path);
+        std::fs::remove_file(&path).ok();
+        wav
+    }
+
+    #[test]
+    fn float_round_trip() {
+        let path = temporary("float.wav");
+        let samples = [0.5, -0.25, 1.0, 0.125, -1.0];
+        {
+            let mut writer = WavWriter::create(&path, 44100).unwrap();
+            writer.write(&samples[..3]).unwrap();
+            writer.write(&samples[3..]).unwrap();
+        }
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), HEADER_LENGTH as usize + 20);
+        assert_eq!(read_wav(&path), Ok((44100, samples.to_vec())));
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn length_limit() {
+        let path = temporary("limit.wav");
+        let mut writer = WavWriter::create(&path, 44100).unwrap();
+        writer.length = MAX_DATA_LENGTH - 4;
+        assert!(writer.write(&[0.5, 0.5]).is_err());
+        assert!(writer.write(&[0.5]).is_ok());
+        writer.length = 0;
+        drop(writer);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn pcm() {
+        // Two 16 bit channels mixed down
+        let data = [0x00, 0x40, 0x00, 0x40, 0x00, 0x40, 0x00, 0xC0];
+        let wav = read_bytes("pcm16.wav", &wav_file(FORMAT_PCM, 16, 2, false, &data));
+        assert_eq!(wav, Ok((48000, vec![0.5, 0.0])));
+        let data = [0x00, 0x00, 0x40, 0x00, 0x00, 0xE0];
+        let wav = read_bytes("pcm24.wav", &wav_file(FORMAT_PCM, 24, 1, false, &data));
+        assert_eq!(wav, Ok((48000, vec![0.5, -0.25])));
+    }
+
+    #[test]
+    fn extensible() {
+        let data = [0x00, 0x00, 0x40, 0x00, 0x00, 0xE0];
+        let wav = read_bytes("ext24.wav", &wav_file(FORMAT_PCM, 24, 1, true, &data));
+        assert_eq!(wav, Ok((48000, vec![0.5, -0.25])));
+        let data = [0.75f32.to_le_bytes(), (-0.5f32).to_le_bytes()].concat();
+        let wav = read_bytes("extfloat.wav", &wav_file(FORMAT_FLOAT, 32, 1, true, &data));
+        assert_eq!(wav, Ok((48000, vec![0.75, -0.5])));
+    }
+
+    #[test]
+    fn not_a_wav_file() {
+        assert!(read_bytes("text.wav", b"RIFF but not a WAV file").is_err());
+        let wav = read_bytes("pcm12.wav", &wav_file(FORMAT_PCM, 12, 1, false, &[0; 6]));
+        assert!(wav.is_err());
+    }
+}
@@ -0,0 +1,92 @@
+// Turns fourier data into columns of a log-frequency spectrogram
+
+use crate::frequency::Frequency;
+use crate::notes::Note;
+
+// The frequency range displayed by default
+pub const MIN_NOTE: Note = Note::C1;
+pub const MAX_NOTE: Note = Note::C9;
+
+// How fast the loudness reference follows a quieter input, in decibels per frame
+const REFERENCE_DECAY: f32 = 0.05;
+
+pub struct Spectrogram {
+    // The number of rows in a column
+    height: u32,
+    min_freq: f32,
+    max_freq: f32,
+    // The loudness range displayed below the reference
+    db_range: f32,
+    // The loudness mapped to the top of the colour map, follows the loudest bins
+    reference: Option<f32>,
+}
+
+impl Spectrogram {
+    pub fn new(height: u32, db_range: f32) -> Spectrogram {
+        Spectrogram {
+            height,
+            min_freq: MIN_NOTE.freq(),
+            max_freq: MAX_NOTE.freq(),
+            db_range,
+            reference: None,
+        }
+    }
+
+    // The frequency at the bottom edge of a row, row 0 being the top
+    fn row_freq(&self, row: f32) -> f32 {
+        let ratio = (self.height as f32 - row) / self.height as f32;
+        self.min_freq * (self.max_freq / self.min_freq).powf(ratio)
+    }
+
+    // The row displaying a frequency, if in range
+    pub fn row_of(&self, freq: f32) -> Option<i32> {
+        if freq < self.min_freq || freq > self.max_freq {
+            return None;
+        }
+        let ratio = (freq / self.min_freq).ln() / (self.max_freq / self.min_freq).ln();
+        let row = ((1f32 - ratio) * self.height as f32) as i32;
+        Some(row.min(self.height as i32 - 1))
+    }
+
+    // Get the loudness of every row, from 0.0 to 1.0, top row first
+    pub fn column(&mut self, fourier: &[Frequency]) -> Vec<f32> {
+        if fourier.is_empty() {
+            return vec![0f32; self.height as usize];
+        }
+        // Bins are evenly spaced, the first one being one step above 0hz
+        let step = fourier[0].value;
+        let bin = |freq: f32| ((freq / step).ceil() as usize).max(1).min(fourier.len());
+
+        let db = (0..self.height)
+            .map(|row| {
+                let high = self.row_freq(row as f32);
+                let low = self.row_freq(row as f32 + 1f32);
+                // Take the loudest bin of the row, or the closest one if rows are thinner than bins
+                let (first, last) = (bin(low), bin(high));
+                let intensity = if first < last {
+                    fourier[first - 1..last - 1]
+                        .iter()
+                        .map(|f| f.intensity)
+                        .fold(0f32, f32::max)
+                } else {
+                    let closest = ((low + high) / 2f32 / step).round() as usize;
+                    fourier[closest.max(1).min(fourier.len()) - 1].intensity
+                };
+                10f32 * intensity.max(1e-20).log10()
+            })
+            .collect::<Vec<f32>>();
+
+        // Follow the loudest bin instantly, but decay slowly
+        let loudest = db.iter().cloned().fold(f32::MIN, f32::max);
+        let reference = match self.reference {
+            Some(reference) => loudest.max(reference - REFERENCE_DECAY),
+            None => loudest,
+        };
+        self.reference = Some(reference);
+
+        let floor = reference - self.db_range;
+        db.into_iter()
+            .map(|db| ((db - floor) / self.db_range).clamp(0f32, 1f32))
+            .collect()
+    }
+}
@@ -0,0 +1,122 @@
+// Per-frame performance measurements, averaged for display and optionally logged
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant};
+
+use itertools::Itertools;
+
+// The timings of the analysis of a single frame
+#[derive(Clone, Copy, Debug)]
+pub struct FrameStats {
+    // The position of the frame in the stream, in samples
+    pub timestamp: u64,
+    // Estimated time at which the last sample of the frame was recorded
+    pub captured: Instant,
+    // Time spent waiting for the audio buffer
+    pub wait: Duration,
+    // Time spent in the fourier transform
+    pub fourier: Duration,
+    // Time spent calculating the scores
+    pub scoring: Duration,
+    // Total number of samples dropped by the audio callback
+    pub overruns: usize,
+    // Total number of frames that had to wait for audio
+    pub underruns: usize,
+}
+
+// Weight of the newest frame in the displayed averages
+const SMOOTHING: f64 = 0.1;
+
+// The names of the averaged timings, in display order
+const NAMES: [&str; 5] = ["wait", "fft", "score", "render", "latency"];
+
+// Receives the stats of every displayed frame
+pub struct Monitor {
+    // If the stats should be drawn by the display
+    pub visible: bool,
+    // Walking average of every timing, in seconds
+    averages: Option<[f64; 5]>,
+    // The counters of the last frame
+    overruns: usize,
+    underruns: usize,
+    // The optional csv output
+    log: Option<BufWriter<File>>,
+}
+
+impl Monitor {
+    pub fn new(visible: bool, log_path: Option<&str>) -> Result<Monitor, String> {
+        let log = match log_path {
+            Some(path) => {
+                let file = File::create(path)
+                    .map_err(|e| format!("Could not create {}: {}", path, e))?;
+                let mut log = BufWriter::new(file);
+                writeln!(
+                    log,
+                    "timestamp,{},overruns,underruns",
+                    NAMES.iter().map(|name| format!("{}_ms", name)).join(",")
+                )
+                .map_err(|e| e.to_string())?;
+                Some(log)
+            }
+            None => None,
+        };
+        Ok(Monitor {
+            visible,
+            averages: None,
+            overruns: 0,
+            underruns: 0,
+            log,
+        })
+    }
+
+    // Record a frame once it has been displayed
+    pub fn record(&mut self, stats: &FrameStats, render: Duration) {
+        let latency = stats.captured.elapsed();
+        let values = [stats.wait, stats.fourier, stats.scoring, render, latency];
+        let seconds = |i: usize| values[i].as_secs_f64();
+
+        // Start the averages at the first values to avoid a slow ramp up
+        let averages = self.averages.get_or_insert([
+            seconds(0),
+            seconds(1),
+            seconds(2),
+            seconds(3),
+            seconds(4),
+        ]);
+        for (i, average) in averages.iter_mut().enumerate() {
+            *average = *average * (1.0 - SMOOTHING) + seconds(i) * SMOOTHING;
+        }
+        self.overruns = stats.overruns;
+        self.underruns = stats.underruns;
+
+        if let Some(log) = &mut self.log {
+            let timings = (0..values.len())
+                .map(|i| format!("{:.3}", seconds(i) * 1e3))
+                .join(",");
+            // Flushed every row, as the program can exit without dropping the monitor
+            writeln!(
+                log,
+                "{},{},{},{}",
+                stats.timestamp, timings, stats.overruns, stats.underruns
+            )
+            .and_then(|_| log.flush())
+            .ok();
+        }
+    }
+
+    // The averaged timings and counters, one per line
+    pub fn lines(&self) -> Vec<String> {
+        let averages = match self.averages {
+            Some(averages) => averages,
+            None => return vec![],
+        };
+        NAMES
+            .iter()
+            .zip(averages.iter())
+            .map(|(name, average)| format!("{:<9}{:7.1} ms", name, average * 1e3))
+            .chain(std::iter::once(format!("{:<9}{:7}", "overruns", self.overruns)))
+            .chain(std::iter::once(format!("{:<9}{:7}", "underruns", self.underruns)))
+            .collect()
+    }
+}
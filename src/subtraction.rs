@@ -0,0 +1,167 @@
+// Removes the note the player is holding from the partials heard, before they are scored
+// The player is assumed to be the loudest, their note having the strongest partial heard
+
+use std::ops::Range;
+
+use crate::dissonance::{self, Harmonics};
+use crate::frequency::Frequency;
+use crate::notes::Note;
+
+// The partials of a note compared to the ones heard to find it
+const MATCHED_PARTIALS: usize = 8;
+// The partials of the note found that are removed
+const REMOVED_PARTIALS: usize = 16;
+// How far from a partial the heard frequencies still belong to it, as a ratio
+// This is half a semitone, fft bins being wider in the low notes
+const TOLERANCE: f32 = 0.0293;
+
+pub struct Subtraction {
+    harmonics: Box<Harmonics>,
+    // The range of notes the player can play
+    lowest: Note,
+    highest: Note,
+}
+
+impl Subtraction {
+    pub fn new(lowest: Note, highest: Note) -> Subtraction {
+        Subtraction {
+            harmonics: Box::new(dissonance::get_notes_harmonics()),
+            lowest,
+            highest,
+        }
+    }
+
+    // The partials of the simulated instrument playing a note, from its fundamental
+    fn partials(&self, note: Note) -> &[Frequency] {
+        dissonance::note_partials(&self.harmonics, note)
+    }
+
+    // The positions of the heard frequencies belonging to a partial
+    // The heard frequencies are the bins of an fft, sorted by frequency
+    fn around(heard: &[Frequency], frequency: f32) -> Range<usize> {
+        let spacing = match heard {
+            [first, second, ..] => second.value - first.value,
+            _ => 0f32,
+        };
+        let tolerance = (frequency * TOLERANCE).max(spacing);
+        let start = heard.partition_point(|f| f.value < frequency - tolerance);
+        let end = heard.partition_point(|f| f.value <= frequency + tolerance);
+        start..end
+    }
+
+    // How much of a note is heard, its partials being weighed as in the instrument
+    fn salience(&self, heard: &[Frequency], note: Note) -> f32 {
+        self.partials(note)
+            .iter()
+            .take(MATCHED_PARTIALS)
+            .map(|partial| {
+                let peak = heard[Subtraction::around(heard, partial.value)]
+                    .iter()
+                    .map(|f| f.intensity)
+                    .fold(0f32, f32::max);
+                peak.sqrt() * partial.intensity
+            })
+            .sum()
+    }
+
+    // The note being played, the one with the loudest heard frequency among its partials
+    // that matches the most of the others
+    fn detect(&self, heard: &[Frequency]) -> Option<Note> {
+        let (loudest, _) = heard
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.intensity > 0f32)
+            .max_by(|(_, a), (_, b)| a.intensity.partial_cmp(&b.intensity).unwrap())?;
+        let range = self.lowest as usize..=self.highest as usize;
+        Note::iter()
+            .filter(|&note| range.contains(&(note as usize)))
+            .filter(|&note| {
+                self.partials(note)
+                    .iter()
+                    .take(MATCHED_PARTIALS)
+                    .any(|partial| Subtraction::around(heard, partial.value).contains(&loudest))
+            })
+            .map(|note| (note, self.salience(heard, note)))
+            .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+            .map(|(note, _)| note)
+    }
+
+    // Silence the partials of the note being played, if any
+    // The heard frequencies stay in place, as the dissonance table is indexed by their position
+    pub fn subtract(&self, heard: &mut [Frequency]) {
+        let note = match self.detect(heard) {
+            Some(note) => note,
+            None => return,
+        };
+        for partial in self.partials(note).iter().take(REMOVED_PARTIALS) {
+            let range = Subtraction::around(heard, partial.value);
+            for f in heard[range].iter_mut() {
+                f.intensity = 0f32;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The bins of an fft every 2 Hz, silent but for the partials given
+    fn spectrum(partials: &[(f32, f32)]) -> Vec<Frequency> {
+        let mut heard = (0..4000)
+            .map(|i| Frequency {
+                value: i as f32 * 2f32,
+                intensity: 0f32,
+            })
+            .collect::<Vec<_>>();
+        for &(value, intensity) in partials {
+            heard[(value / 2f32).round() as usize].intensity = intensity;
+        }
+        heard
+    }
+
+    // An A3 with its first 8 harmonics, and a partial of another instrument between them
+    fn a3_and_other() -> (Vec<Frequency>, f32) {
+        let a3 = Note::A3.freq();
+        let other = Note::DSharp4.freq();
+        let mut partials = (1..=8)
+            .map(|k| (a3 * k as f32, 1f32 / k as f32))
+            .collect::<Vec<_>>();
+        partials.push((other, 0.3));
+        (spectrum(&partials), other)
+    }
+
+    #[test]
+    fn subtract_the_note_played() {
+        let subtraction = Subtraction::new(Note::E2, Note::E6);
+        let (mut heard, other) = a3_and_other();
+        assert_eq!(subtraction.detect(&heard), Some(Note::A3));
+        subtraction.subtract(&mut heard);
+        for k in 1..=8 {
+            let harmonic = Note::A3.freq() * k as f32;
+            assert!(heard
+                .iter()
+                .filter(|f| (f.value / harmonic).log2().abs() * 12f32 <= 0.5)
+                .all(|f| f.intensity == 0f32));
+        }
+        let other = &heard[(other / 2f32).round() as usize];
+        assert_eq!(other.intensity, 0.3);
+    }
+
+    #[test]
+    fn notes_out_of_range_are_left() {
+        let subtraction = Subtraction::new(Note::C4, Note::E6);
+        let (mut heard, _) = a3_and_other();
+        let before = heard.clone();
+        assert_eq!(subtraction.detect(&heard), None);
+        subtraction.subtract(&mut heard);
+        assert_eq!(heard, before);
+    }
+
+    #[test]
+    fn silence() {
+        let subtraction = Subtraction::new(Note::E2, Note::E6);
+        assert_eq!(subtraction.detect(&spectrum(&[])), None);
+        assert_eq!(subtraction.detect(&[]), None);
+    }
+}
@@ -99,4 +99,68 @@ impl Normalizable for [f64] {
             *i = (*i - min) / (max - min);
         });
     }
-}
\ No newline at end of file
+}
+
+// Map every index of 0..count, splitting the work between threads
+// The results are in order, and identical to a serial map
+// The calling thread maps the first block itself, sparing the spawn of one thread per frame
+pub fn parallel_map<T, F>(count: usize, threads: usize, f: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(usize) -> T + Sync,
+{
+    if threads <= 1 || count <= 1 {
+        return (0..count).map(f).collect();
+    }
+    // Give each thread a contiguous block of indices
+    let chunk = count.div_ceil(threads);
+    let f = &f;
+    std::thread::scope(|scope| {
+        let handles = (chunk..count)
+            .step_by(chunk)
+            .map(|first| {
+                let last = (first + chunk).min(count);
+                scope.spawn(move || (first..last).map(f).collect::<Vec<T>>())
+            })
+            .collect_vec();
+        let mut results = (0..chunk).map(f).collect_vec();
+        for handle in handles {
+            results.extend(handle.join().unwrap());
+        }
+        results
+    })
+}
+
+// The default number of worker threads
+pub fn default_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parallel_map_matches_serial_map() {
+        let f = |i: usize| (i as f32).sqrt() * 3f32 + i as f32;
+        for &count in [0, 1, 7, 120].iter() {
+            let serial = (0..count).map(f).collect_vec();
+            for &threads in [1, 3, 8].iter() {
+                assert_eq!(parallel_map(count, threads, f), serial);
+            }
+        }
+    }
+
+    #[test]
+    fn panics_reach_the_caller() {
+        let result = std::panic::catch_unwind(|| {
+            parallel_map(40, 4, |i| {
+                assert_ne!(i, 25);
+                i
+            })
+        });
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,587 @@
+// Serves a web page drawing the fretboard, and streams the scores to it over WebSockets
+// Any number of browsers can watch at once, as tablets clipped to the mic stands
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::display::DisplayOptions;
+use crate::messages;
+use crate::scores::Scores;
+
+// The page, with the script drawing the fretboard and the keyboard
+const PAGE: &str = include_str!("../assets/web.html");
+
+// Appended to the key of a WebSocket handshake, as given by RFC 6455
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// The frames waiting for a slow client, newer frames are dropped beyond this
+const CLIENT_QUEUE: usize = 4;
+
+// A client whose queue stays full for this many frames in a row is disconnected
+const MAX_SKIPPED: usize = 100;
+
+// The time allowed for a browser to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The longest request read, its line and headers together, browsers sending much less
+const MAX_REQUEST: u64 = 16 * 1024;
+
+// The time allowed for a frame to be sent, a browser not reading being dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often a client waiting for frames checks if the browser closed the connection
+const CLOSE_POLL: Duration = Duration::from_millis(500);
+
+// The largest frame read from a browser, which only sends control frames
+const MAX_CLIENT_FRAME: u64 = 1 << 16;
+
+// The WebSocket opcodes
+const TEXT: u8 = 0x1;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+// The number of colours sent for the browser to interpolate the colour map
+const COLOR_STOPS: usize = 17;
+
+// A number, or null as JSON has no NaN
+fn json_number(value: f32) -> String {
+    if value.is_finite() {
+        format!("{}", value)
+    } else {
+        "null".to_owned()
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_array<T>(values: impl Iterator<Item = T>, format: impl Fn(T) -> String) -> String {
+    format!("[{}]", values.map(format).collect::<Vec<_>>().join(","))
+}
+
+fn json_color((r, g, b): (u8, u8, u8)) -> String {
+    format!("\"#{:02x}{:02x}{:02x}\"", r, g, b)
+}
+
+// The fretboard and the colours, sent once to every new client
+fn config_json(options: DisplayOptions) -> String {
+    let fretboard = options.fretboard;
+    let (lowest, highest) = fretboard.range();
+    let colors =
+        (0..COLOR_STOPS).map(|i| options.colormap.get(i as f32 / (COLOR_STOPS - 1) as f32));
+    format!(
+        "{{\"type\":\"config\",\"strings\":{},\"frets\":{},\"inlays\":{},\"names\":{},\
+         \"lowest\":{},\"highest\":{},\"colors\":{},\"background\":{},\"panel\":{},\
+         \"foreground\":{},\"leftHanded\":{},\"flipStrings\":{}}}",
+        json_array(fretboard.strings().into_iter(), |note| (note as usize)
+            .to_string()),
+        json_array(fretboard.columns(), |fret| fret.to_string()),
+        json_array(fretboard.columns(), |fret| fretboard
+            .inlays(fret)
+            .to_string()),
+        json_array(options.notation.get_names().iter(), |name| json_string(
+            name.trim()
+        )),
+        lowest as usize,
+        highest as usize,
+        json_array(colors, json_color),
+        json_color(options.theme.background()),
+        json_color(options.theme.panel()),
+        json_color(options.theme.foreground()),
+        options.left_handed,
+        options.flip_strings,
+    )
+}
+
+// The scores of a frame
+fn scores_json(scores: &Scores, options: DisplayOptions) -> String {
+    let suggestions = scores.suggestions.iter().map(|suggestion| {
+        let name = if suggestion.folded {
+            options.notation.get_name(suggestion.note).trim().to_owned()
+        } else {
+            options.notation.get_full_name(suggestion.note)
+        };
+        format!(
+            "{{\"note\":{},\"name\":{},\"score\":{}}}",
+            suggestion.note as usize,
+            json_string(&name),
+            json_number(suggestion.score)
+        )
+    });
+    format!(
+        "{{\"type\":\"scores\",\"scores\":{},\"values\":{},\"pitchClasses\":{},\
+         \"suggestions\":{},\"label\":{}}}",
+        json_array(scores.note_scores.iter(), |&v| json_number(v)),
+        json_array(scores.note_values.iter(), |&v| json_number(v)),
+        json_array(scores.pitch_classes.iter(), |&v| json_number(v)),
+        json_array(suggestions, |s| s),
+        scores
+            .label
+            .as_ref()
+            .map_or("null".to_owned(), |label| json_string(label)),
+    )
+}
+
+// SHA-1 of a message, only used for the WebSocket handshake
+fn sha1(message: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [
+        0x6745_2301,
+        0xEFCD_AB89,
+        0x98BA_DCFE,
+        0x1032_5476,
+        0xC3D2_E1F0,
+    ];
+    let mut data = message.to_vec();
+    data.push(0x80);
+    while data.len() % 64 != 56 {
+        data.push(0);
+    }
+    data.extend_from_slice(&(message.len() as u64 * 8).to_be_bytes());
+
+    for chunk in data.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e].iter()) {
+            *h = h.wrapping_add(*v);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, h) in digest.chunks_mut(4).zip(h.iter()) {
+        bytes.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// A frame sent by the server, which is never masked
+fn websocket_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let length = payload.len();
+    let mut frame = vec![0x80 | opcode];
+    if length < 126 {
+        frame.push(length as u8);
+    } else if length <= 0xFFFF {
+        frame.push(126);
+        frame.extend_from_slice(&(length as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(length as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// Read a frame sent by a browser, which is always masked, as its opcode and payload
+fn read_frame(reader: &mut impl Read) -> Result<(u8, Vec<u8>), String> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).map_err(|e| e.to_string())?;
+    let opcode = header[0] & 0x0F;
+    let length = match header[1] & 0x7F {
+        126 => {
+            let mut length = [0u8; 2];
+            reader.read_exact(&mut length).map_err(|e| e.to_string())?;
+            u16::from_be_bytes(length) as u64
+        }
+        127 => {
+            let mut length = [0u8; 8];
+            reader.read_exact(&mut length).map_err(|e| e.to_string())?;
+            u64::from_be_bytes(length)
+        }
+        length => length as u64,
+    };
+    if length > MAX_CLIENT_FRAME {
+        return Err(format!("Frame of {} bytes from the browser", length));
+    }
+    let mut mask = [0u8; 4];
+    if header[1] & 0x80 != 0 {
+        reader.read_exact(&mut mask).map_err(|e| e.to_string())?;
+    }
+    let mut payload = vec![0u8; length as usize];
+    reader.read_exact(&mut payload).map_err(|e| e.to_string())?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((opcode, payload))
+}
+
+// Answer the control frames of a browser until it closes the connection
+// The frames are written with the scores, one at a time
+fn read_frames(mut reader: impl Read, writer: &Mutex<TcpStream>) -> Result<(), String> {
+    loop {
+        let (opcode, payload) = read_frame(&mut reader)?;
+        let reply = match opcode {
+            PING => websocket_frame(PONG, &payload),
+            // Echo the status code, which ends the connection
+            CLOSE => websocket_frame(CLOSE, &payload[..payload.len().min(2)]),
+            // The browser has nothing else to say
+            _ => continue,
+        };
+        let mut writer = writer.lock().map_err(|e| e.to_string())?;
+        writer.write_all(&reply).map_err(|e| e.to_string())?;
+        if opcode == CLOSE {
+            return Ok(());
+        }
+    }
+}
+
+// The browsers watching, and the last frame for the ones only asking once
+#[derive(Default)]
+struct Clients {
+    // The queue of every client, and the number of frames it skipped in a row
+    queues: Vec<(SyncSender<Arc<String>>, usize)>,
+    latest: Option<Arc<String>>,
+}
+
+pub struct WebServer {
+    options: DisplayOptions,
+    clients: Arc<Mutex<Clients>>,
+}
+
+impl WebServer {
+    // Listen on an address, as 0.0.0.0:8080, from a new thread
+    pub fn start(address: &str, options: DisplayOptions) -> Result<WebServer, String> {
+        let listener = TcpListener::bind(address)
+            .map_err(|e| format!("Could not listen on {}: {}", address, e))?;
+        println!("Serving the fretboard on http://{}", address);
+        let clients = Arc::new(Mutex::new(Clients::default()));
+        let config = Arc::new(config_json(options));
+        {
+            let clients = clients.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let clients = clients.clone();
+                    let config = config.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = serve(stream, &clients, &config) {
+                            messages::warn(&format!("Web client dropped: {}", e));
+                        }
+                    });
+                }
+            });
+        }
+        Ok(WebServer { options, clients })
+    }
+
+    // Stream the scores to every client, skipping the ones that can't keep up
+    // The clients that stopped reading are dropped, which closes their connection
+    pub fn send(&self, scores: &Scores) {
+        let frame = Arc::new(scores_json(scores, self.options));
+        if let Ok(mut clients) = self.clients.lock() {
+            clients
+                .queues
+                .retain_mut(|(queue, skipped)| match queue.try_send(frame.clone()) {
+                    Ok(()) => {
+                        *skipped = 0;
+                        true
+                    }
+                    Err(TrySendError::Full(_)) => {
+                        *skipped += 1;
+                        *skipped < MAX_SKIPPED
+                    }
+                    Err(TrySendError::Disconnected(_)) => false,
+                });
+            clients.latest = Some(frame);
+        }
+    }
+}
+
+// Read the path and the WebSocket key of a request, None if the request is too long
+// The rest of the stream is left to the WebSocket frames
+fn read_request(reader: &mut impl BufRead) -> Result<Option<(String, Option<String>)>, String> {
+    let mut head = reader.take(MAX_REQUEST);
+    let mut request = String::new();
+    let mut key = None;
+    loop {
+        let mut line = String::new();
+        let read = head.read_line(&mut line).map_err(|e| e.to_string())?;
+        // The end of the request must come before the limit
+        if head.limit() == 0 {
+            return Ok(None);
+        }
+        if read == 0 {
+            break;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if request.is_empty() {
+            request = line.to_owned();
+        } else if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.trim().to_owned());
+            }
+        }
+    }
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+    Ok(Some((path.to_owned(), key)))
+}
+
+// Answer a request, streaming the scores if it asks for a WebSocket
+fn serve(stream: TcpStream, clients: &Mutex<Clients>, config: &str) -> Result<(), String> {
+    stream
+        .set_read_timeout(Some(REQUEST_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+        .map_err(|e| e.to_string())?;
+    let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+    let mut stream = stream;
+    let (path, key) = match read_request(&mut reader)? {
+        Some(request) => request,
+        None => {
+            respond(
+                &mut stream,
+                "431 Request Header Fields Too Large",
+                "text/plain",
+                "Request too long",
+            )?;
+            return Err("Request too long".to_owned());
+        }
+    };
+
+    match (path.as_str(), key) {
+        ("/ws", Some(key)) => {
+            let accept = base64(&sha1(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()));
+            write!(
+                stream,
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept
+            )
+            .map_err(|e| e.to_string())?;
+            stream
+                .write_all(&websocket_frame(TEXT, config.as_bytes()))
+                .map_err(|e| e.to_string())?;
+
+            let (queue, frames): (_, Receiver<Arc<String>>) = sync_channel(CLIENT_QUEUE);
+            clients
+                .lock()
+                .map_err(|e| e.to_string())?
+                .queues
+                .push((queue, 0));
+
+            // The browser only sends control frames, answered from their own thread
+            // The scores may take a while, so reads have no timeout
+            stream.set_read_timeout(None).map_err(|e| e.to_string())?;
+            let writer = Arc::new(Mutex::new(stream));
+            let closed = Arc::new(AtomicBool::new(false));
+            {
+                let writer = writer.clone();
+                let closed = closed.clone();
+                std::thread::spawn(move || {
+                    read_frames(reader, &writer).ok();
+                    closed.store(true, Ordering::Release);
+                });
+            }
+
+            let result = loop {
+                let frame = match frames.recv_timeout(CLOSE_POLL) {
+                    Ok(frame) => websocket_frame(TEXT, frame.as_bytes()),
+                    Err(RecvTimeoutError::Timeout) if !closed.load(Ordering::Acquire) => continue,
+                    // Closed by the browser, or dropped for not keeping up
+                    Err(_) => break Ok(()),
+                };
+                let mut stream = writer.lock().map_err(|e| e.to_string())?;
+                if closed.load(Ordering::Acquire) {
+                    break Ok(());
+                }
+                if let Err(e) = stream.write_all(&frame) {
+                    break Err(e.to_string());
+                }
+            };
+            // Stops the reading thread too
+            if let Ok(stream) = writer.lock() {
+                stream.shutdown(Shutdown::Both).ok();
+            }
+            result
+        }
+        ("/scores.json", _) => {
+            let latest = clients.lock().map_err(|e| e.to_string())?.latest.clone();
+            match latest {
+                Some(latest) => respond(&mut stream, "200 OK", "application/json", &latest),
+                None => respond(
+                    &mut stream,
+                    "503 Service Unavailable",
+                    "application/json",
+                    "{\"type\":\"waiting\"}",
+                ),
+            }
+        }
+        ("/config.json", _) => respond(&mut stream, "200 OK", "application/json", config),
+        ("/", _) | ("/index.html", _) => respond(&mut stream, "200 OK", "text/html", PAGE),
+        _ => respond(&mut stream, "404 Not Found", "text/plain", "Not found"),
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, kind: &str, body: &str) -> Result<(), String> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}; charset=utf-8\r\nContent-Length: {}\r\n\
+         Cache-Control: no-cache\r\nConnection: close\r\n\r\n{}",
+        status,
+        kind,
+        body.len(),
+        body
+    )
+    .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // A frame as sent by a browser, masked
+    fn masked_frame(opcode: u8, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+        let mut frame = websocket_frame(opcode, payload);
+        let header = frame.len() - payload.len();
+        frame[1] |= 0x80;
+        for (i, byte) in frame[header..].iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        frame.splice(header..header, mask.iter().cloned());
+        frame
+    }
+
+    #[test]
+    fn handshake() {
+        // The example of RFC 6455
+        let key = "dGhlIHNhbXBsZSBub25jZQ==";
+        let accept = base64(&sha1(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()));
+        assert_eq!(accept, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn sha1_digests() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(&sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        // Two blocks, the padding not fitting in the first
+        assert_eq!(
+            hex(&sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn frames() {
+        // The three forms of the length, and the length of their header
+        for &(length, header) in [(5, 2), (126, 4), (0xFFFF, 4), (0x10000, 10)].iter() {
+            let payload = (0..length).map(|i| i as u8).collect::<Vec<_>>();
+            let frame = websocket_frame(TEXT, &payload);
+            assert_eq!(frame.len(), header + length);
+            assert_eq!(read_frame(&mut &frame[..]), Ok((TEXT, payload.clone())));
+            let frame = masked_frame(PING, &payload, [0x37, 0xFA, 0x21, 0x3D]);
+            assert_eq!(frame.len(), header + 4 + length);
+            assert_eq!(read_frame(&mut &frame[..]), Ok((PING, payload)));
+        }
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let frame = masked_frame(TEXT, &[0; 0x10001], [1, 2, 3, 4]);
+        assert!(read_frame(&mut &frame[..]).is_err());
+        let frame = masked_frame(TEXT, b"Hello", [1, 2, 3, 4]);
+        assert!(read_frame(&mut &frame[..frame.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn requests() {
+        let request = "GET /ws HTTP/1.1\r\nHost: localhost\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        let mut reader = request.as_bytes();
+        assert_eq!(
+            read_request(&mut reader),
+            Ok(Some((
+                "/ws".to_owned(),
+                Some("dGhlIHNhbXBsZSBub25jZQ==".to_owned())
+            )))
+        );
+        let mut reader = &b"GET /scores.json HTTP/1.1\r\n\r\nNext"[..];
+        assert_eq!(
+            read_request(&mut reader),
+            Ok(Some(("/scores.json".to_owned(), None)))
+        );
+        assert_eq!(reader, b"Next");
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_REQUEST as usize));
+        assert_eq!(read_request(&mut long.as_bytes()), Ok(None));
+        let many = format!("GET / HTTP/1.1\r\n{}\r\n", "Cookie: a\r\n".repeat(2000));
+        assert_eq!(read_request(&mut many.as_bytes()), Ok(None));
+    }
+}